@@ -0,0 +1,60 @@
+use miette::{Context, IntoDiagnostic, Result};
+use reqwest::Client;
+use serde::Deserialize;
+
+/// The archived snapshot the availability API picked for a URL.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Snapshot {
+    pub url: String,
+    pub timestamp: String,
+    /// Archived HTTP status code.
+    pub status: Option<String>,
+    #[serde(default)]
+    available: bool,
+}
+
+#[derive(Deserialize)]
+struct AvailabilityResponse {
+    #[serde(default)]
+    archived_snapshots: ArchivedSnapshots,
+}
+
+#[derive(Default, Deserialize)]
+struct ArchivedSnapshots {
+    closest: Option<Snapshot>,
+}
+
+/// Asks the availability API whether `url` has been archived and returns the
+/// snapshot closest to `timestamp`, or the most recent one without it.
+pub async fn check_availability(
+    client: &Client,
+    url: &str,
+    timestamp: Option<&str>,
+    user_agent: &str,
+) -> Result<Option<Snapshot>> {
+    let mut api_url = format!(
+        "https://archive.org/wayback/available?url={}",
+        urlencoding::encode(url)
+    );
+    if let Some(timestamp) = timestamp {
+        api_url.push_str(&format!("&timestamp={}", timestamp));
+    }
+
+    tracing::debug!("Availability request: {}", api_url);
+    let response: AvailabilityResponse = client
+        .get(&api_url)
+        .header("User-Agent", user_agent)
+        .send()
+        .await
+        .into_diagnostic()
+        .context("Failed to fetch availability API")?
+        .json()
+        .await
+        .into_diagnostic()
+        .context("Failed to parse availability response")?;
+
+    Ok(response
+        .archived_snapshots
+        .closest
+        .filter(|snapshot| snapshot.available))
+}
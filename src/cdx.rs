@@ -0,0 +1,724 @@
+use clap::ValueEnum;
+use miette::{Context, IntoDiagnostic, Result, miette};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::time::{Duration, Instant};
+
+use crate::download::DEFAULT_USER_AGENT;
+
+/// The Wayback Machine's CDX API, the default [`CdxQuery::cdx_api`].
+pub const DEFAULT_CDX_API: &str = "https://web.archive.org/cdx/search/cdx";
+
+/// Where the Wayback Machine serves captures, see [`CdxCapture::capture_url`].
+pub const DEFAULT_CAPTURE_URL_TEMPLATE: &str = "https://web.archive.org/web/{timestamp}/{url}";
+
+/// A single row from the CDX API.
+#[derive(Debug, Clone, Serialize)]
+pub struct CdxCapture {
+    pub url_key: Option<String>,
+    pub timestamp: String,
+    pub original_url: String,
+    pub mime_type: Option<String>,
+    pub status_code: Option<String>,
+    pub digest: Option<String>,
+    pub length: Option<u64>,
+    /// Robots directives recorded for the capture, e.g. `noindex`.
+    pub robot_flags: Option<String>,
+}
+
+impl CdxCapture {
+    /// URL of this capture in the Wayback Machine.
+    pub fn wayback_url(&self) -> String {
+        self.capture_url(DEFAULT_CAPTURE_URL_TEMPLATE)
+    }
+
+    /// URL of this capture in an archive serving captures at `template`,
+    /// with `{timestamp}` and `{url}` standing for the capture timestamp
+    /// and the original URL.
+    pub fn capture_url(&self, template: &str) -> String {
+        template
+            .replace("{timestamp}", &self.timestamp)
+            .replace("{url}", &self.original_url)
+    }
+
+    /// Whether `robotflags` has an `X`, for a capture the crawler was kept
+    /// out of by robots.txt. These usually only hold an error page.
+    pub fn robot_excluded(&self) -> bool {
+        self.robot_flags
+            .as_deref()
+            .is_some_and(|flags| flags.contains('X'))
+    }
+}
+
+/// How the CDX API matches the queried URL against archived ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, ValueEnum)]
+pub enum MatchType {
+    /// Only the exact URL
+    Exact,
+    /// Every URL under the given path
+    #[default]
+    Prefix,
+    /// Every URL on the same host
+    Host,
+    /// Every URL on the host and its subdomains
+    Domain,
+}
+
+impl MatchType {
+    /// The value the CDX API expects for `matchType`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MatchType::Exact => "exact",
+            MatchType::Prefix => "prefix",
+            MatchType::Host => "host",
+            MatchType::Domain => "domain",
+        }
+    }
+}
+
+/// Response format requested from the CDX API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CdxOutput {
+    /// A JSON array of rows with a header row naming the columns
+    #[default]
+    Json,
+    /// Newline delimited `<urlkey> <timestamp> {json fields}` lines, which
+    /// carry fields such as `robotflags` by name
+    Cdxj,
+}
+
+impl CdxOutput {
+    /// The value the CDX API expects for `output`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CdxOutput::Json => "json",
+            CdxOutput::Cdxj => "cdxj",
+        }
+    }
+}
+
+/// Parameters for a CDX API search.
+#[derive(Debug, Clone)]
+pub struct CdxQuery {
+    /// Endpoint of the CDX API, e.g. [`DEFAULT_CDX_API`].
+    pub cdx_api: String,
+    pub url: String,
+    pub match_type: MatchType,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// `filter` expressions, all of which have to match, e.g.
+    /// `statuscode:200` or `!mimetype:image/.*`. See [`validate_cdx_filter`].
+    pub filters: Vec<String>,
+    /// `mimetype` filter patterns, all of which have to match.
+    pub mime_types: Vec<String>,
+    /// Server side `collapse` values, e.g. `digest` or `timestamp:8`.
+    pub collapse: Vec<String>,
+    /// Timeout for the CDX request, the client's default when `None`.
+    pub timeout: Option<Duration>,
+    /// Response format to request.
+    pub output: CdxOutput,
+    /// Server side `limit`: a positive value returns only the first N
+    /// captures, a negative one only the last N, of the whole query rather
+    /// than of each URL under a prefix, host or domain.
+    pub limit: Option<i64>,
+    /// Sort captures by their distance to this timestamp, nearest first.
+    pub closest: Option<String>,
+    /// Columns to request with `fl`, every column when empty. `timestamp`
+    /// and `original` are needed to parse the response.
+    pub fields: Vec<String>,
+    /// Page of the results to fetch, see [`get_cdx_page_count`].
+    pub page: Option<u32>,
+    /// Size of every page, the server's default when `None`.
+    pub page_size: Option<u32>,
+    /// User-Agent sent with the request.
+    pub user_agent: String,
+}
+
+impl CdxQuery {
+    /// A `prefix` search for `url` without any further filters.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            cdx_api: DEFAULT_CDX_API.to_string(),
+            url: url.into(),
+            match_type: MatchType::Prefix,
+            from: None,
+            to: None,
+            filters: vec![status_code_filter(&["200".to_string()])],
+            mime_types: Vec::new(),
+            collapse: Vec::new(),
+            timeout: None,
+            output: CdxOutput::Json,
+            limit: None,
+            closest: None,
+            fields: Vec::new(),
+            page: None,
+            page_size: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+        }
+    }
+}
+
+/// Checks that a timestamp is a CDX style `YYYYMMDDHHMMSS` prefix, e.g.
+/// `2023`, `20230115` or `20230115123045`.
+pub fn validate_timestamp(timestamp: &str) -> Result<()> {
+    let invalid = || {
+        miette!(
+            help =
+                "Expected YYYYMMDDHHMMSS or a prefix of it, e.g. 2023, 20230115 or 20230115123045",
+            "'{}' is not a valid timestamp",
+            timestamp
+        )
+    };
+
+    if !(4..=14).contains(&timestamp.len()) || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    // Any component that is present has to be in range, e.g. no month 13
+    let ranges = [
+        (4, 1..=12),
+        (6, 1..=31),
+        (8, 0..=23),
+        (10, 0..=59),
+        (12, 0..=59),
+    ];
+    for (start, range) in ranges {
+        if let Some(part) = timestamp.get(start..start + 2) {
+            let value: u32 = part.parse().map_err(|_| invalid())?;
+            if !range.contains(&value) {
+                return Err(invalid());
+            }
+        } else if timestamp.len() > start {
+            return Err(invalid());
+        }
+    }
+
+    Ok(())
+}
+
+/// The `filter` expression that only lets captures with one of the archived
+/// status `codes` through.
+pub fn status_code_filter(codes: &[String]) -> String {
+    // The filter value is a regex, so alternation matches any of them
+    format!("statuscode:{}", codes.join("|"))
+}
+
+/// Checks that a CDX `filter` expression is `[!]FIELD:REGEX`, e.g.
+/// `mimetype:text/html`, `!statuscode:200` or `original:.*\.pdf`.
+pub fn validate_cdx_filter(filter: &str) -> Result<()> {
+    let field = filter
+        .strip_prefix('!')
+        .unwrap_or(filter)
+        .split_once(':')
+        .map(|(field, _)| field);
+    match field {
+        Some(field) if CDX_FIELDS.contains(&field) => Ok(()),
+        _ => Err(miette!(
+            help = format!(
+                "Use FIELD:REGEX or !FIELD:REGEX with FIELD one of {}",
+                CDX_FIELDS.join(", ")
+            ),
+            "'{}' is not a valid CDX filter",
+            filter
+        )),
+    }
+}
+
+/// Columns the Wayback Machine returns when `fl` isn't given, which leave
+/// out `robotflags`.
+pub const DEFAULT_CDX_FIELDS: [&str; 7] = [
+    "urlkey",
+    "timestamp",
+    "original",
+    "mimetype",
+    "statuscode",
+    "digest",
+    "length",
+];
+
+/// Columns of a CDX row, which `fl` can ask for and a `filter` can look at.
+pub const CDX_FIELDS: [&str; 8] = [
+    "urlkey",
+    "timestamp",
+    "original",
+    "mimetype",
+    "statuscode",
+    "digest",
+    "length",
+    "robotflags",
+];
+
+/// Checks that a capture URL template is an http(s) URL with `{timestamp}`
+/// and `{url}` in it, like [`DEFAULT_CAPTURE_URL_TEMPLATE`].
+pub fn validate_capture_url_template(template: &str) -> Result<()> {
+    let missing: Vec<&str> = ["{timestamp}", "{url}"]
+        .into_iter()
+        .filter(|placeholder| !template.contains(placeholder))
+        .collect();
+    if !missing.is_empty() {
+        return Err(miette!(
+            help = format!(
+                "The Wayback Machine's template is {}",
+                DEFAULT_CAPTURE_URL_TEMPLATE
+            ),
+            "Capture URL template {} has no {}",
+            template,
+            missing.join(" or ")
+        ));
+    }
+    validate_archive_url(&template.replace("{timestamp}", "0").replace("{url}", ""))
+}
+
+/// Checks that the address of an archive endpoint is an http(s) URL.
+pub fn validate_archive_url(url: &str) -> Result<()> {
+    let parsed = url::Url::parse(url)
+        .into_diagnostic()
+        .context(format!("Invalid archive URL: {}", url))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(miette!(
+            help = "Use a URL like https://archive.example.org/cdx",
+            "Unsupported archive URL scheme: {}",
+            parsed.scheme()
+        ));
+    }
+    Ok(())
+}
+
+/// Canonicalizes a URL to query the CDX API for: `https://` instead of
+/// `http://` or no scheme, a lowercase host without `www.`, query parameters
+/// sorted by name and no fragment. E.g. `HTTP://www.Example.com/?b=2&a=1#top`
+/// becomes `https://example.com/?a=1&b=2`.
+pub fn normalize_url(url: &str) -> Result<String> {
+    let with_scheme = if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("https://{}", url)
+    };
+    let mut parsed = url::Url::parse(&with_scheme)
+        .into_diagnostic()
+        .context(format!("Can't normalize URL: {}", url))?;
+    if parsed.scheme() == "http" {
+        // Only fails for schemes the URL can't switch between
+        let _ = parsed.set_scheme("https");
+    }
+    if let Some(host) = parsed.host_str().and_then(|host| host.strip_prefix("www."))
+        && !host.is_empty()
+    {
+        let host = host.to_string();
+        parsed
+            .set_host(Some(&host))
+            .into_diagnostic()
+            .context(format!("Can't normalize URL: {}", url))?;
+    }
+    // Sorting the parameters as written keeps their encoding, and the
+    // order of repeated ones
+    if let Some(query) = parsed.query().filter(|query| !query.is_empty()) {
+        let mut params: Vec<&str> = query.split('&').collect();
+        params.sort_by_key(|param| param.split('=').next().unwrap_or_default());
+        let sorted = params.join("&");
+        parsed.set_query(Some(&sorted));
+    }
+    parsed.set_fragment(None);
+    Ok(parsed.to_string())
+}
+
+/// Number of pages the results of `query` are split into with its
+/// `page_size`.
+pub async fn get_cdx_page_count(client: &Client, query: &CdxQuery) -> Result<u32> {
+    let cdx_api_url = format!("{}&showNumPages=true", cdx_api_url(query));
+    tracing::debug!("CDX request: {}", cdx_api_url);
+
+    let mut request = client
+        .get(&cdx_api_url)
+        .header("User-Agent", &query.user_agent);
+    if let Some(timeout) = query.timeout {
+        request = request.timeout(timeout);
+    }
+
+    let body = request
+        .send()
+        .await
+        .into_diagnostic()
+        .context("Failed to fetch CDX page count")?
+        .text()
+        .await
+        .into_diagnostic()
+        .context("Failed to read CDX page count")?;
+
+    // Plain number from the Wayback Machine, `{"pages": N, ...}` from pywb
+    // based servers asked for JSON
+    let body = body.trim();
+    body.parse()
+        .ok()
+        .or_else(|| {
+            let value: Value = serde_json::from_str(body).ok()?;
+            value.get("pages")?.as_u64()?.try_into().ok()
+        })
+        .ok_or_else(|| miette!("Invalid CDX page count: {}", body))
+}
+
+/// Queries the CDX API for every capture matching `query`, or the ones on
+/// `query.page`.
+pub async fn get_wayback_cdx_urls(client: &Client, query: &CdxQuery) -> Result<Vec<CdxCapture>> {
+    let fetch = fetch_cdx_captures(client, query);
+    #[cfg(feature = "opentelemetry")]
+    let span = tracing::info_span!(
+        "cdx_query",
+        url = %query.url,
+        match_type = query.match_type.as_str(),
+        page = query.page,
+        status_code = tracing::field::Empty,
+        captures = tracing::field::Empty,
+        error = tracing::field::Empty,
+    );
+    #[cfg(feature = "opentelemetry")]
+    let fetch = tracing::Instrument::instrument(fetch, span.clone());
+    let result = fetch.await;
+    #[cfg(feature = "opentelemetry")]
+    if let Err(e) = &result {
+        span.record("error", tracing::field::display(e));
+    }
+    result
+}
+
+async fn fetch_cdx_captures(client: &Client, query: &CdxQuery) -> Result<Vec<CdxCapture>> {
+    let cdx_api_url = cdx_api_url(query);
+
+    match query.page {
+        Some(page) => tracing::debug!("Fetching page {} of archived URLs for {}", page, query.url),
+        None => tracing::info!("Fetching archived URLs for {} using CDX API", query.url),
+    }
+    tracing::debug!("CDX request: {}", cdx_api_url);
+    let started = Instant::now();
+
+    let mut request = client
+        .get(&cdx_api_url)
+        .header("User-Agent", &query.user_agent);
+    if let Some(timeout) = query.timeout {
+        request = request.timeout(timeout);
+    }
+
+    let response = request
+        .send()
+        .await
+        .into_diagnostic()
+        .context("Failed to fetch CDX API")?;
+    tracing::debug!(
+        "CDX API responded with {} in {:?}",
+        response.status(),
+        started.elapsed()
+    );
+    #[cfg(feature = "opentelemetry")]
+    tracing::Span::current().record("status_code", response.status().as_u16());
+
+    let captures = match query.output {
+        CdxOutput::Json => {
+            let data: Vec<Vec<Value>> = response
+                .json()
+                .await
+                .into_diagnostic()
+                .context("Failed to parse CDX JSON")?;
+            parse_json_rows(&data)?
+        }
+        CdxOutput::Cdxj => {
+            let body = response
+                .text()
+                .await
+                .into_diagnostic()
+                .context("Failed to read CDXJ response")?;
+            parse_cdxj(&body)?
+        }
+    };
+
+    tracing::debug!("CDX API returned {} rows", captures.len());
+    #[cfg(feature = "opentelemetry")]
+    tracing::Span::current().record("captures", captures.len());
+
+    if captures.is_empty() {
+        tracing::info!("No captures found in CDX API response.");
+    }
+
+    Ok(captures)
+}
+
+fn cdx_api_url(query: &CdxQuery) -> String {
+    let encoded_url = urlencoding::encode(&query.url);
+    let separator = if query.cdx_api.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let mut cdx_api_url = format!(
+        "{}{}url={}&matchType={}&output={}",
+        query.cdx_api,
+        separator,
+        encoded_url,
+        query.match_type.as_str(),
+        query.output.as_str()
+    );
+    for filter in &query.filters {
+        cdx_api_url.push_str(&format!("&filter={}", urlencoding::encode(filter)));
+    }
+    if let Some(from) = &query.from {
+        cdx_api_url.push_str(&format!("&from={}", from));
+    }
+    if let Some(to) = &query.to {
+        cdx_api_url.push_str(&format!("&to={}", to));
+    }
+    for mime_type in &query.mime_types {
+        cdx_api_url.push_str(&format!(
+            "&filter=mimetype:{}",
+            urlencoding::encode(mime_type)
+        ));
+    }
+    for collapse in &query.collapse {
+        cdx_api_url.push_str(&format!("&collapse={}", urlencoding::encode(collapse)));
+    }
+    if let Some(closest) = &query.closest {
+        cdx_api_url.push_str(&format!("&closest={}&sort=closest", closest));
+    }
+    if !query.fields.is_empty() {
+        cdx_api_url.push_str(&format!("&fl={}", query.fields.join(",")));
+    }
+    if let Some(limit) = query.limit {
+        cdx_api_url.push_str(&format!("&limit={}", limit));
+        // Lets the Wayback Machine find the last rows without reading all of
+        // them first
+        if limit < 0 {
+            cdx_api_url.push_str("&fastLatest=true");
+        }
+    }
+    if let Some(page) = query.page {
+        cdx_api_url.push_str(&format!("&page={}", page));
+    }
+    if let Some(page_size) = query.page_size {
+        cdx_api_url.push_str(&format!("&pageSize={}", page_size));
+    }
+    cdx_api_url
+}
+
+/// Parses an `output=json` response, an array of rows whose first row names
+/// the columns.
+fn parse_json_rows(data: &[Vec<Value>]) -> Result<Vec<CdxCapture>> {
+    let Some((headers, rows)) = data.split_first() else {
+        return Ok(Vec::new());
+    };
+
+    let column = |name: &str| headers.iter().position(|h| h.as_str() == Some(name));
+    let timestamp_idx = column("timestamp").context("timestamp field not found")?;
+    let original_url_idx = column("original").context("original field not found")?;
+    let url_key_idx = column("urlkey");
+    let mime_type_idx = column("mimetype");
+    let status_code_idx = column("statuscode");
+    let digest_idx = column("digest");
+    let length_idx = column("length");
+    let robot_flags_idx = column("robotflags");
+
+    let mut captures = Vec::new();
+    for row in rows {
+        let field = |idx: Option<usize>| {
+            idx.and_then(|i| row.get(i))
+                .and_then(Value::as_str)
+                .map(String::from)
+        };
+        let timestamp = field(Some(timestamp_idx)).context("Invalid timestamp")?;
+        let original_url = field(Some(original_url_idx)).context("Invalid URL")?;
+
+        captures.push(CdxCapture {
+            url_key: field(url_key_idx),
+            timestamp,
+            original_url,
+            mime_type: field(mime_type_idx),
+            status_code: field(status_code_idx),
+            digest: field(digest_idx),
+            length: field(length_idx).and_then(|length| length.parse().ok()),
+            robot_flags: field(robot_flags_idx),
+        });
+    }
+
+    Ok(captures)
+}
+
+/// Parses an `output=cdxj` response, one `<urlkey> <timestamp> {fields}`
+/// line per capture.
+fn parse_cdxj(body: &str) -> Result<Vec<CdxCapture>> {
+    let mut captures = Vec::new();
+    for line in body.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let mut parts = line.splitn(3, ' ');
+        let (Some(url_key), Some(timestamp), Some(fields)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(miette!("Invalid CDXJ line: {}", line));
+        };
+        let fields: HashMap<String, Value> = serde_json::from_str(fields)
+            .into_diagnostic()
+            .context(format!("Invalid CDXJ fields: {}", fields))?;
+
+        // Servers use either the short CDXJ names or the CDX column names
+        let field = |names: &[&str]| {
+            names.iter().find_map(|name| match fields.get(*name)? {
+                Value::String(value) => Some(value.clone()),
+                Value::Number(value) => Some(value.to_string()),
+                _ => None,
+            })
+        };
+        let original_url = field(&["url", "original"]).context("CDXJ line has no url field")?;
+
+        captures.push(CdxCapture {
+            url_key: Some(url_key.to_string()),
+            timestamp: timestamp.to_string(),
+            original_url,
+            mime_type: field(&["mime", "mimetype"]),
+            status_code: field(&["status", "statuscode"]),
+            digest: field(&["digest"]),
+            length: field(&["length"]).and_then(|length| length.parse().ok()),
+            robot_flags: field(&["robotflags"]),
+        });
+    }
+
+    Ok(captures)
+}
+
+/// Keeps only the earliest capture for every distinct `digest`, dropping
+/// later captures whose content didn't change. Captures without a digest are
+/// always kept.
+pub fn deduplicate_by_digest(captures: Vec<CdxCapture>) -> Vec<CdxCapture> {
+    let mut earliest: HashMap<&str, usize> = HashMap::new();
+    for (i, capture) in captures.iter().enumerate() {
+        let Some(digest) = capture.digest.as_deref() else {
+            continue;
+        };
+        match earliest.entry(digest) {
+            Entry::Occupied(mut entry) => {
+                if capture.timestamp < captures[*entry.get()].timestamp {
+                    entry.insert(i);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(i);
+            }
+        }
+    }
+
+    let keep: Vec<bool> = captures
+        .iter()
+        .enumerate()
+        .map(|(i, capture)| match capture.digest.as_deref() {
+            Some(digest) => earliest[digest] == i,
+            None => true,
+        })
+        .collect();
+
+    captures
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(capture, keep)| keep.then_some(capture))
+        .collect()
+}
+
+/// Keeps only the most recent capture of every distinct `original_url`,
+/// in the order of `captures`.
+pub fn latest_per_url(captures: Vec<CdxCapture>) -> Vec<CdxCapture> {
+    latest_by(captures, |capture| (capture.original_url.as_str(), ""))
+}
+
+/// Keeps only the most recent capture of every distinct `original_url` on
+/// each day, the first 8 digits of its timestamp, in the order of
+/// `captures`.
+pub fn latest_per_url_per_day(captures: Vec<CdxCapture>) -> Vec<CdxCapture> {
+    latest_by(captures, |capture| {
+        let day = capture.timestamp.get(..8).unwrap_or(&capture.timestamp);
+        (capture.original_url.as_str(), day)
+    })
+}
+
+fn latest_by<F>(captures: Vec<CdxCapture>, key: F) -> Vec<CdxCapture>
+where
+    F: Fn(&CdxCapture) -> (&str, &str),
+{
+    let mut latest: HashMap<(&str, &str), usize> = HashMap::new();
+    for (i, capture) in captures.iter().enumerate() {
+        match latest.entry(key(capture)) {
+            Entry::Occupied(mut entry) => {
+                if capture.timestamp > captures[*entry.get()].timestamp {
+                    entry.insert(i);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(i);
+            }
+        }
+    }
+
+    let keep: Vec<bool> = (0..captures.len())
+        .map(|i| latest[&key(&captures[i])] == i)
+        .collect();
+
+    captures
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(capture, keep)| keep.then_some(capture))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asks_for_the_last_rows_quickly() {
+        let query = CdxQuery {
+            match_type: MatchType::Exact,
+            limit: Some(-1),
+            ..CdxQuery::new("example.com")
+        };
+        assert!(cdx_api_url(&query).ends_with("&limit=-1&fastLatest=true"));
+    }
+
+    #[test]
+    fn asks_for_the_first_rows_without_fast_latest() {
+        let query = CdxQuery {
+            match_type: MatchType::Exact,
+            limit: Some(1),
+            ..CdxQuery::new("example.com")
+        };
+        let url = cdx_api_url(&query);
+        assert!(url.ends_with("&limit=1"));
+        assert!(!url.contains("fastLatest"));
+    }
+
+    #[test]
+    fn collapses_on_every_value() {
+        let query = CdxQuery {
+            collapse: vec!["urlkey".to_string(), "timestamp:8".to_string()],
+            ..CdxQuery::new("example.com")
+        };
+        assert!(cdx_api_url(&query).contains("&collapse=urlkey&collapse=timestamp%3A8"));
+    }
+
+    #[test]
+    fn reads_robot_flags_into_captures() {
+        let data: Vec<Vec<Value>> = serde_json::from_str(
+            r#"[
+                ["urlkey","timestamp","original","mimetype","statuscode","digest","length","robotflags"],
+                ["com,example)/a","20240101000000","http://example.com/a","text/html","200","A","10","X"],
+                ["com,example)/b","20240101000000","http://example.com/b","text/html","200","B","10","-"],
+                ["com,example)/c","20240101000000","http://example.com/c","text/html","200","C","10","AIF"]
+            ]"#,
+        )
+        .unwrap();
+        let mut captures = parse_json_rows(&data).unwrap();
+        assert_eq!(captures[0].robot_flags.as_deref(), Some("X"));
+        assert!(captures[0].robot_excluded());
+
+        captures.retain(|capture| !capture.robot_excluded());
+        let urls: Vec<&str> = captures
+            .iter()
+            .map(|capture| capture.original_url.as_str())
+            .collect();
+        assert_eq!(urls, ["http://example.com/b", "http://example.com/c"]);
+    }
+}
@@ -0,0 +1,286 @@
+use clap::ValueEnum;
+use miette::{Context, IntoDiagnostic, Result};
+use ring::digest::{Context as DigestContext, SHA1_FOR_LEGACY_USE_ONLY, SHA256, SHA512};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::md5::Md5;
+
+/// Hash functions a checksum manifest can be written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ChecksumAlgorithm {
+    /// SHA-1, the hash of CDX digests
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+    /// MD5, only for tools that expect it
+    Md5,
+}
+
+impl ChecksumAlgorithm {
+    /// Name of the hash, e.g. `SHA-256`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha1 => "SHA-1",
+            ChecksumAlgorithm::Sha256 => "SHA-256",
+            ChecksumAlgorithm::Sha512 => "SHA-512",
+            ChecksumAlgorithm::Md5 => "MD5",
+        }
+    }
+
+    /// The coreutils tool that checks a manifest of this hash, e.g.
+    /// `sha256sum`, and also the extension of its file name without `sum`.
+    pub fn tool(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha1 => "sha1sum",
+            ChecksumAlgorithm::Sha256 => "sha256sum",
+            ChecksumAlgorithm::Sha512 => "sha512sum",
+            ChecksumAlgorithm::Md5 => "md5sum",
+        }
+    }
+
+    /// Name of the manifest file, e.g. `checksums.sha256`.
+    pub fn manifest_filename(self) -> String {
+        format!("checksums.{}", self.tool().trim_end_matches("sum"))
+    }
+
+    /// The `#` line a manifest starts with to say which hash it lists.
+    pub fn manifest_header(self) -> String {
+        format!(
+            "# {} checksums, check with {} -c\n",
+            self.name(),
+            self.tool()
+        )
+    }
+
+    /// A new [`Hasher`] computing this hash.
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            ChecksumAlgorithm::Sha1 => Box::new(DigestContext::new(&SHA1_FOR_LEGACY_USE_ONLY)),
+            ChecksumAlgorithm::Sha256 => Box::new(DigestContext::new(&SHA256)),
+            ChecksumAlgorithm::Sha512 => Box::new(DigestContext::new(&SHA512)),
+            ChecksumAlgorithm::Md5 => Box::new(Md5::new()),
+        }
+    }
+}
+
+/// A hash computed over data that arrives in pieces.
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+
+    /// The hash of everything passed to [`update`](Self::update), hex
+    /// encoded.
+    fn finish_hex(self: Box<Self>) -> String;
+}
+
+impl Hasher for DigestContext {
+    fn update(&mut self, data: &[u8]) {
+        DigestContext::update(self, data);
+    }
+
+    fn finish_hex(self: Box<Self>) -> String {
+        hex(self.finish().as_ref())
+    }
+}
+
+impl Hasher for Md5 {
+    fn update(&mut self, data: &[u8]) {
+        Md5::update(self, data);
+    }
+
+    fn finish_hex(self: Box<Self>) -> String {
+        hex(&self.finish())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex encoded hash of a file's contents.
+pub fn hash_file(path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+    let mut file = File::open(path)
+        .into_diagnostic()
+        .context(format!("Failed to open {}", path.display()))?;
+
+    let mut hasher = algorithm.hasher();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .into_diagnostic()
+            .context(format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finish_hex())
+}
+
+/// Hex encoded hash of `data`.
+pub fn hash_hex(data: &[u8], algorithm: ChecksumAlgorithm) -> String {
+    let mut hasher = algorithm.hasher();
+    hasher.update(data);
+    hasher.finish_hex()
+}
+
+/// Hex encoded SHA-256 of a file's contents.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    hash_file(path, ChecksumAlgorithm::Sha256)
+}
+
+/// Hex encoded SHA-256 of `data`.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hash_hex(data, ChecksumAlgorithm::Sha256)
+}
+
+/// SHA-1 of `data` in base 32, the form of the CDX `digest` field, e.g.
+/// `3I42H3S6NNFQ2MSVX7XZKYAYSCX5QBYJ`.
+pub fn cdx_digest(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let hash = ring::digest::digest(&SHA1_FOR_LEGACY_USE_ONLY, data);
+    let mut encoded = String::new();
+    // Every 5 bytes make 8 characters, and 20 bytes need no padding
+    for chunk in hash.as_ref().chunks(5) {
+        let mut bits = [0u8; 8];
+        bits[8 - chunk.len()..].copy_from_slice(chunk);
+        let value = u64::from_be_bytes(bits);
+        for i in (0..8).rev() {
+            encoded.push(ALPHABET[(value >> (i * 5) & 31) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Hashes `filenames` inside `dir` in parallel and writes them to the
+/// [`manifest_filename`](ChecksumAlgorithm::manifest_filename) of
+/// `algorithm`, in the format its `sha256sum -c` style tool understands. The
+/// [`manifest_header`](ChecksumAlgorithm::manifest_header) comes first, then
+/// `comment` as `#` lines when there is one.
+pub async fn write_checksum_manifest(
+    dir: &Path,
+    filenames: &[String],
+    algorithm: ChecksumAlgorithm,
+    comment: Option<&str>,
+) -> Result<PathBuf> {
+    let tasks: Vec<_> = filenames
+        .iter()
+        .map(|filename| {
+            let path = dir.join(filename);
+            tokio::task::spawn_blocking(move || hash_file(&path, algorithm))
+        })
+        .collect();
+
+    let mut manifest = algorithm.manifest_header();
+    for line in comment.into_iter().flat_map(str::lines) {
+        manifest.push_str(&format!("# {}\n", line));
+    }
+    for (filename, task) in filenames.iter().zip(tasks) {
+        let hash = task.await.into_diagnostic()??;
+        manifest.push_str(&format!("{}  {}\n", hash, filename));
+    }
+
+    let path = dir.join(algorithm.manifest_filename());
+    std::fs::write(&path, manifest)
+        .into_diagnostic()
+        .context(format!("Failed to write {}", path.display()))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [ChecksumAlgorithm; 4] = [
+        ChecksumAlgorithm::Sha1,
+        ChecksumAlgorithm::Sha256,
+        ChecksumAlgorithm::Sha512,
+        ChecksumAlgorithm::Md5,
+    ];
+
+    #[test]
+    fn hashes_with_every_algorithm() {
+        let expected = [
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+            "900150983cd24fb0d6963f7d28e17f72",
+        ];
+        for (algorithm, expected) in ALGORITHMS.into_iter().zip(expected) {
+            assert_eq!(
+                hash_hex(b"abc", algorithm),
+                expected,
+                "{}",
+                algorithm.name()
+            );
+
+            let mut hasher = algorithm.hasher();
+            hasher.update(b"a");
+            hasher.update(b"bc");
+            assert_eq!(hasher.finish_hex(), expected, "{}", algorithm.name());
+        }
+    }
+
+    #[test]
+    fn names_the_manifest_after_the_tool() {
+        let manifests: Vec<(String, String)> = ALGORITHMS
+            .into_iter()
+            .map(|algorithm| (algorithm.manifest_filename(), algorithm.manifest_header()))
+            .collect();
+        assert_eq!(
+            manifests,
+            [
+                (
+                    "checksums.sha1",
+                    "# SHA-1 checksums, check with sha1sum -c\n"
+                ),
+                (
+                    "checksums.sha256",
+                    "# SHA-256 checksums, check with sha256sum -c\n"
+                ),
+                (
+                    "checksums.sha512",
+                    "# SHA-512 checksums, check with sha512sum -c\n"
+                ),
+                ("checksums.md5", "# MD5 checksums, check with md5sum -c\n"),
+            ]
+            .map(|(name, header)| (name.to_string(), header.to_string()))
+        );
+    }
+
+    #[test]
+    fn encodes_cdx_digests_in_base_32() {
+        assert_eq!(cdx_digest(b""), "3I42H3S6NNFQ2MSVX7XZKYAYSCX5QBYJ");
+    }
+
+    #[tokio::test]
+    async fn writes_the_header_and_comment_before_the_hashes() {
+        let dir = std::env::temp_dir().join(format!("noway-checksum-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "abc").unwrap();
+
+        let path = write_checksum_manifest(
+            &dir,
+            &["a.txt".to_string()],
+            ChecksumAlgorithm::Md5,
+            Some("Files are gzip compressed\nsecond line"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, dir.join("checksums.md5"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# MD5 checksums, check with md5sum -c\n\
+             # Files are gzip compressed\n\
+             # second line\n\
+             900150983cd24fb0d6963f7d28e17f72  a.txt\n"
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
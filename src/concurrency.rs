@@ -0,0 +1,288 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+
+/// Concurrent downloads `--concurrency-auto` starts with.
+pub const INITIAL_CONCURRENCY: usize = 5;
+/// Most concurrent downloads `--concurrency-auto` goes up to.
+pub const MAX_CONCURRENCY: usize = 50;
+
+// Downloads in a row that have to finish within FAST_DOWNLOAD before one
+// more may run at a time
+const FAST_STREAK: usize = 10;
+const FAST_DOWNLOAD: Duration = Duration::from_secs(2);
+// Fewer downloads at a time after a timeout
+const TIMEOUT_DECREASE: usize = 2;
+
+/// Permits to take out of circulation once downloads are done with them.
+/// Released permits go straight to the downloads waiting for one, so a busy
+/// semaphore never has any to forget itself.
+#[derive(Debug, Default)]
+pub struct PermitDebt(AtomicUsize);
+
+impl PermitDebt {
+    // Forgets the permit instead of handing it back to its semaphore while
+    // permits are owed
+    fn settle(&self, permit: OwnedSemaphorePermit) {
+        if self.take_one() {
+            permit.forget();
+        }
+    }
+
+    fn add(&self, permits: usize) {
+        self.0.fetch_add(permits, Ordering::SeqCst);
+    }
+
+    fn take_one(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |owed| {
+                owed.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+/// A download permit that is settled with a [`PermitDebt`], when there is
+/// one, as it is dropped.
+#[derive(Debug)]
+pub struct DownloadPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    debt: Option<Arc<PermitDebt>>,
+}
+
+impl DownloadPermit {
+    pub fn new(permit: OwnedSemaphorePermit, debt: Option<Arc<PermitDebt>>) -> Self {
+        Self {
+            permit: Some(permit),
+            debt,
+        }
+    }
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        if let (Some(permit), Some(debt)) = (self.permit.take(), &self.debt) {
+            debt.settle(permit);
+        }
+    }
+}
+
+/// Tunes how many downloads run at once by the permits of their semaphore:
+/// one more after 10 downloads in a row finished within 2 seconds, two fewer
+/// whenever a request timed out, between 1 and [`MAX_CONCURRENCY`].
+#[derive(Debug)]
+pub struct ConcurrencyController {
+    semaphore: Arc<Semaphore>,
+    debt: Arc<PermitDebt>,
+    /// Requests that timed out so far, see
+    /// [`DownloadOptions::timeouts`](crate::DownloadOptions::timeouts).
+    timeouts: Arc<AtomicUsize>,
+    limit: usize,
+    fast_streak: usize,
+    timeouts_seen: usize,
+}
+
+impl ConcurrencyController {
+    /// A controller for `semaphore`, which has to start with
+    /// [`INITIAL_CONCURRENCY`] permits. Downloads hand their permits to
+    /// `debt` when they finish.
+    pub fn new(
+        semaphore: Arc<Semaphore>,
+        debt: Arc<PermitDebt>,
+        timeouts: Arc<AtomicUsize>,
+    ) -> Self {
+        let timeouts_seen = timeouts.load(Ordering::Relaxed);
+        Self {
+            semaphore,
+            debt,
+            timeouts,
+            limit: INITIAL_CONCURRENCY,
+            fast_streak: 0,
+            timeouts_seen,
+        }
+    }
+
+    /// Adjusts the permits after every download duration that arrives on
+    /// `finished`, until all its senders are gone.
+    pub async fn run(mut self, mut finished: mpsc::UnboundedReceiver<Duration>) {
+        while let Some(duration) = finished.recv().await {
+            self.observe(duration);
+        }
+    }
+
+    fn observe(&mut self, duration: Duration) {
+        let timeouts = self.timeouts.load(Ordering::Relaxed);
+        if timeouts > self.timeouts_seen {
+            self.timeouts_seen = timeouts;
+            self.fast_streak = 0;
+            let decrease = TIMEOUT_DECREASE.min(self.limit - 1);
+            if decrease > 0 {
+                self.limit -= decrease;
+                let forgotten = self.semaphore.forget_permits(decrease);
+                self.debt.add(decrease - forgotten);
+                tracing::debug!(
+                    "Requests timed out, down to {} concurrent downloads",
+                    self.limit
+                );
+            }
+            return;
+        }
+
+        if duration < FAST_DOWNLOAD {
+            self.fast_streak += 1;
+        } else {
+            self.fast_streak = 0;
+        }
+        if self.fast_streak >= FAST_STREAK && self.limit < MAX_CONCURRENCY {
+            self.fast_streak = 0;
+            self.limit += 1;
+            // A permit still owed doesn't have to be given back first
+            if !self.debt.take_one() {
+                self.semaphore.add_permits(1);
+            }
+            tracing::debug!("Up to {} concurrent downloads", self.limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(100);
+    const SLOW: Duration = Duration::from_secs(5);
+
+    fn controller() -> ConcurrencyController {
+        ConcurrencyController::new(
+            Arc::new(Semaphore::new(INITIAL_CONCURRENCY)),
+            Arc::new(PermitDebt::default()),
+            Arc::new(AtomicUsize::new(0)),
+        )
+    }
+
+    /// A permit the way downloads hold one.
+    fn take_permit(controller: &ConcurrencyController) -> DownloadPermit {
+        let permit = Arc::clone(&controller.semaphore)
+            .try_acquire_owned()
+            .unwrap();
+        DownloadPermit::new(permit, Some(Arc::clone(&controller.debt)))
+    }
+
+    fn time_out(controller: &mut ConcurrencyController) {
+        controller.timeouts.fetch_add(1, Ordering::Relaxed);
+        controller.observe(FAST);
+    }
+
+    #[test]
+    fn allows_one_more_after_a_streak_of_fast_downloads() {
+        let mut controller = controller();
+        for _ in 0..FAST_STREAK - 1 {
+            controller.observe(FAST);
+        }
+        assert_eq!(controller.limit, INITIAL_CONCURRENCY);
+        controller.observe(FAST);
+        assert_eq!(controller.limit, INITIAL_CONCURRENCY + 1);
+        assert_eq!(
+            controller.semaphore.available_permits(),
+            INITIAL_CONCURRENCY + 1
+        );
+    }
+
+    #[test]
+    fn starts_the_streak_over_after_a_slow_download() {
+        let mut controller = controller();
+        for _ in 0..FAST_STREAK - 1 {
+            controller.observe(FAST);
+        }
+        controller.observe(SLOW);
+        for _ in 0..FAST_STREAK - 1 {
+            controller.observe(FAST);
+        }
+        assert_eq!(controller.limit, INITIAL_CONCURRENCY);
+    }
+
+    #[test]
+    fn stops_at_the_maximum() {
+        let mut controller = controller();
+        for _ in 0..FAST_STREAK * (MAX_CONCURRENCY + 10) {
+            controller.observe(FAST);
+        }
+        assert_eq!(controller.limit, MAX_CONCURRENCY);
+        assert_eq!(controller.semaphore.available_permits(), MAX_CONCURRENCY);
+    }
+
+    #[test]
+    fn allows_fewer_after_a_timeout_down_to_one() {
+        let mut controller = controller();
+        time_out(&mut controller);
+        assert_eq!(controller.limit, INITIAL_CONCURRENCY - TIMEOUT_DECREASE);
+        assert_eq!(
+            controller.semaphore.available_permits(),
+            INITIAL_CONCURRENCY - TIMEOUT_DECREASE
+        );
+
+        for _ in 0..INITIAL_CONCURRENCY {
+            time_out(&mut controller);
+        }
+        assert_eq!(controller.limit, 1);
+        assert_eq!(controller.semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn a_timeout_starts_the_streak_over() {
+        let mut controller = controller();
+        for _ in 0..FAST_STREAK - 1 {
+            controller.observe(FAST);
+        }
+        time_out(&mut controller);
+        controller.observe(FAST);
+        assert_eq!(controller.limit, INITIAL_CONCURRENCY - TIMEOUT_DECREASE);
+    }
+
+    #[test]
+    fn takes_back_permits_in_use_as_they_are_released() {
+        let mut controller = controller();
+        let permits: Vec<DownloadPermit> = (0..INITIAL_CONCURRENCY)
+            .map(|_| take_permit(&controller))
+            .collect();
+
+        // No permit is free to forget, so both are owed
+        time_out(&mut controller);
+        assert_eq!(controller.debt.0.load(Ordering::SeqCst), TIMEOUT_DECREASE);
+
+        let mut permits = permits.into_iter();
+        for _ in 0..TIMEOUT_DECREASE {
+            drop(permits.next());
+            assert_eq!(controller.semaphore.available_permits(), 0);
+        }
+        assert_eq!(controller.debt.0.load(Ordering::SeqCst), 0);
+        drop(permits);
+        assert_eq!(
+            controller.semaphore.available_permits(),
+            INITIAL_CONCURRENCY - TIMEOUT_DECREASE
+        );
+    }
+
+    #[test]
+    fn cancels_a_debt_instead_of_adding_a_permit() {
+        let mut controller = controller();
+        let permits: Vec<DownloadPermit> = (0..INITIAL_CONCURRENCY)
+            .map(|_| take_permit(&controller))
+            .collect();
+        time_out(&mut controller);
+        for _ in 0..FAST_STREAK {
+            controller.observe(FAST);
+        }
+        let limit = INITIAL_CONCURRENCY - TIMEOUT_DECREASE + 1;
+        assert_eq!(controller.limit, limit);
+        assert_eq!(
+            controller.debt.0.load(Ordering::SeqCst),
+            TIMEOUT_DECREASE - 1
+        );
+
+        drop(permits);
+        assert_eq!(controller.semaphore.available_permits(), limit);
+    }
+}
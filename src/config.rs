@@ -0,0 +1,494 @@
+use clap::{Arg, ArgAction, Command};
+use miette::{Context, IntoDiagnostic, Result, miette};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Written by `noway config --init`.
+pub const TEMPLATE: &str = r#"# noway configuration
+#
+# Every long option can get a default here, written with underscores or
+# dashes: `concurrency = 20` is the same as passing --concurrency 20 each
+# time. Options given on the command line and NOWAY_* environment variables
+# take precedence. Flags without a value take true or false, options that
+# can be repeated take a list.
+
+# output = "archive"
+# match_type = ["prefix"]
+# concurrency = 5
+# concurrency_cdx = 3
+# retries = 3
+# rate_limit = 5
+# user_agent = "noway"
+# proxy = "http://localhost:8080"
+# connect_timeout = 10
+# read_timeout = 30
+# strip_toolbar = true
+# skip_existing = true
+# status_codes = ["200"]
+# exclude = ["/wp-admin/"]
+"#;
+
+/// `$XDG_CONFIG_HOME/noway/config.toml`, falling back to
+/// `~/.config/noway/config.toml`.
+pub fn config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("noway").join("config.toml"))
+}
+
+/// Makes the settings in the config file at `path` the default values of
+/// the matching options of `command` and its subcommands. A missing file
+/// changes nothing.
+pub fn apply_defaults(mut command: Command, path: &Path) -> Result<Command> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(command),
+        Err(e) => {
+            return Err(e)
+                .into_diagnostic()
+                .context(format!("Failed to read config file: {}", path.display()));
+        }
+    };
+    let settings =
+        parse(&content).map_err(|e| miette!("Invalid config file {}: {}", path.display(), e))?;
+
+    for (key, values) in settings {
+        let long = key.replace('_', "-");
+        let mut found = false;
+        command = set_default(command, &long, &values, &mut found);
+        let subcommands: Vec<String> = command
+            .get_subcommands()
+            .map(|subcommand| subcommand.get_name().to_string())
+            .collect();
+        for name in subcommands {
+            command = command.mut_subcommand(name, |subcommand| {
+                set_default(subcommand, &long, &values, &mut found)
+            });
+        }
+
+        if !found {
+            return Err(miette!(
+                help = "Keys are the long options of noway, see noway --help",
+                "Unknown setting in config file {}: {}",
+                path.display(),
+                key
+            ));
+        }
+        if values.len() != 1 && !repeatable(&command, &long) {
+            return Err(miette!(
+                "Setting {} in config file {} takes a single value",
+                key,
+                path.display()
+            ));
+        }
+    }
+    Ok(command)
+}
+
+/// Lets every long option of `command` and its subcommands be set through a
+/// `NOWAY_*` environment variable named after it, e.g. `NOWAY_STRIP_TOOLBAR`
+/// for `--strip-toolbar`. Options that name their own variable keep it.
+pub fn add_env_vars(command: Command) -> Command {
+    let mut command = command.mut_args(with_env_var);
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    for name in subcommands {
+        command = command.mut_subcommand(name, |subcommand| subcommand.mut_args(with_env_var));
+    }
+    command
+}
+
+fn with_env_var(arg: Arg) -> Arg {
+    match arg.get_long() {
+        Some(long) if arg.get_env().is_none() && !matches!(long, "help" | "version") => {
+            let name = format!("NOWAY_{}", long.replace('-', "_").to_ascii_uppercase());
+            // Listing a variable for every option would drown out the help
+            let arg = arg.env(name).hide_env(true);
+            if matches!(arg.get_action(), ArgAction::SetTrue) {
+                // NOWAY_STRIP_TOOLBAR=1 as well as =true
+                arg.value_parser(clap::builder::BoolishValueParser::new())
+            } else {
+                arg
+            }
+        }
+        _ => arg,
+    }
+}
+
+/// Sets the default of the option `--<long>` if `command` has one.
+fn set_default(command: Command, long: &str, values: &[String], found: &mut bool) -> Command {
+    let Some(id) = find_arg(&command, long).map(|arg| arg.get_id().clone()) else {
+        return command;
+    };
+    *found = true;
+    command.mut_arg(id, |arg| arg.default_values(values.to_vec()))
+}
+
+fn find_arg<'a>(command: &'a Command, long: &str) -> Option<&'a clap::Arg> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long) && long != "help")
+}
+
+/// Whether `--<long>` takes several values, on `command` or any subcommand.
+fn repeatable(command: &Command, long: &str) -> bool {
+    std::iter::once(command)
+        .chain(command.get_subcommands())
+        .filter_map(|command| find_arg(command, long))
+        .any(|arg| {
+            matches!(arg.get_action(), ArgAction::Append) || arg.get_value_delimiter().is_some()
+        })
+}
+
+/// Parses the subset of TOML a flat list of settings needs: `key = value`
+/// pairs with strings, numbers, booleans and arrays of those, plus comments.
+/// Every value comes back as the strings clap would parse on the command
+/// line.
+fn parse(content: &str) -> ParseResult<Vec<(String, Vec<String>)>> {
+    let mut parser = Parser {
+        chars: content.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    let mut settings: Vec<(String, Vec<String>)> = Vec::new();
+    loop {
+        parser.skip_blank(true);
+        let Some(c) = parser.peek() else {
+            return Ok(settings);
+        };
+        if c == '[' {
+            return Err(parser.error("tables aren't supported, settings go at the top level"));
+        }
+
+        let key = parser.key()?;
+        parser.skip_blank(false);
+        if parser.next() != Some('=') {
+            return Err(parser.error(&format!("expected = after {}", key)));
+        }
+        parser.skip_blank(false);
+        let values = match parser.peek() {
+            Some('[') => parser.array()?,
+            _ => vec![parser.value()?],
+        };
+        parser.skip_blank(false);
+        if !matches!(parser.peek(), None | Some('\n')) {
+            return Err(parser.error("expected a new line after the value"));
+        }
+
+        if settings.iter().any(|(other, _)| other == &key) {
+            return Err(parser.error(&format!("{} is set twice", key)));
+        }
+        settings.push((key, values));
+    }
+}
+
+/// Parse errors are plain messages with the line they were found on.
+type ParseResult<T> = std::result::Result<T, String>;
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {}", self.line, message)
+    }
+
+    /// Skips spaces and comments, and line breaks too with `newlines`.
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                '\n' if newlines => {}
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                _ => return,
+            }
+            self.next();
+        }
+    }
+
+    fn key(&mut self) -> ParseResult<String> {
+        if matches!(self.peek(), Some('"' | '\'')) {
+            return self.string();
+        }
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(self.error("expected a setting name"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn array(&mut self) -> ParseResult<Vec<String>> {
+        self.next();
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank(true);
+            match self.peek() {
+                Some(']') => {
+                    self.next();
+                    return Ok(values);
+                }
+                Some('[') => return Err(self.error("nested arrays aren't supported")),
+                None => return Err(self.error("unclosed array")),
+                _ => values.push(self.value()?),
+            }
+            self.skip_blank(true);
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(values),
+                None => return Err(self.error("unclosed array")),
+                _ => return Err(self.error("expected , or ] in array")),
+            }
+        }
+    }
+
+    fn value(&mut self) -> ParseResult<String> {
+        if matches!(self.peek(), Some('"' | '\'')) {
+            return self.string();
+        }
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '.'))
+        {
+            self.pos += 1;
+        }
+        let value: String = self.chars[start..self.pos].iter().collect();
+        let number = value.replace('_', "");
+        if value == "true" || value == "false" || number.parse::<f64>().is_ok() {
+            Ok(number)
+        } else if value.is_empty() {
+            Err(self.error("expected a value"))
+        } else {
+            Err(self.error(&format!("{} is not a value, put text in quotes", value)))
+        }
+    }
+
+    fn string(&mut self) -> ParseResult<String> {
+        let quote = self.next();
+        let mut value = String::new();
+        loop {
+            // Stop before the line break so the error names the string's line
+            let c = match self.peek() {
+                None | Some('\n') => return Err(self.error("unclosed string")),
+                Some(c) => c,
+            };
+            self.pos += 1;
+            match c {
+                c if Some(c) == quote => return Ok(value),
+                // Literal strings in single quotes have no escapes
+                '\\' if quote == Some('"') => value.push(self.escape()?),
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> ParseResult<char> {
+        let c = match self.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(kind @ ('u' | 'U')) => {
+                let len = if kind == 'u' { 4 } else { 8 };
+                let digits: String = self.chars.iter().skip(self.pos).take(len).collect();
+                self.pos += digits.chars().count();
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| self.error(&format!("invalid escape \\{}{}", kind, digits)))?
+            }
+            _ => return Err(self.error("invalid escape in string")),
+        };
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(key: &str, values: &[&str]) -> (String, Vec<String>) {
+        (
+            key.to_string(),
+            values.iter().map(|value| value.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn parses_scalar_values() {
+        let settings = parse(
+            "# comment\n\
+             output = \"archive\"\n\
+             concurrency = 1_000\n\
+             rate_limit = 2.5\n\
+             strip_toolbar = true\n\
+             'quoted-key' = 'C:\\path'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                setting("output", &["archive"]),
+                setting("concurrency", &["1000"]),
+                setting("rate_limit", &["2.5"]),
+                setting("strip_toolbar", &["true"]),
+                setting("quoted-key", &["C:\\path"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_escapes_in_basic_strings() {
+        let settings = parse(r#"user_agent = "a\tb\n\"c\" \\ \u00e9 \U0001F600""#).unwrap();
+        assert_eq!(
+            settings,
+            vec![setting("user_agent", &["a\tb\n\"c\" \\ \u{e9} \u{1F600}"])]
+        );
+        assert!(parse(r#"a = "\q""#).unwrap_err().contains("invalid escape"));
+        assert!(
+            parse(r#"a = "\uZZZZ""#)
+                .unwrap_err()
+                .contains("invalid escape \\uZZZZ")
+        );
+    }
+
+    #[test]
+    fn keeps_a_hash_inside_quotes() {
+        let settings = parse("exclude = \"#anchor\" # comment\n").unwrap();
+        assert_eq!(settings, vec![setting("exclude", &["#anchor"])]);
+    }
+
+    #[test]
+    fn parses_arrays() {
+        let settings = parse(
+            "status_codes = [\"200\", \"404\"]\n\
+             match_type = [\n  \"exact\", # first\n  \"prefix\"\n]\n\
+             empty = []\n\
+             trailing = [1, 2,]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                setting("status_codes", &["200", "404"]),
+                setting("match_type", &["exact", "prefix"]),
+                setting("empty", &[]),
+                setting("trailing", &["1", "2"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let error = |content: &str| parse(content).unwrap_err();
+        assert_eq!(error("a = \"open\n"), "line 1: unclosed string");
+        assert_eq!(error("a = [1, 2"), "line 1: unclosed array");
+        assert_eq!(error("a = [1 2]"), "line 1: expected , or ] in array");
+        assert_eq!(error("a = [[1]]"), "line 1: nested arrays aren't supported");
+        assert_eq!(
+            error("\nkey = bare"),
+            "line 2: bare is not a value, put text in quotes"
+        );
+        assert_eq!(error("a ="), "line 1: expected a value");
+        assert_eq!(error("a 1"), "line 1: expected = after a");
+        assert_eq!(error("= 1"), "line 1: expected a setting name");
+        assert_eq!(
+            error("a = 1 2"),
+            "line 1: expected a new line after the value"
+        );
+        assert_eq!(error("a = 1\na = 2"), "line 2: a is set twice");
+        assert_eq!(
+            error("[section]"),
+            "line 1: tables aren't supported, settings go at the top level"
+        );
+    }
+
+    fn command() -> Command {
+        Command::new("noway")
+            .arg(Arg::new("output").long("output"))
+            .arg(
+                Arg::new("exclude")
+                    .long("exclude")
+                    .action(ArgAction::Append),
+            )
+            .subcommand(Command::new("verify").arg(Arg::new("index").long("index")))
+    }
+
+    fn apply(name: &str, content: &str) -> Result<Command> {
+        let path =
+            std::env::temp_dir().join(format!("noway-config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        let result = apply_defaults(command(), &path);
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn sets_defaults_of_options_and_subcommands() {
+        let command = apply(
+            "defaults",
+            "output = \"saved\"\nexclude = [\"a\", \"b\"]\nindex = \"i.ndjson\"\n",
+        )
+        .unwrap();
+        let matches = command.clone().try_get_matches_from(["noway"]).unwrap();
+        assert_eq!(matches.get_one::<String>("output").unwrap(), "saved");
+        let excluded: Vec<&String> = matches.get_many("exclude").unwrap().collect();
+        assert_eq!(excluded, ["a", "b"]);
+
+        let matches = command.try_get_matches_from(["noway", "verify"]).unwrap();
+        let (_, verify) = matches.subcommand().unwrap();
+        assert_eq!(verify.get_one::<String>("index").unwrap(), "i.ndjson");
+    }
+
+    #[test]
+    fn ignores_a_missing_file() {
+        let path = std::env::temp_dir().join("noway-config-missing.toml");
+        assert!(apply_defaults(command(), &path).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let error = apply("unknown", "colour = \"red\"\n").unwrap_err();
+        assert!(error.to_string().contains("Unknown setting"));
+        assert!(error.to_string().ends_with(": colour"));
+    }
+
+    #[test]
+    fn rejects_several_values_for_a_single_value_option() {
+        let error = apply("single", "output = [\"a\", \"b\"]\n").unwrap_err();
+        assert!(error.to_string().contains("Setting output"));
+        assert!(error.to_string().contains("takes a single value"));
+    }
+}
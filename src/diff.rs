@@ -0,0 +1,460 @@
+/// One step of turning the old lines into the new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    /// Line `.0` of the old text is line `.1` of the new one.
+    Keep(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// A line by line unified diff of `old` and `new`, like `diff -u`, with
+/// `context` unchanged lines around every change. Empty when they are the
+/// same.
+pub fn unified_diff(
+    old: &str,
+    new: &str,
+    old_label: &str,
+    new_label: &str,
+    context: usize,
+) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let edits = diff_lines(&old_lines, &new_lines);
+    if edits.iter().all(|edit| matches!(edit, Edit::Keep(..))) {
+        return String::new();
+    }
+
+    // Lines of each side before every edit
+    let mut positions = Vec::with_capacity(edits.len());
+    let (mut old_position, mut new_position) = (0, 0);
+    for edit in &edits {
+        positions.push((old_position, new_position));
+        match edit {
+            Edit::Keep(..) => {
+                old_position += 1;
+                new_position += 1;
+            }
+            Edit::Delete(_) => old_position += 1,
+            Edit::Insert(_) => new_position += 1,
+        }
+    }
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_label, new_label);
+    for hunk in hunks(&edits, context) {
+        let (old_before, new_before) = positions[hunk.start];
+        let edits = &edits[hunk];
+        let old_count = edits
+            .iter()
+            .filter(|e| !matches!(e, Edit::Insert(_)))
+            .count();
+        let new_count = edits
+            .iter()
+            .filter(|e| !matches!(e, Edit::Delete(_)))
+            .count();
+        // Lines count from 1, and a side without lines names the one before
+        let start = |before: usize, count: usize| if count == 0 { before } else { before + 1 };
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            start(old_before, old_count),
+            old_count,
+            start(new_before, new_count),
+            new_count
+        ));
+
+        for edit in edits {
+            match *edit {
+                Edit::Keep(old, _) => diff.push_str(&format!(" {}\n", old_lines[old])),
+                Edit::Delete(old) => diff.push_str(&format!("-{}\n", old_lines[old])),
+                Edit::Insert(new) => diff.push_str(&format!("+{}\n", new_lines[new])),
+            }
+        }
+    }
+    diff
+}
+
+/// A standalone HTML page showing `old` and `new` side by side, with
+/// removed lines on the left and added ones on the right highlighted.
+pub fn html_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let edits = diff_lines(&old_lines, &new_lines);
+
+    let mut html = String::from(concat!(
+        "<!DOCTYPE html>\n",
+        "<html lang=\"en\">\n",
+        "<head>\n",
+        "<meta charset=\"utf-8\">\n",
+        "<title>Capture diff</title>\n",
+        "<style>\n",
+        "body { font-family: system-ui, sans-serif; margin: 1rem; }\n",
+        "table { border-collapse: collapse; width: 100%; table-layout: fixed; }\n",
+        "th { text-align: left; padding: 0.25rem 0.5rem; background: #eee; ",
+        "word-break: break-all; }\n",
+        "td { font-family: monospace; font-size: 0.8rem; white-space: pre-wrap; ",
+        "word-break: break-all; vertical-align: top; padding: 0 0.5rem; }\n",
+        "td.number { width: 3.5rem; color: #888; text-align: right; }\n",
+        ".delete { background: #fdd; }\n",
+        ".insert { background: #dfd; }\n",
+        "</style>\n",
+        "</head>\n",
+        "<body>\n",
+        "<table>\n",
+    ));
+    html.push_str(&format!(
+        "<tr><th colspan=\"2\">{}</th><th colspan=\"2\">{}</th></tr>\n",
+        escape(old_label),
+        escape(new_label)
+    ));
+
+    let mut i = 0;
+    while i < edits.len() {
+        if let Edit::Keep(old, new) = edits[i] {
+            push_row(
+                &mut html,
+                Some((old, old_lines[old])),
+                Some((new, new_lines[new])),
+                false,
+            );
+            i += 1;
+            continue;
+        }
+
+        // Deleted and inserted lines of the same change share rows
+        let end = edits[i..]
+            .iter()
+            .position(|edit| matches!(edit, Edit::Keep(..)))
+            .map_or(edits.len(), |end| i + end);
+        let deleted: Vec<usize> = edits[i..end]
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Delete(old) => Some(*old),
+                _ => None,
+            })
+            .collect();
+        let inserted: Vec<usize> = edits[i..end]
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Insert(new) => Some(*new),
+                _ => None,
+            })
+            .collect();
+        for row in 0..deleted.len().max(inserted.len()) {
+            push_row(
+                &mut html,
+                deleted.get(row).map(|&old| (old, old_lines[old])),
+                inserted.get(row).map(|&new| (new, new_lines[new])),
+                true,
+            );
+        }
+        i = end;
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// One table row with the numbered lines on each side, highlighted when
+/// `changed`.
+fn push_row(
+    html: &mut String,
+    old: Option<(usize, &str)>,
+    new: Option<(usize, &str)>,
+    changed: bool,
+) {
+    html.push_str("<tr>");
+    for (side, class) in [(old, "delete"), (new, "insert")] {
+        let class = if changed { class } else { "" };
+        match side {
+            Some((number, line)) => html.push_str(&format!(
+                "<td class=\"number {}\">{}</td><td class=\"{}\">{}</td>",
+                class,
+                number + 1,
+                class,
+                escape(line)
+            )),
+            None => html.push_str("<td class=\"number\"></td><td></td>"),
+        }
+    }
+    html.push_str("</tr>\n");
+}
+
+/// Ranges of `edits` to show as hunks: every change with up to `context`
+/// kept lines on each side, merging changes that are close together.
+fn hunks(edits: &[Edit], context: usize) -> Vec<std::ops::Range<usize>> {
+    let mut hunks: Vec<std::ops::Range<usize>> = Vec::new();
+    for (i, edit) in edits.iter().enumerate() {
+        if matches!(edit, Edit::Keep(..)) {
+            continue;
+        }
+        let start = i.saturating_sub(context);
+        let end = (i + 1 + context).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.end => last.end = end,
+            _ => hunks.push(start..end),
+        }
+    }
+    hunks
+}
+
+/// The shortest list of edits from `old` to `new`, using Myers' algorithm
+/// after setting aside the lines both start and end with.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut edits: Vec<Edit> = (0..prefix).map(|i| Edit::Keep(i, i)).collect();
+    edits.extend(myers(a, b).into_iter().map(|edit| match edit {
+        Edit::Keep(x, y) => Edit::Keep(x + prefix, y + prefix),
+        Edit::Delete(x) => Edit::Delete(x + prefix),
+        Edit::Insert(y) => Edit::Insert(y + prefix),
+    }));
+    edits.extend((0..suffix).map(|i| Edit::Keep(old.len() - suffix + i, new.len() - suffix + i)));
+    edits
+}
+
+fn myers(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let offset = max as usize;
+    // Furthest x reached on each diagonal k = x - y, indexed by k + offset
+    let mut v = vec![0isize; 2 * offset + 2];
+    // v after each round d, only the diagonals -d..=d that round can reach
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    'search: for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let index = (k + max) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+            if x >= n && y >= m {
+                trace.push(v[offset - d as usize..=offset + d as usize].to_vec());
+                break 'search;
+            }
+        }
+        trace.push(v[offset - d as usize..=offset + d as usize].to_vec());
+    }
+
+    // Walk back from the end through the rounds to recover the path
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let k = x - y;
+        let at = |round: &Vec<isize>, k: isize| round[(k + d - 1) as usize];
+        if d == 0 {
+            while x > 0 && y > 0 {
+                x -= 1;
+                y -= 1;
+                edits.push(Edit::Keep(x as usize, y as usize));
+            }
+            break;
+        }
+        let previous = &trace[d as usize - 1];
+        let previous_k = if k == -d || (k != d && at(previous, k - 1) < at(previous, k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let previous_x = at(previous, previous_k);
+        let previous_y = previous_x - previous_k;
+
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Keep(x as usize, y as usize));
+        }
+        if x == previous_x {
+            edits.push(Edit::Insert(previous_y as usize));
+        } else {
+            edits.push(Edit::Delete(previous_x as usize));
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+    edits.reverse();
+    edits
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length of the longest common subsequence, the number of lines a
+    /// shortest diff keeps.
+    fn common_lines(a: &[&str], b: &[&str]) -> usize {
+        let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lengths[i][j] = if a[i] == b[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+        lengths[0][0]
+    }
+
+    /// Checks that `edits` walk both sides in order, keep only equal lines
+    /// and keep as many as possible.
+    fn check(a: &[&str], b: &[&str]) {
+        let edits = diff_lines(a, b);
+        let (mut x, mut y) = (0, 0);
+        for edit in &edits {
+            match *edit {
+                Edit::Keep(old, new) => {
+                    assert_eq!((old, new), (x, y), "{:?} -> {:?}", a, b);
+                    assert_eq!(a[old], b[new]);
+                    x += 1;
+                    y += 1;
+                }
+                Edit::Delete(old) => {
+                    assert_eq!(old, x, "{:?} -> {:?}", a, b);
+                    x += 1;
+                }
+                Edit::Insert(new) => {
+                    assert_eq!(new, y, "{:?} -> {:?}", a, b);
+                    y += 1;
+                }
+            }
+        }
+        assert_eq!((x, y), (a.len(), b.len()));
+        let kept = edits
+            .iter()
+            .filter(|edit| matches!(edit, Edit::Keep(..)))
+            .count();
+        assert_eq!(kept, common_lines(a, b), "{:?} -> {:?}", a, b);
+    }
+
+    #[test]
+    fn finds_shortest_edits() {
+        check(&[], &[]);
+        check(&["a"], &[]);
+        check(&[], &["a"]);
+        check(&["a", "b", "c"], &["a", "b", "c"]);
+        check(
+            &["a", "b", "c", "a", "b", "b", "a"],
+            &["c", "b", "a", "b", "a", "c"],
+        );
+        check(&["x", "a", "y"], &["a", "x", "a"]);
+
+        // Every pair of short texts over a small alphabet
+        let mut seed = 0x2545_f491_u32;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        let words = ["a", "b", "c"];
+        for _ in 0..500 {
+            let a: Vec<&str> = (0..next() % 8)
+                .map(|_| words[next() as usize % 3])
+                .collect();
+            let b: Vec<&str> = (0..next() % 8)
+                .map(|_| words[next() as usize % 3])
+                .collect();
+            check(&a, &b);
+        }
+    }
+
+    #[test]
+    fn same_texts_have_no_diff() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", "old", "new", 3), "");
+        assert_eq!(unified_diff("", "", "old", "new", 3), "");
+    }
+
+    #[test]
+    fn writes_unified_hunks() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\nten\n";
+        assert_eq!(
+            unified_diff(old, new, "a/page", "b/page", 1),
+            concat!(
+                "--- a/page\n",
+                "+++ b/page\n",
+                "@@ -2,3 +2,3 @@\n",
+                " 2\n",
+                "-3\n",
+                "+three\n",
+                " 4\n",
+                "@@ -9,1 +9,2 @@\n",
+                " 9\n",
+                "+ten\n",
+            )
+        );
+
+        // Changes closer than twice the context share a hunk
+        assert_eq!(
+            unified_diff(old, new, "a", "b", 3),
+            concat!(
+                "--- a\n",
+                "+++ b\n",
+                "@@ -1,9 +1,10 @@\n",
+                " 1\n",
+                " 2\n",
+                "-3\n",
+                "+three\n",
+                " 4\n",
+                " 5\n",
+                " 6\n",
+                " 7\n",
+                " 8\n",
+                " 9\n",
+                "+ten\n",
+            )
+        );
+    }
+
+    #[test]
+    fn numbers_empty_sides_after_the_line_before() {
+        assert_eq!(
+            unified_diff("", "a\nb\n", "old", "new", 3),
+            "--- old\n+++ new\n@@ -0,0 +1,2 @@\n+a\n+b\n"
+        );
+        assert_eq!(
+            unified_diff("a\nb\nc\n", "a\nc\n", "old", "new", 0),
+            "--- old\n+++ new\n@@ -2,1 +1,0 @@\n-b\n"
+        );
+    }
+
+    #[test]
+    fn pairs_changed_lines_in_html() {
+        let html = html_diff("same\n<old>\n", "same\nnew & more\nextra\n", "a", "b & c");
+        assert!(html.contains("<th colspan=\"2\">a</th><th colspan=\"2\">b &amp; c</th>"));
+        assert!(html.contains(concat!(
+            "<tr><td class=\"number \">1</td><td class=\"\">same</td>",
+            "<td class=\"number \">1</td><td class=\"\">same</td></tr>\n",
+        )));
+        assert!(html.contains(concat!(
+            "<tr><td class=\"number delete\">2</td><td class=\"delete\">&lt;old&gt;</td>",
+            "<td class=\"number insert\">2</td><td class=\"insert\">new &amp; more</td></tr>\n",
+        )));
+        assert!(html.contains(concat!(
+            "<tr><td class=\"number\"></td><td></td>",
+            "<td class=\"number insert\">3</td><td class=\"insert\">extra</td></tr>\n",
+        )));
+        assert!(html.ends_with("</table>\n</body>\n</html>\n"));
+    }
+}
@@ -0,0 +1,1771 @@
+use miette::{Context, IntoDiagnostic, Result, miette};
+use reqwest::header::{ACCEPT_RANGES, CONTENT_RANGE, HeaderMap, RANGE};
+use reqwest::{Client, Response, StatusCode};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::cdx::{CdxCapture, DEFAULT_CAPTURE_URL_TEMPLATE};
+use crate::gzip::gzip;
+use crate::mime::{extension_for_mime, fallback_extension, is_html};
+use crate::mirror::DiscoveredLinks;
+use crate::rate_limit::{BandwidthLimiter, HeaderRateLimiter, RateLimiter};
+use crate::retry::Backoff;
+use crate::s3::{S3Config, put_object};
+use crate::tar::TarWriter;
+use crate::timestamp::{DEFAULT_TIMESTAMP_FORMAT, format_timestamp};
+use crate::toolbar::strip_wayback_artifacts;
+use crate::user_agent::UserAgentRotation;
+use crate::zip::ZipWriter;
+
+/// User-Agent sent with download requests unless another one is configured.
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36";
+
+/// Settings shared by every download of a run.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Directory captures are saved to.
+    pub output_dir: PathBuf,
+    /// How many times a transient failure is retried.
+    pub retries: u32,
+    /// Remove the Wayback Machine toolbar from HTML before saving it.
+    pub strip_toolbar: bool,
+    /// Timeout for each download request.
+    pub timeout: Duration,
+    /// Mirror the original host and path as subdirectories of `output_dir`.
+    pub preserve_path: bool,
+    /// User-Agent header sent with every download request.
+    pub user_agent: String,
+    /// User-Agents download requests take turns with instead of
+    /// `user_agent`.
+    pub user_agent_rotation: Option<Arc<UserAgentRotation>>,
+    /// Pauses between the attempts of a failed request.
+    pub backoff: Backoff,
+    /// Shared limiter every request waits on, unlimited when `None`.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Pauses requests while the archive's rate limit headers say it's
+    /// nearly used up.
+    pub header_rate_limiter: Option<Arc<HeaderRateLimiter>>,
+    /// Requests that timed out so far, which `--concurrency-auto` backs off
+    /// on.
+    pub timeouts: Arc<AtomicUsize>,
+    /// Throttles reading response bodies to a number of bytes per second
+    /// shared by all downloads.
+    pub bandwidth_limiter: Option<Arc<BandwidthLimiter>>,
+    /// Upload captures to this bucket instead of writing them to
+    /// `output_dir`.
+    pub s3: Option<S3Config>,
+    /// Write the response headers to `<filename>.headers.json` next to every
+    /// downloaded file.
+    pub save_headers: bool,
+    /// Start file names with the capture timestamp. Without it captures of
+    /// the same URL are told apart by numbering them through `filenames`.
+    pub timestamp_prefix: bool,
+    /// Names already given to captures of this run.
+    pub filenames: Arc<FilenameRegistry>,
+    /// Pattern for file names, see [`validate_filename_template`]. Takes
+    /// precedence over `preserve_path` and `timestamp_prefix`.
+    pub filename_template: Option<String>,
+    /// Store captures gzip compressed, with `.gz` added to their names.
+    pub gzip: bool,
+    /// Collects the same domain links of every downloaded HTML capture.
+    pub discovered_links: Option<Arc<DiscoveredLinks>>,
+    /// Where captures are downloaded from, see [`CdxCapture::capture_url`].
+    pub capture_url_template: String,
+    /// strftime style format of the timestamp in file names, see
+    /// [`format_timestamp`].
+    pub timestamp_format: String,
+    /// Extensions for lowercase MIME types that take precedence over
+    /// [`extension_for_mime`].
+    pub content_type_map: HashMap<String, String>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            output_dir: PathBuf::from("."),
+            retries: 3,
+            backoff: Backoff::default(),
+            strip_toolbar: false,
+            timeout: Duration::from_secs(15),
+            preserve_path: false,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            user_agent_rotation: None,
+            rate_limiter: None,
+            header_rate_limiter: None,
+            bandwidth_limiter: None,
+            timeouts: Arc::default(),
+            s3: None,
+            save_headers: false,
+            timestamp_prefix: true,
+            filenames: Arc::default(),
+            filename_template: None,
+            gzip: false,
+            discovered_links: None,
+            capture_url_template: DEFAULT_CAPTURE_URL_TEMPLATE.to_string(),
+            timestamp_format: DEFAULT_TIMESTAMP_FORMAT.to_string(),
+            content_type_map: HashMap::new(),
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// User-Agent to send with the next download request.
+    pub fn request_user_agent(&self) -> &str {
+        match &self.user_agent_rotation {
+            Some(rotation) => rotation.next(),
+            None => &self.user_agent,
+        }
+    }
+
+    /// URL `capture` is downloaded from.
+    pub fn capture_url(&self, capture: &CdxCapture) -> String {
+        capture.capture_url(&self.capture_url_template)
+    }
+
+    /// File extension for a MIME type, from `content_type_map` or else
+    /// [`extension_for_mime`].
+    pub fn extension_for(&self, mime: &str) -> Option<&str> {
+        let essence = mime.split(';').next().unwrap_or_default().trim();
+        self.content_type_map
+            .get(&essence.to_ascii_lowercase())
+            .map(String::as_str)
+            .or_else(|| extension_for_mime(mime))
+    }
+
+    /// Extension a capture of MIME type `mime` is saved with, from
+    /// [`extension_for`](Self::extension_for) or else [`fallback_extension`],
+    /// so binary types don't end up as `.html`.
+    pub fn file_extension(&self, mime: &str) -> &str {
+        self.extension_for(mime)
+            .unwrap_or_else(|| fallback_extension(mime))
+    }
+}
+
+/// File names handed out during a run, so captures that end up with the same
+/// name are numbered instead of overwriting each other.
+#[derive(Debug, Default)]
+pub struct FilenameRegistry {
+    state: std::sync::Mutex<RegistryState>,
+}
+
+#[derive(Debug, Default)]
+struct RegistryState {
+    claimed: HashMap<String, String>,
+    names: HashSet<String>,
+}
+
+impl FilenameRegistry {
+    /// Returns `filename`, or the first of `<stem>_2.<ext>`, `<stem>_3.<ext>`
+    /// and so on that wasn't claimed yet. A capture asking for the same name
+    /// again gets the one it was given the first time.
+    pub fn claim(&self, capture: &CdxCapture, filename: String) -> String {
+        let key = format!(
+            "{} {} {}",
+            capture.timestamp, capture.original_url, filename
+        );
+        let mut state = self.state.lock().unwrap();
+        if let Some(claimed) = state.claimed.get(&key) {
+            return claimed.clone();
+        }
+        let claimed = Self::unused_name(&state.names, filename);
+        state.names.insert(claimed.clone());
+        state.claimed.insert(key, claimed.clone());
+        claimed
+    }
+
+    fn unused_name(names: &HashSet<String>, filename: String) -> String {
+        if !names.contains(&filename) {
+            return filename;
+        }
+
+        let (dir, name) = match filename.rsplit_once('/') {
+            Some((dir, name)) => (format!("{}/", dir), name),
+            None => (String::new(), filename.as_str()),
+        };
+        let (stem, extension) = name.rsplit_once('.').unwrap_or((name, "html"));
+        let mut number = 2;
+        loop {
+            let suffix = format!("_{}", number);
+            // The number must survive truncation or every candidate would
+            // come out the same
+            let max_stem = MAX_FILENAME_BYTES.saturating_sub(extension.len() + 1 + suffix.len());
+            let mut end = stem.len().min(max_stem);
+            while !stem.is_char_boundary(end) {
+                end -= 1;
+            }
+            let candidate = format!("{}{}{}.{}", dir, &stem[..end], suffix, extension);
+            if !names.contains(&candidate) {
+                return candidate;
+            }
+            number += 1;
+        }
+    }
+}
+
+/// Sends a GET request for a capture, retrying transient failures after the
+/// pauses of `options.backoff`.
+///
+/// Besides 2xx responses, a response with the same status code the capture
+/// was archived with counts as success, so archived error pages can be
+/// downloaded too.
+///
+/// With `resume_from` bytes already downloaded only the rest is asked for.
+/// A `206 Partial Content` response continues right after them, any other
+/// one holds the whole body.
+async fn fetch_with_retries(
+    client: &Client,
+    capture: &CdxCapture,
+    options: &DownloadOptions,
+    mut resume_from: usize,
+) -> Result<Response> {
+    let url = &options.capture_url(capture);
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_default();
+    let archived_status: Option<u16> = capture
+        .status_code
+        .as_deref()
+        .and_then(|status| status.parse().ok());
+    let retries = options.retries;
+    let mut attempt = 0;
+    loop {
+        if let Some(rate_limiter) = &options.rate_limiter {
+            rate_limiter.acquire(&host).await;
+        }
+        if let Some(header_rate_limiter) = &options.header_rate_limiter {
+            header_rate_limiter.wait().await;
+        }
+
+        let started = Instant::now();
+        let mut request = client
+            .get(url)
+            .header("User-Agent", options.request_user_agent())
+            .timeout(options.timeout);
+        if resume_from > 0 {
+            request = request.header(RANGE, format!("bytes={}-", resume_from));
+        }
+        let result = request.send().await;
+
+        if let Ok(response) = &result {
+            tracing::debug!(
+                "{} responded with {} in {:?}",
+                url,
+                response.status(),
+                started.elapsed()
+            );
+            if let Some(header_rate_limiter) = &options.header_rate_limiter {
+                header_rate_limiter.observe(response.headers()).await;
+            }
+        }
+
+        // A range the archive can't serve, e.g. of a capture that is shorter
+        // than the partial file, is asked for again in full
+        if resume_from > 0
+            && let Ok(response) = &result
+            && (response.status() == StatusCode::RANGE_NOT_SATISFIABLE
+                || (response.status() == StatusCode::PARTIAL_CONTENT
+                    && !continues_at(response, resume_from)))
+        {
+            tracing::debug!(
+                "{} can't resume after {} bytes, starting over",
+                url,
+                resume_from
+            );
+            resume_from = 0;
+            continue;
+        }
+
+        // Rate limiting and server errors are usually transient, anything
+        // else in the 4xx range won't change on a second attempt.
+        let (error, retryable) = match result {
+            Ok(response)
+                if response.status().is_success()
+                    || Some(response.status().as_u16()) == archived_status =>
+            {
+                #[cfg(feature = "opentelemetry")]
+                tracing::Span::current().record("status_code", response.status().as_u16());
+                return Ok(response);
+            }
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+                (miette!("HTTP {}", status), retryable)
+            }
+            Err(e) => {
+                if e.is_timeout() {
+                    options.timeouts.fetch_add(1, Ordering::Relaxed);
+                }
+                (miette!("Failed to fetch URL: {}", e), true)
+            }
+        };
+
+        if !retryable || attempt >= retries {
+            return Err(error);
+        }
+
+        attempt += 1;
+        let delay = options.backoff.delay(attempt);
+        tracing::warn!(
+            "Retrying {} in {:.1?} ({}/{}): {}",
+            url,
+            delay,
+            attempt,
+            retries,
+            error
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Longest file name most filesystems accept
+const MAX_FILENAME_BYTES: usize = 255;
+
+/// Path relative to `options.output_dir` that [`download_capture`] saves a
+/// capture as, with an extension matching the capture's CDX MIME type.
+pub fn capture_filename(capture: &CdxCapture, options: &DownloadOptions) -> String {
+    let extension = capture
+        .mime_type
+        .as_deref()
+        .map_or("html", |mime| options.file_extension(mime));
+    filename_with_extension(capture, options, extension)
+}
+
+fn filename_with_extension(
+    capture: &CdxCapture,
+    options: &DownloadOptions,
+    extension: &str,
+) -> String {
+    let filename = uncompressed_filename(capture, options, extension);
+    if !options.gzip {
+        return filename;
+    }
+
+    // Room for the suffix comes out of the stem, like for any other extension
+    let (dir, name) = match filename.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), filename.as_str()),
+    };
+    let name = match name.rsplit_once('.') {
+        Some((stem, extension)) => truncate_filename(stem, &format!("{}.gz", extension)),
+        None => truncate_filename(name, "gz"),
+    };
+    format!("{}{}", dir, name)
+}
+
+fn uncompressed_filename(
+    capture: &CdxCapture,
+    options: &DownloadOptions,
+    extension: &str,
+) -> String {
+    if let Some(template) = &options.filename_template {
+        let timestamp = filename_timestamp(capture, options);
+        let filename = template_filename(template, capture, &timestamp, extension);
+        return if options.timestamp_format == DEFAULT_TIMESTAMP_FORMAT {
+            filename
+        } else {
+            options.filenames.claim(capture, filename)
+        };
+    }
+    let timestamp = options
+        .timestamp_prefix
+        .then(|| filename_timestamp(capture, options));
+    let timestamp = timestamp.as_deref();
+    let preserved = options
+        .preserve_path
+        .then(|| preserved_path(&capture.original_url, timestamp, extension))
+        .flatten();
+    let filename = match (preserved, timestamp) {
+        (Some(path), _) => path,
+        (None, Some(timestamp)) => make_filename(timestamp, &capture.original_url, extension),
+        (None, None) => flat_filename(None, &capture.original_url, extension),
+    };
+    // Other formats can give two captures the same name, e.g. %Y-%m-%d for
+    // two captures of one day, so those are numbered too
+    if options.timestamp_prefix && options.timestamp_format == DEFAULT_TIMESTAMP_FORMAT {
+        filename
+    } else {
+        options.filenames.claim(capture, filename)
+    }
+}
+
+/// The capture timestamp in `options.timestamp_format`, with characters
+/// Windows doesn't allow in file names, like the `:` of `%H:%M`, replaced on
+/// every OS so the names stay the same everywhere.
+fn filename_timestamp(capture: &CdxCapture, options: &DownloadOptions) -> String {
+    format_timestamp(&capture.timestamp, &options.timestamp_format)
+        .chars()
+        .map(|c| if is_illegal_on_windows(c) { '-' } else { c })
+        .collect()
+}
+
+/// Flat file name for a capture, e.g. `20230115123045_example.com_blog_post.html`
+/// for `https://example.com/blog/post` archived at `20230115123045`.
+///
+/// The host and percent decoded path of `original_url` are joined with `_`,
+/// followed by the query string if there is one, so `/page?id=1` and
+/// `/page?id=2` get names of their own. A path that already ends in the
+/// extension, like `/robots.txt`, doesn't get it twice. Characters the
+/// target OS doesn't allow in file names are replaced and the result is cut
+/// to fit in 255 bytes.
+pub fn make_filename(timestamp: &str, original_url: &str, extension: &str) -> String {
+    flat_filename(Some(timestamp), original_url, extension)
+}
+
+/// [`make_filename`], without the timestamp when it is `None`.
+fn flat_filename(timestamp: Option<&str>, original_url: &str, extension: &str) -> String {
+    let (name, query) = url_filename_parts(original_url);
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, existing)) if !stem.is_empty() && has_extension(&name, extension) => {
+            (stem, existing)
+        }
+        _ => (name.as_str(), extension),
+    };
+    let mut stem = match timestamp {
+        Some(timestamp) => format!("{}_{}", timestamp, stem),
+        None => stem.to_string(),
+    };
+    if let Some(query) = query {
+        stem.push('_');
+        stem.push_str(&query);
+    }
+    truncate_filename(&stem, extension)
+}
+
+/// The host and path part of [`make_filename`], and its query string if it
+/// has one.
+fn url_filename_parts(original_url: &str) -> (String, Option<String>) {
+    let (host, path, query) = match Url::parse(original_url) {
+        Ok(url) => (
+            url.host_str().unwrap_or_default().to_string(),
+            url.path().to_string(),
+            url.query().map(str::to_string),
+        ),
+        Err(_) => {
+            let without_scheme = original_url
+                .split_once("://")
+                .map_or(original_url, |(_, rest)| rest);
+            let (path, query) = match without_scheme.split_once('?') {
+                Some((path, query)) => (path, Some(query.to_string())),
+                None => (without_scheme, None),
+            };
+            (String::new(), path.to_string(), query)
+        }
+    };
+    let decode = |value: String| {
+        urlencoding::decode(&value)
+            .map(|decoded| decoded.into_owned())
+            .unwrap_or(value)
+    };
+
+    let name = format!("{}{}", host, decode(path).trim_end_matches('/'));
+    let name = sanitize_segment(&name.replace('/', "_"));
+    let name = if name.is_empty() {
+        "index".to_string()
+    } else {
+        name
+    };
+    let query = query
+        .filter(|query| !query.is_empty())
+        .map(|query| sanitize_segment(&decode(query).replace('/', "_")));
+    (name, query)
+}
+
+/// Whether the file name `name` already ends in `extension`, or a common
+/// spelling of it like `.htm` for `html`.
+fn has_extension(name: &str, extension: &str) -> bool {
+    name.ends_with(&format!(".{}", extension))
+        || (extension == "html" && name.ends_with(".htm"))
+        || (extension == "jpg" && name.ends_with(".jpeg"))
+}
+
+/// Placeholders a file name template can use.
+pub const TEMPLATE_PLACEHOLDERS: [&str; 8] = [
+    "timestamp",
+    "year",
+    "month",
+    "day",
+    "host",
+    "path",
+    "ext",
+    "digest",
+];
+
+/// Checks a file name template like `{host}/{year}/{month}/{timestamp}_{path}.{ext}`.
+/// Every `{...}` has to be one of [`TEMPLATE_PLACEHOLDERS`], and
+/// `{timestamp}` or `{digest}` has to be among them so two captures of the
+/// same URL don't end up with the same name.
+pub fn validate_filename_template(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            return Err(miette!("Unclosed {{ in --output-template: {}", template));
+        };
+        let placeholder = &rest[start + 1..start + end];
+        if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+            return Err(miette!(
+                help = format!(
+                    "Valid placeholders are {}",
+                    TEMPLATE_PLACEHOLDERS
+                        .map(|name| format!("{{{}}}", name))
+                        .join(", ")
+                ),
+                "Unknown placeholder {{{}}} in --output-template",
+                placeholder
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    if !template.contains("{timestamp}") && !template.contains("{digest}") {
+        return Err(miette!(
+            help = "Add {timestamp} or {digest}, e.g. {host}/{timestamp}_{path}.{ext}",
+            "--output-template has to include {{timestamp}} or {{digest}} to keep file names \
+             unique"
+        ));
+    }
+    Ok(())
+}
+
+/// Fills in `template` for a capture. `/` separates directories, every
+/// other character the OS doesn't allow is replaced and each part is cut to
+/// fit in [`MAX_FILENAME_BYTES`].
+fn template_filename(
+    template: &str,
+    capture: &CdxCapture,
+    formatted_timestamp: &str,
+    extension: &str,
+) -> String {
+    let url = Url::parse(&capture.original_url).ok();
+    let host = url
+        .as_ref()
+        .and_then(|url| url.host_str())
+        .unwrap_or_default();
+    let path = url.as_ref().map(|url| url.path()).unwrap_or_default();
+    let path = urlencoding::decode(path)
+        .map(|decoded| decoded.into_owned())
+        .unwrap_or_else(|_| path.to_string());
+    let path = sanitize_segment(&path.trim_matches('/').replace('/', "_"));
+    let timestamp = &capture.timestamp;
+    let part = |range: std::ops::Range<usize>| timestamp.get(range).unwrap_or_default();
+
+    let filled = template
+        .replace("{timestamp}", formatted_timestamp)
+        .replace("{year}", part(0..4))
+        .replace("{month}", part(4..6))
+        .replace("{day}", part(6..8))
+        .replace("{host}", &sanitize_segment(host))
+        .replace("{path}", if path.is_empty() { "index" } else { &path })
+        .replace("{ext}", extension)
+        .replace("{digest}", capture.digest.as_deref().unwrap_or("nodigest"));
+
+    filled
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            let segment = sanitize_segment(segment);
+            match segment.rsplit_once('.') {
+                Some((stem, extension)) if !stem.is_empty() => truncate_filename(stem, extension),
+                _ => truncate_filename(&segment, "")
+                    .trim_end_matches('.')
+                    .to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Joins `stem` and `extension`, cutting `stem` short at a character
+/// boundary if the result would be longer than [`MAX_FILENAME_BYTES`].
+fn truncate_filename(stem: &str, extension: &str) -> String {
+    let max_stem = MAX_FILENAME_BYTES.saturating_sub(extension.len() + 1);
+    let mut end = stem.len().min(max_stem);
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}.{}", &stem[..end], extension)
+}
+
+/// Mirrors the original URL, e.g. a capture of
+/// `https://example.com/blog/2020/post.html` becomes
+/// `example.com/blog/2020/<timestamp>_post.html`, or
+/// `example.com/blog/2020/post.html` without a timestamp.
+fn preserved_path(original_url: &str, timestamp: Option<&str>, extension: &str) -> Option<String> {
+    let original = Url::parse(original_url).ok()?;
+    let host = original.host_str()?;
+
+    let mut segments: Vec<String> = original
+        .path_segments()
+        .map(|segments| segments.map(sanitize_segment).collect())
+        .unwrap_or_default();
+    let name = match segments.pop() {
+        Some(name) if !name.is_empty() => name,
+        _ => "index".to_string(),
+    };
+    let has_extension = has_extension(&name, extension);
+    let name = match timestamp {
+        Some(timestamp) => format!("{}_{}", timestamp, name),
+        None => name,
+    };
+    let name = if has_extension {
+        match name.rsplit_once('.') {
+            Some((stem, extension)) => truncate_filename(stem, extension),
+            None => name,
+        }
+    } else {
+        truncate_filename(&name, extension)
+    };
+
+    let mut path = vec![sanitize_segment(host)];
+    path.extend(segments.into_iter().filter(|segment| !segment.is_empty()));
+    path.push(name);
+    Some(path.join("/"))
+}
+
+/// Makes a URL path segment safe to use as a directory or file name on the
+/// target OS.
+fn sanitize_segment(segment: &str) -> String {
+    match segment {
+        "." | ".." => "_".to_string(),
+        _ => segment
+            .chars()
+            .map(|c| if is_illegal_in_filename(c) { '_' } else { c })
+            .collect(),
+    }
+}
+
+fn is_illegal_in_filename(c: char) -> bool {
+    if cfg!(windows) {
+        is_illegal_on_windows(c)
+    } else {
+        c == '/' || c == '\0'
+    }
+}
+
+fn is_illegal_on_windows(c: char) -> bool {
+    c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+}
+
+/// A downloaded capture that hasn't been stored anywhere yet.
+#[derive(Debug, Clone)]
+pub struct FetchedCapture {
+    /// Name the capture is stored under, see [`capture_filename`].
+    pub filename: String,
+    pub mime_type: String,
+    /// Response body, with the toolbar removed if `options.strip_toolbar` is
+    /// set and it is HTML.
+    pub body: Vec<u8>,
+    /// Response headers as JSON, only with `options.save_headers`.
+    pub headers: Option<String>,
+}
+
+impl FetchedCapture {
+    /// Name of the file the headers are stored in.
+    pub fn headers_filename(&self) -> String {
+        format!("{}.headers.json", self.filename)
+    }
+}
+
+/// Downloads a capture into memory.
+///
+/// The file extension of its name comes from the CDX MIME type, or the
+/// response's `Content-Type` when the CDX row doesn't have one.
+pub async fn fetch_capture(
+    client: &Client,
+    capture: &CdxCapture,
+    options: &DownloadOptions,
+) -> Result<FetchedCapture> {
+    let (fetched, _) = fetch_into(client, capture, options, None).await?;
+    Ok(fetched)
+}
+
+/// Like [`fetch_capture`], but appends the body to `part` as it arrives when
+/// there is one, after the bytes the file already holds. Also returns whether
+/// the body is still the one that was downloaded, which it isn't once the
+/// toolbar was stripped.
+async fn fetch_into(
+    client: &Client,
+    capture: &CdxCapture,
+    options: &DownloadOptions,
+    part: Option<&mut PartFile>,
+) -> Result<(FetchedCapture, bool)> {
+    if let Some(part) = &part
+        && part.len > 0
+    {
+        tracing::info!(
+            "Resuming {} after the {} bytes in {}",
+            options.capture_url(capture),
+            part.len,
+            part.path.display()
+        );
+    }
+    let resume_from = part.as_ref().map_or(0, |part| part.len);
+    let response = fetch_with_retries(client, capture, options, resume_from).await?;
+
+    let headers = options
+        .save_headers
+        .then(|| headers_json(response.headers()));
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let mime_type = capture
+        .mime_type
+        .as_deref()
+        .filter(|mime| options.extension_for(mime).is_some())
+        .or(content_type.as_deref())
+        .unwrap_or("text/html")
+        .to_string();
+
+    let body = read_body(client, capture, options, response, part).await?;
+
+    let stripped = options.strip_toolbar && is_html(&mime_type);
+    let body = if stripped {
+        strip_wayback_artifacts(&String::from_utf8_lossy(&body)).into_bytes()
+    } else {
+        body
+    };
+    if let Some(links) = &options.discovered_links
+        && is_html(&mime_type)
+    {
+        links.add(&String::from_utf8_lossy(&body), &capture.original_url);
+    }
+
+    let extension = options.file_extension(&mime_type);
+    let filename = filename_with_extension(capture, options, extension);
+
+    let fetched = FetchedCapture {
+        filename,
+        mime_type,
+        body,
+        headers,
+    };
+    Ok((fetched, !stripped))
+}
+
+/// Suffix of the file a capture is downloaded into, next to the file it is
+/// saved as, until it is complete.
+pub const PART_FILE_SUFFIX: &str = ".part";
+
+/// Name of the file in the output directory that [`download_capture`] reads
+/// a capture into until it is complete. It only depends on the capture, so a
+/// later run carries on with the file an earlier one left behind.
+fn part_filename(capture: &CdxCapture) -> String {
+    // Tells apart URLs whose file name stems are the same
+    let hash = ring::digest::digest(&ring::digest::SHA256, capture.original_url.as_bytes());
+    let hash: String = hash.as_ref()[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    make_filename(
+        &capture.timestamp,
+        &capture.original_url,
+        &format!("{}{}", hash, PART_FILE_SUFFIX),
+    )
+}
+
+/// A file that the body of a capture is appended to as it arrives, so the
+/// bytes already received survive a dropped connection and a later attempt
+/// can ask for the rest.
+#[derive(Debug)]
+struct PartFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes in the file.
+    len: usize,
+}
+
+impl PartFile {
+    /// Opens the partial file at `path` to append to, creating it and its
+    /// directory if needed.
+    async fn open(path: PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .into_diagnostic()
+                .context(format!("Failed to create directory: {}", parent.display()))?;
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .into_diagnostic()
+            .context(format!("Failed to open {}", path.display()))?;
+        let len = file
+            .metadata()
+            .await
+            .into_diagnostic()
+            .context(format!("Failed to read {}", path.display()))?
+            .len() as usize;
+        Ok(Self { path, file, len })
+    }
+
+    /// Appends `bytes`. The file is removed when they can't be written, what
+    /// it holds is no longer the start of the body.
+    async fn append(&mut self, bytes: &[u8]) -> Result<()> {
+        if let Err(e) = self.file.write_all(bytes).await {
+            let _ = tokio::fs::remove_file(&self.path).await;
+            return Err(e)
+                .into_diagnostic()
+                .context(format!("Failed to write {}", self.path.display()));
+        }
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    /// Empties the file for a body that starts over.
+    async fn truncate(&mut self) -> Result<()> {
+        self.file
+            .set_len(0)
+            .await
+            .into_diagnostic()
+            .context(format!("Failed to truncate {}", self.path.display()))?;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Waits for every write to reach the file.
+    async fn flush(&mut self) -> Result<()> {
+        self.file
+            .flush()
+            .await
+            .into_diagnostic()
+            .context(format!("Failed to write {}", self.path.display()))
+    }
+}
+
+/// Whether `response` is a `206 Partial Content` holding the body from byte
+/// `offset` on.
+fn continues_at(response: &Response, offset: usize) -> bool {
+    let start = format!("bytes {}-", offset);
+    response.status() == StatusCode::PARTIAL_CONTENT
+        && response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|range| range.starts_with(&start))
+}
+
+/// Reads the body of a capture's `response`, at the pace of
+/// `options.bandwidth_limiter` when there is one, and appends it to `part`
+/// if given. A `206 Partial Content` response continues the bytes already in
+/// `part`, which are kept when the read fails.
+///
+/// When the connection drops midway and the archive announced
+/// `Accept-Ranges: bytes`, the rest is requested with a `Range` header
+/// instead of starting over, up to `options.retries` times.
+async fn read_body(
+    client: &Client,
+    capture: &CdxCapture,
+    options: &DownloadOptions,
+    mut response: Response,
+    mut part: Option<&mut PartFile>,
+) -> Result<Vec<u8>> {
+    let url = options.capture_url(capture);
+    let host = Url::parse(&url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_default();
+    let resumable = response
+        .headers()
+        .get(ACCEPT_RANGES)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("bytes"));
+    let mut body = Vec::new();
+    if let Some(part) = part.as_deref_mut() {
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            body = tokio::fs::read(&part.path)
+                .await
+                .into_diagnostic()
+                .context(format!("Failed to read {}", part.path.display()))?;
+        } else {
+            part.truncate().await?;
+        }
+    }
+    let mut attempt = 0;
+    loop {
+        let error = loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    if let Some(bandwidth_limiter) = &options.bandwidth_limiter {
+                        bandwidth_limiter.consume(chunk.len()).await;
+                    }
+                    if let Some(part) = part.as_deref_mut() {
+                        part.append(&chunk).await?;
+                    }
+                    body.extend_from_slice(&chunk);
+                }
+                Ok(None) => {
+                    if let Some(part) = part.as_deref_mut() {
+                        part.flush().await?;
+                    }
+                    return Ok(body);
+                }
+                Err(e) => {
+                    if e.is_timeout() {
+                        options.timeouts.fetch_add(1, Ordering::Relaxed);
+                    }
+                    break e;
+                }
+            }
+        };
+        if !resumable || body.is_empty() || attempt >= options.retries {
+            // The bytes received so far stay in the partial file for the
+            // next attempt
+            if let Some(part) = part.as_deref_mut() {
+                part.flush().await?;
+            }
+            return Err(error)
+                .into_diagnostic()
+                .context("Failed to read response");
+        }
+
+        attempt += 1;
+        let delay = options.backoff.delay(attempt);
+        tracing::warn!(
+            "Resuming {} after {} bytes in {:.1?} ({}/{}): {}",
+            url,
+            body.len(),
+            delay,
+            attempt,
+            options.retries,
+            error
+        );
+        tokio::time::sleep(delay).await;
+        if let Some(rate_limiter) = &options.rate_limiter {
+            rate_limiter.acquire(&host).await;
+        }
+        if let Some(header_rate_limiter) = &options.header_rate_limiter {
+            header_rate_limiter.wait().await;
+        }
+        response = client
+            .get(&url)
+            .header("User-Agent", options.request_user_agent())
+            .header(RANGE, format!("bytes={}-", body.len()))
+            .timeout(options.timeout)
+            .send()
+            .await
+            .into_diagnostic()
+            .context("Failed to resume download")?;
+
+        // The rest has to start right where the body stopped, an archive
+        // that ignores the range sends all of it again
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT if continues_at(&response, body.len()) => {}
+            StatusCode::OK => {
+                body.clear();
+                if let Some(part) = part.as_deref_mut() {
+                    part.truncate().await?;
+                }
+            }
+            status => return Err(miette!("Failed to resume download: HTTP {}", status)),
+        }
+    }
+}
+
+/// Downloads a capture into `options.output_dir` and returns the name of the
+/// file it was saved as along with its size. With `options.s3` set the
+/// capture is uploaded to the bucket instead, under the same name after the
+/// configured prefix.
+pub async fn download_capture(
+    client: &Client,
+    capture: &CdxCapture,
+    options: &DownloadOptions,
+) -> Result<(String, u64)> {
+    let started = Instant::now();
+    if let Some(s3) = &options.s3 {
+        let fetched = fetch_capture(client, capture, options).await?;
+        let FetchedCapture {
+            filename,
+            mime_type,
+            body,
+            headers,
+        } = fetched;
+        let (body, mime_type) = if options.gzip {
+            (gzip(&body), "application/gzip".to_string())
+        } else {
+            (body, mime_type)
+        };
+        let size = body.len();
+        put_object(client, s3, &s3.key(&filename), body, &mime_type).await?;
+        if let Some(headers) = headers {
+            let key = s3.key(&format!("{}.headers.json", filename));
+            put_object(client, s3, &key, headers.into_bytes(), "application/json").await?;
+        }
+        tracing::debug!(
+            "Uploaded {} bytes as {} in {:?}",
+            size,
+            filename,
+            started.elapsed()
+        );
+        return Ok((filename, size as u64));
+    }
+
+    let mut part = PartFile::open(options.output_dir.join(part_filename(capture))).await?;
+    let (fetched, as_downloaded) = match fetch_into(client, capture, options, Some(&mut part)).await
+    {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            // Nothing to carry on with, e.g. after an HTTP error
+            if part.len == 0 {
+                let PartFile { path, file, .. } = part;
+                drop(file);
+                let _ = tokio::fs::remove_file(&path).await;
+            }
+            return Err(e);
+        }
+    };
+    let PartFile {
+        path: part_path,
+        file,
+        ..
+    } = part;
+    drop(file);
+
+    let size = if as_downloaded && !options.gzip {
+        let filepath = options.output_dir.join(&fetched.filename);
+        if let Some(parent) = filepath.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .into_diagnostic()
+                .context(format!("Failed to create directory: {}", parent.display()))?;
+        }
+        tokio::fs::rename(&part_path, &filepath)
+            .await
+            .into_diagnostic()
+            .context(format!("Failed to move {} into place", part_path.display()))?;
+        write_headers(&fetched, options).await?;
+        fetched.body.len() as u64
+    } else {
+        let size = write_capture(&fetched, options).await?;
+        let _ = tokio::fs::remove_file(&part_path).await;
+        size
+    };
+    tracing::debug!(
+        "Wrote {} bytes to {} in {:?}",
+        size,
+        fetched.filename,
+        started.elapsed()
+    );
+
+    Ok((fetched.filename, size))
+}
+
+/// Writes a fetched capture, and its headers when there are any, below
+/// `options.output_dir`. Returns the number of bytes written for the
+/// capture, which is less than its size with `options.gzip`.
+pub(crate) async fn write_capture(
+    fetched: &FetchedCapture,
+    options: &DownloadOptions,
+) -> Result<u64> {
+    let filepath = options.output_dir.join(&fetched.filename);
+    if let Some(parent) = filepath.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .into_diagnostic()
+            .context(format!("Failed to create directory: {}", parent.display()))?;
+    }
+
+    let compressed = options.gzip.then(|| gzip(&fetched.body));
+    let body = compressed.as_deref().unwrap_or(&fetched.body);
+    let mut file = File::create(&filepath)
+        .await
+        .into_diagnostic()
+        .context("Failed to create file")?;
+    // Flushing waits for the write to reach the file, which dropping the
+    // file doesn't
+    let written = async {
+        file.write_all(body).await?;
+        file.flush().await
+    };
+    if let Err(e) = written.await {
+        // A truncated file would pass for the capture, e.g. to --skip-existing
+        drop(file);
+        let _ = tokio::fs::remove_file(&filepath).await;
+        return Err(e).into_diagnostic().context("Failed to write file");
+    }
+
+    write_headers(fetched, options).await?;
+    Ok(body.len() as u64)
+}
+
+/// Writes the headers of a fetched capture next to it, if there are any.
+async fn write_headers(fetched: &FetchedCapture, options: &DownloadOptions) -> Result<()> {
+    if let Some(headers) = &fetched.headers {
+        let headers_filename = fetched.headers_filename();
+        tokio::fs::write(options.output_dir.join(&headers_filename), headers)
+            .await
+            .into_diagnostic()
+            .context(format!("Failed to write {}", headers_filename))?;
+    }
+    Ok(())
+}
+
+/// Downloads a capture and adds it to `zip`, together with its headers if
+/// `options.save_headers` is set. The capture is returned so callers can
+/// still look at its contents.
+pub async fn download_into_zip(
+    client: &Client,
+    capture: &CdxCapture,
+    zip: &Mutex<ZipWriter>,
+    options: &DownloadOptions,
+) -> Result<FetchedCapture> {
+    let fetched = fetch_capture(client, capture, options).await?;
+
+    let mut zip = zip.lock().await;
+    zip.add_file(&fetched.filename, &fetched.body, &capture.timestamp)?;
+    if let Some(headers) = &fetched.headers {
+        zip.add_file(
+            &fetched.headers_filename(),
+            headers.as_bytes(),
+            &capture.timestamp,
+        )?;
+    }
+
+    tracing::debug!(
+        "Added {} bytes to the archive as {}",
+        fetched.body.len(),
+        fetched.filename
+    );
+
+    Ok(fetched)
+}
+
+/// Downloads a capture and appends it to `tar`, together with its headers if
+/// `options.save_headers` is set.
+pub async fn download_into_tar(
+    client: &Client,
+    capture: &CdxCapture,
+    tar: &Mutex<TarWriter>,
+    options: &DownloadOptions,
+) -> Result<FetchedCapture> {
+    let fetched = fetch_capture(client, capture, options).await?;
+
+    let mut tar = tar.lock().await;
+    tar.add_file(&fetched.filename, &fetched.body, &capture.timestamp)?;
+    if let Some(headers) = &fetched.headers {
+        tar.add_file(
+            &fetched.headers_filename(),
+            headers.as_bytes(),
+            &capture.timestamp,
+        )?;
+    }
+
+    tracing::debug!(
+        "Added {} bytes to the tar archive as {}",
+        fetched.body.len(),
+        fetched.filename
+    );
+
+    Ok(fetched)
+}
+
+/// Response headers as a JSON object. Headers sent more than once become an
+/// array of their values.
+fn headers_json(headers: &HeaderMap) -> String {
+    let mut object = Map::new();
+    for name in headers.keys() {
+        let mut values: Vec<Value> = headers
+            .get_all(name)
+            .iter()
+            .map(|value| Value::String(String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect();
+        let value = if values.len() == 1 {
+            values.remove(0)
+        } else {
+            Value::Array(values)
+        };
+        object.insert(name.as_str().to_string(), value);
+    }
+    serde_json::to_string_pretty(&object).unwrap_or_default()
+}
+
+/// Downloads a capture and appends it to `warc_file` as a WARC/1.0
+/// `response` record holding the HTTP status line, headers and body.
+/// Returns the size of the record.
+pub async fn download_as_warc_record(
+    client: &Client,
+    capture: &CdxCapture,
+    warc_file: &Mutex<File>,
+    options: &DownloadOptions,
+) -> Result<u64> {
+    let response = fetch_with_retries(client, capture, options, 0).await?;
+
+    let mut http_block = format!("{:?} {}\r\n", response.version(), response.status());
+    for (name, value) in response.headers() {
+        http_block.push_str(name.as_str());
+        http_block.push_str(": ");
+        http_block.push_str(&String::from_utf8_lossy(value.as_bytes()));
+        http_block.push_str("\r\n");
+    }
+    http_block.push_str("\r\n");
+    let html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_html);
+
+    let body = read_body(client, capture, options, response, None).await?;
+    if let Some(links) = &options.discovered_links
+        && html
+    {
+        links.add(&String::from_utf8_lossy(&body), &capture.original_url);
+    }
+
+    let mut block = http_block.into_bytes();
+    block.extend_from_slice(&body);
+
+    let header = format!(
+        "WARC/1.0\r\n\
+         WARC-Type: response\r\n\
+         WARC-Record-ID: <urn:uuid:{}>\r\n\
+         WARC-Date: {}\r\n\
+         WARC-Target-URI: {}\r\n\
+         Content-Type: application/http; msgtype=response\r\n\
+         Content-Length: {}\r\n\
+         \r\n",
+        random_uuid(),
+        warc_date(&capture.timestamp),
+        capture.original_url,
+        block.len()
+    );
+
+    let mut record = header.into_bytes();
+    record.extend_from_slice(&block);
+    record.extend_from_slice(b"\r\n\r\n");
+
+    // Records are written in one go under the lock so concurrent downloads
+    // never interleave inside the file.
+    let mut file = warc_file.lock().await;
+    file.write_all(&record)
+        .await
+        .into_diagnostic()
+        .context("Failed to write WARC record")?;
+
+    tracing::debug!(
+        "Appended {} byte WARC record for {}",
+        record.len(),
+        capture.original_url
+    );
+
+    Ok(record.len() as u64)
+}
+
+/// Converts a CDX timestamp to the ISO 8601 form WARC-Date expects, e.g.
+/// `20230115123045` becomes `2023-01-15T12:30:45Z`.
+fn warc_date(timestamp: &str) -> String {
+    let digits = format!("{:0<14}", timestamp);
+    format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &digits[0..4],
+        &digits[4..6],
+        &digits[6..8],
+        &digits[8..10],
+        &digits[10..12],
+        &digits[12..14]
+    )
+}
+
+/// Random (version 4) UUID for WARC-Record-ID.
+fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const TIMESTAMP: &str = "20230115103000";
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\xff\xfe";
+
+    /// Serves `body` as `content_type` to every request, returning the
+    /// capture URL template of the server.
+    async fn serve(content_type: &'static str, body: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n",
+                    content_type,
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            }
+        });
+        format!("http://{}/web/{{timestamp}}id_/{{url}}", address)
+    }
+
+    /// Serves `body` with `Accept-Ranges: bytes`, answering `Range: bytes=N-`
+    /// with the rest, but drops every connection after `drop_after` bytes.
+    async fn serve_dropping(body: &'static [u8], drop_after: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let read = stream.read(&mut request).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&request[..read]).to_ascii_lowercase();
+                let start: usize = request
+                    .split_once("range: bytes=")
+                    .and_then(|(_, range)| range.split('-').next()?.parse().ok())
+                    .unwrap_or_default();
+                let status = if start > 0 {
+                    format!(
+                        "206 Partial Content\r\nContent-Range: bytes {}-{}/{}",
+                        start,
+                        body.len() - 1,
+                        body.len()
+                    )
+                } else {
+                    "200 OK".to_string()
+                };
+                let rest = &body[start..];
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: image/png\r\nAccept-Ranges: bytes\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    rest.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&rest[..rest.len().min(drop_after)]).await;
+            }
+        });
+        format!("http://{}/web/{{timestamp}}id_/{{url}}", address)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("noway-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn capture(url: &str, mime_type: Option<&str>) -> CdxCapture {
+        CdxCapture {
+            url_key: None,
+            timestamp: TIMESTAMP.to_string(),
+            original_url: url.to_string(),
+            mime_type: mime_type.map(String::from),
+            status_code: Some("200".to_string()),
+            digest: None,
+            length: None,
+            robot_flags: None,
+        }
+    }
+
+    /// Downloads a capture of `url` served as `content_type` and returns the
+    /// name it was saved as and the saved bytes.
+    async fn download(
+        name: &str,
+        url: &str,
+        cdx_mime: Option<&str>,
+        content_type: &'static str,
+        body: &'static [u8],
+    ) -> (String, Vec<u8>) {
+        let options = DownloadOptions {
+            output_dir: temp_dir(name),
+            retries: 0,
+            capture_url_template: serve(content_type, body).await,
+            ..DownloadOptions::default()
+        };
+        let (filename, size) = download_capture(&Client::new(), &capture(url, cdx_mime), &options)
+            .await
+            .unwrap();
+        let saved = std::fs::read(options.output_dir.join(&filename)).unwrap();
+        assert_eq!(size, saved.len() as u64);
+        let _ = std::fs::remove_dir_all(&options.output_dir);
+        (filename, saved)
+    }
+
+    #[tokio::test]
+    async fn resumes_a_partial_file_on_the_next_attempt() {
+        static BODY: [u8; 2500] = [7; 2500];
+        let options = DownloadOptions {
+            output_dir: temp_dir("part"),
+            retries: 0,
+            capture_url_template: serve_dropping(&BODY, 1000).await,
+            ..DownloadOptions::default()
+        };
+        let capture = capture("https://example.com/large", Some("image/png"));
+        let part = options.output_dir.join(part_filename(&capture));
+
+        // Every attempt gets 1000 more bytes before the connection drops
+        for downloaded in [1000, 2000] {
+            assert!(
+                download_capture(&Client::new(), &capture, &options)
+                    .await
+                    .is_err()
+            );
+            assert_eq!(std::fs::read(&part).unwrap(), &BODY[..downloaded]);
+        }
+
+        let (filename, size) = download_capture(&Client::new(), &capture, &options)
+            .await
+            .unwrap();
+        assert_eq!(filename, "20230115103000_example.com_large.png");
+        assert_eq!(size, BODY.len() as u64);
+        assert_eq!(
+            std::fs::read(options.output_dir.join(&filename)).unwrap(),
+            BODY
+        );
+        assert!(!part.exists());
+        let _ = std::fs::remove_dir_all(&options.output_dir);
+    }
+
+    #[tokio::test]
+    async fn resumes_within_an_attempt_with_retries() {
+        static BODY: [u8; 2500] = [9; 2500];
+        let options = DownloadOptions {
+            output_dir: temp_dir("part-retries"),
+            retries: 3,
+            backoff: Backoff {
+                delay: Duration::ZERO,
+                ..Backoff::default()
+            },
+            capture_url_template: serve_dropping(&BODY, 1000).await,
+            ..DownloadOptions::default()
+        };
+        let capture = capture("https://example.com/large", Some("image/png"));
+
+        let (filename, _) = download_capture(&Client::new(), &capture, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(options.output_dir.join(&filename)).unwrap(),
+            BODY
+        );
+        assert!(!options.output_dir.join(part_filename(&capture)).exists());
+        let _ = std::fs::remove_dir_all(&options.output_dir);
+    }
+
+    #[tokio::test]
+    async fn reads_warc_records_at_the_bandwidth_limit() {
+        static BODY: [u8; 50_000] = [b'a'; 50_000];
+        let output_dir = temp_dir("warc-bandwidth");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let path = output_dir.join("archive.warc");
+        let warc_file = Mutex::new(File::create(&path).await.unwrap());
+        let options = DownloadOptions {
+            output_dir: output_dir.clone(),
+            retries: 0,
+            capture_url_template: serve("text/html", &BODY).await,
+            bandwidth_limiter: Some(Arc::new(BandwidthLimiter::new(20_000))),
+            ..DownloadOptions::default()
+        };
+
+        // A second worth of bytes is free, the other 30_000 take 1.5 seconds
+        let started = Instant::now();
+        let capture = capture("https://example.com/", Some("text/html"));
+        download_as_warc_record(&Client::new(), &capture, &warc_file, &options)
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(1400));
+
+        warc_file.lock().await.flush().await.unwrap();
+        let record = std::fs::read(&path).unwrap();
+        assert!(record.starts_with(b"WARC/1.0\r\n"));
+        assert!(record.ends_with(&[&BODY[..], b"\r\n\r\n"].concat()));
+        let _ = std::fs::remove_dir_all(&output_dir);
+    }
+
+    #[tokio::test]
+    async fn saves_json_as_json() {
+        let body = br#"{"name":"noway","tags":["a","b"]}"#;
+        let mime = Some("application/json");
+        let (filename, saved) = download(
+            "json",
+            "https://example.com/api",
+            mime,
+            "application/json",
+            body,
+        )
+        .await;
+        assert_eq!(filename, "20230115103000_example.com_api.json");
+        assert_eq!(saved, body);
+    }
+
+    #[tokio::test]
+    async fn saves_plain_text_as_txt() {
+        let body = b"User-agent: *\nDisallow: /private\n";
+        let mime = Some("text/plain");
+        let (filename, saved) = download(
+            "text",
+            "https://example.com/robots.txt",
+            mime,
+            "text/plain; charset=utf-8",
+            body,
+        )
+        .await;
+        assert_eq!(filename, "20230115103000_example.com_robots.txt");
+        assert_eq!(saved, body);
+    }
+
+    #[tokio::test]
+    async fn saves_png_byte_for_byte() {
+        let mime = Some("image/png");
+        let (filename, saved) =
+            download("png", "https://example.com/logo", mime, "image/png", PNG).await;
+        assert_eq!(filename, "20230115103000_example.com_logo.png");
+        assert_eq!(saved, PNG);
+    }
+
+    #[tokio::test]
+    async fn falls_back_on_the_content_type_header() {
+        // Without a CDX MIME type the response's Content-Type is used
+        let (filename, saved) =
+            download("header", "https://example.com/logo", None, "image/png", PNG).await;
+        assert_eq!(filename, "20230115103000_example.com_logo.png");
+        assert_eq!(saved, PNG);
+    }
+
+    #[tokio::test]
+    async fn saves_unknown_types_by_their_kind() {
+        let cases: [(&str, &'static [u8], &str); 4] = [
+            (
+                "application/ld+json",
+                br#"{"@context":"https://schema.org"}"#,
+                "json",
+            ),
+            (
+                "application/vnd.example+xml",
+                b"<?xml version=\"1.0\"?><a/>",
+                "xml",
+            ),
+            ("text/csv", b"a,b\n1,2\n", "txt"),
+            ("application/octet-stream", PNG, "bin"),
+        ];
+        for (i, (mime, body, extension)) in cases.into_iter().enumerate() {
+            let name = format!("fallback-{}", i);
+            let (filename, saved) =
+                download(&name, "https://example.com/data", Some(mime), mime, body).await;
+            assert_eq!(
+                filename,
+                format!("20230115103000_example.com_data.{}", extension),
+                "{}",
+                mime
+            );
+            assert_eq!(saved, body, "{}", mime);
+        }
+    }
+
+    #[test]
+    fn names_a_capture_by_timestamp_host_and_path() {
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/blog/post", "html"),
+            "20230115103000_example.com_blog_post.html"
+        );
+    }
+
+    #[test]
+    fn decodes_unicode_paths() {
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/café/日本", "html"),
+            "20230115103000_example.com_café_日本.html"
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/caf%C3%A9", "html"),
+            "20230115103000_example.com_café.html"
+        );
+    }
+
+    #[test]
+    fn keeps_invalid_percent_escapes() {
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/100%zz", "html"),
+            "20230115103000_example.com_100%zz.html"
+        );
+        // Not UTF-8 once decoded
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/%FF%FE", "html"),
+            "20230115103000_example.com_%FF%FE.html"
+        );
+    }
+
+    #[test]
+    fn names_a_bare_host() {
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com", "html"),
+            "20230115103000_example.com.html"
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "example.com", "html"),
+            "20230115103000_example.com.html"
+        );
+    }
+
+    #[test]
+    fn names_a_url_without_a_scheme() {
+        assert_eq!(
+            make_filename(TIMESTAMP, "example.com/blog/post", "html"),
+            "20230115103000_example.com_blog_post.html"
+        );
+    }
+
+    #[test]
+    fn drops_a_trailing_slash() {
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/blog/", "html"),
+            "20230115103000_example.com_blog.html"
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/", "html"),
+            "20230115103000_example.com.html"
+        );
+    }
+
+    #[test]
+    fn keeps_the_query_string() {
+        assert_eq!(
+            make_filename(
+                TIMESTAMP,
+                "https://example.com/search?q=rust&page=2",
+                "html"
+            ),
+            "20230115103000_example.com_search_q=rust&page=2.html"
+        );
+        assert_ne!(
+            make_filename(TIMESTAMP, "https://example.com/page?id=1", "html"),
+            make_filename(TIMESTAMP, "https://example.com/page?id=2", "html")
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/?next=%2Fa%2Fb", "html"),
+            "20230115103000_example.com_next=_a_b.html"
+        );
+        // An empty query adds nothing
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/search?", "html"),
+            "20230115103000_example.com_search.html"
+        );
+    }
+
+    #[test]
+    fn does_not_repeat_an_extension_the_path_has() {
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/robots.txt", "txt"),
+            "20230115103000_example.com_robots.txt"
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/index.html", "html"),
+            "20230115103000_example.com_index.html"
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/old.htm", "html"),
+            "20230115103000_example.com_old.htm"
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/list.php?page=2", "html"),
+            "20230115103000_example.com_list.php_page=2.html"
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://example.com/robots.txt?v=1", "txt"),
+            "20230115103000_example.com_robots_v=1.txt"
+        );
+    }
+
+    #[test]
+    fn replaces_characters_the_os_does_not_allow() {
+        let name = make_filename(TIMESTAMP, "https://example.com/a%00b", "html");
+        assert_eq!(name, "20230115103000_example.com_a_b.html");
+
+        let name = make_filename(TIMESTAMP, "https://example.com/a:b*c%7Cd", "html");
+        if cfg!(windows) {
+            assert_eq!(name, "20230115103000_example.com_a_b_c_d.html");
+        } else {
+            assert_eq!(name, "20230115103000_example.com_a:b*c|d.html");
+        }
+    }
+
+    #[test]
+    fn knows_the_characters_windows_does_not_allow() {
+        for c in ['<', '>', ':', '"', '/', '\\', '|', '?', '*', '\0', '\n'] {
+            assert!(is_illegal_on_windows(c), "{:?}", c);
+        }
+        for c in ['a', '-', '_', '.', '%', 'é'] {
+            assert!(!is_illegal_on_windows(c), "{:?}", c);
+        }
+    }
+
+    #[test]
+    fn sanitizes_dot_segments() {
+        assert_eq!(sanitize_segment("."), "_");
+        assert_eq!(sanitize_segment(".."), "_");
+        assert_eq!(sanitize_segment("..."), "...");
+    }
+
+    #[test]
+    fn names_an_empty_path_index() {
+        assert_eq!(
+            make_filename(TIMESTAMP, "", "html"),
+            "20230115103000_index.html"
+        );
+        assert_eq!(
+            make_filename(TIMESTAMP, "https://", "html"),
+            "20230115103000_index.html"
+        );
+    }
+
+    #[test]
+    fn caps_the_name_at_255_bytes_on_a_character_boundary() {
+        // 3 bytes each, so 255 or 254 bytes can't be reached exactly
+        let url = format!("https://example.com/{}", "日".repeat(100));
+        let name = make_filename(TIMESTAMP, &url, "html");
+        assert!(name.len() <= MAX_FILENAME_BYTES);
+        assert!(name.len() > MAX_FILENAME_BYTES - 3);
+        assert!(name.ends_with("日.html"));
+
+        let url = format!("https://example.com/{}", "é".repeat(200));
+        let name = make_filename(TIMESTAMP, &url, "html");
+        assert!(name.len() <= MAX_FILENAME_BYTES);
+        assert!(name.ends_with("é.html"));
+    }
+
+    #[test]
+    fn caps_a_very_long_url() {
+        let url = format!(
+            "https://example.com/{}?q={}",
+            "a/".repeat(2000),
+            "b".repeat(5000)
+        );
+        let name = make_filename(TIMESTAMP, &url, "html");
+        assert_eq!(name.len(), MAX_FILENAME_BYTES);
+        assert!(name.starts_with("20230115103000_example.com_a_a_"));
+        assert!(name.ends_with("_a_a.html"));
+    }
+
+    #[test]
+    fn keeps_the_extension_when_cutting_the_name_short() {
+        let stem = "x".repeat(300);
+        assert_eq!(
+            truncate_filename(&stem, "warc.gz"),
+            format!("{}.warc.gz", "x".repeat(MAX_FILENAME_BYTES - 8))
+        );
+    }
+}
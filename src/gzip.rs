@@ -0,0 +1,46 @@
+use crate::crc32::crc32;
+
+/// Compresses `data` into a gzip member that `gunzip` can read.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let compressed = miniz_oxide::deflate::compress_to_vec(data, 6);
+
+    let mut gzip = Vec::with_capacity(compressed.len() + 18);
+    // Magic, deflate, no flags, no modification time, no extra flags and an
+    // unknown OS
+    gzip.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+    gzip.extend_from_slice(&compressed);
+    gzip.extend_from_slice(&crc32(data).to_le_bytes());
+    // The trailer only has room for the size modulo 2^32
+    gzip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    gzip
+}
+
+/// Decompresses a gzip member like the ones [`gzip`] writes, `None` when
+/// `data` isn't one.
+pub fn gunzip(data: &[u8]) -> Option<Vec<u8>> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    if data.len() < 18 || data[..3] != [0x1f, 0x8b, 8] {
+        return None;
+    }
+
+    // Optional header fields come in this order after the fixed 10 bytes
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let length = u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]);
+        pos += 2 + length as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            pos += data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    // The CRC and size trailer follow the compressed data
+    miniz_oxide::inflate::decompress_to_vec(data.get(pos..data.len() - 8)?).ok()
+}
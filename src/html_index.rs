@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use crate::timestamp::format_readable;
+
+/// A downloaded capture listed on the page [`render_html_index`] builds.
+#[derive(Debug, Clone)]
+pub struct ArchivedFile {
+    pub original_url: String,
+    pub timestamp: String,
+    /// Path of the file relative to the directory of the page.
+    pub filename: String,
+    /// Size of the file, when known.
+    pub size_bytes: Option<u64>,
+}
+
+/// A standalone `index.html` linking to every file in `files`, grouped by
+/// original URL with the captures of each one oldest first. The styles are
+/// inline so the page works when opened straight from disk.
+pub fn render_html_index(files: &[ArchivedFile]) -> String {
+    let mut by_url: BTreeMap<&str, Vec<&ArchivedFile>> = BTreeMap::new();
+    for file in files {
+        by_url.entry(&file.original_url).or_default().push(file);
+    }
+
+    let mut html = String::from(concat!(
+        "<!DOCTYPE html>\n",
+        "<html lang=\"en\">\n",
+        "<head>\n",
+        "<meta charset=\"utf-8\">\n",
+        "<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n",
+        "<title>Archived captures</title>\n",
+        "<style>\n",
+        "body { font-family: system-ui, sans-serif; max-width: 60rem; margin: 2rem auto; ",
+        "padding: 0 1rem; color: #222; }\n",
+        "h1 { font-size: 1.5rem; }\n",
+        "h2 { font-size: 1rem; margin: 1.5rem 0 0.25rem; word-break: break-all; }\n",
+        "ul { margin: 0; padding-left: 1.25rem; }\n",
+        "li { margin: 0.15rem 0; }\n",
+        "a { color: #0645ad; }\n",
+        ".summary, .file { color: #666; }\n",
+        "</style>\n",
+        "</head>\n",
+        "<body>\n",
+        "<h1>Archived captures</h1>\n",
+    ));
+    html.push_str(&format!(
+        "<p class=\"summary\">{} captures of {} URLs</p>\n",
+        files.len(),
+        by_url.len()
+    ));
+
+    for (url, mut captures) in by_url {
+        captures.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        html.push_str(&format!("<h2>{}</h2>\n<ul>\n", escape(url)));
+        for capture in captures {
+            html.push_str(&format!(
+                "<li><a href=\"{}\">{}</a> <span class=\"file\">{}</span></li>\n",
+                escape(&link(&capture.filename)),
+                escape(&format_readable(&capture.timestamp)),
+                escape(&capture.filename)
+            ));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Percent encodes the segments of a relative path for use in a link.
+pub(crate) fn link(path: &str) -> String {
+    path.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,190 @@
+use clap::ValueEnum;
+use miette::{Context, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Whether the download an [`IndexEntry`] records succeeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexStatus {
+    /// The capture was saved
+    #[default]
+    Success,
+    /// Every attempt to download the capture failed
+    Failed,
+}
+
+impl IndexStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IndexStatus::Success => "success",
+            IndexStatus::Failed => "failed",
+        }
+    }
+}
+
+/// A capture recorded in a [`DownloadIndex`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub wayback_url: String,
+    pub original_url: String,
+    pub timestamp: String,
+    pub digest: Option<String>,
+    /// Where the capture was saved, `None` when it failed to download.
+    pub local_path: Option<PathBuf>,
+    /// `YYYYMMDDHHMMSS` UTC time the capture was downloaded at, or failed to.
+    pub downloaded_at: String,
+    /// Entries written before failures were recorded are all successes.
+    #[serde(default)]
+    pub status: IndexStatus,
+    /// Why the download failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl IndexEntry {
+    /// The content digest of a capture that was saved, failed downloads
+    /// don't count as having the content.
+    fn saved_digest(&self) -> Option<&String> {
+        match self.status {
+            IndexStatus::Success => self.digest.as_ref(),
+            IndexStatus::Failed => None,
+        }
+    }
+}
+
+/// Record of every capture downloaded across runs, and of the ones that
+/// failed to, kept as a newline delimited JSON file so that content that was
+/// already saved once can be skipped in later runs, whatever their output
+/// directory.
+#[derive(Debug)]
+pub struct DownloadIndex {
+    path: PathBuf,
+    digests: HashSet<String>,
+}
+
+impl DownloadIndex {
+    /// Loads the index at `path`, which doesn't have to exist yet.
+    pub fn open(path: &Path) -> Result<Self> {
+        let digests = if path.exists() {
+            read_entries(path)?
+                .iter()
+                .filter_map(IndexEntry::saved_digest)
+                .cloned()
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            digests,
+        })
+    }
+
+    /// Whether a capture with this content digest was saved before.
+    pub fn contains_digest(&self, digest: &str) -> bool {
+        self.digests.contains(digest)
+    }
+
+    /// Appends `entries` to the index file.
+    pub fn append(&mut self, entries: &[IndexEntry]) -> Result<()> {
+        let mut content = String::new();
+        for entry in entries {
+            content.push_str(&serde_json::to_string(entry).into_diagnostic()?);
+            content.push('\n');
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .into_diagnostic()
+            .context(format!("Failed to open index: {}", self.path.display()))?;
+        file.write_all(content.as_bytes())
+            .into_diagnostic()
+            .context(format!("Failed to write index: {}", self.path.display()))?;
+
+        self.digests
+            .extend(entries.iter().filter_map(IndexEntry::saved_digest).cloned());
+        Ok(())
+    }
+}
+
+/// Every entry of the index file at `path`.
+pub fn read_entries(path: &Path) -> Result<Vec<IndexEntry>> {
+    let content = fs::read_to_string(path)
+        .into_diagnostic()
+        .context(format!("Failed to read index: {}", path.display()))?;
+    let mut entries = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: IndexEntry = serde_json::from_str(line)
+            .into_diagnostic()
+            .context(format!(
+                "Invalid entry on line {} of {}",
+                number + 1,
+                path.display()
+            ))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(digest: &str, status: IndexStatus) -> IndexEntry {
+        IndexEntry {
+            wayback_url: format!("https://web.archive.org/web/20240101000000/{}", digest),
+            original_url: format!("https://example.com/{}", digest),
+            timestamp: "20240101000000".to_string(),
+            digest: Some(digest.to_string()),
+            local_path: (status == IndexStatus::Success).then(|| PathBuf::from(digest)),
+            downloaded_at: "20240102000000".to_string(),
+            status,
+            error: (status == IndexStatus::Failed).then(|| "HTTP 404 Not Found".to_string()),
+        }
+    }
+
+    #[test]
+    fn reads_entries_without_a_status_as_successes() {
+        let line = r#"{"wayback_url":"w","original_url":"o","timestamp":"20240101000000","digest":"D","local_path":"/tmp/a.html","downloaded_at":"20240102000000"}"#;
+        let entry: IndexEntry = serde_json::from_str(line).unwrap();
+        assert_eq!(entry.status, IndexStatus::Success);
+        assert_eq!(entry.local_path, Some(PathBuf::from("/tmp/a.html")));
+        assert_eq!(entry.error, None);
+    }
+
+    #[test]
+    fn only_skips_the_content_of_saved_captures() {
+        let path = std::env::temp_dir().join(format!("noway-index-{}.ndjson", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut index = DownloadIndex::open(&path).unwrap();
+        index
+            .append(&[
+                entry("SAVED", IndexStatus::Success),
+                entry("FAILED", IndexStatus::Failed),
+            ])
+            .unwrap();
+        assert!(index.contains_digest("SAVED"));
+        assert!(!index.contains_digest("FAILED"));
+
+        let reopened = DownloadIndex::open(&path).unwrap();
+        assert!(reopened.contains_digest("SAVED"));
+        assert!(!reopened.contains_digest("FAILED"));
+
+        let entries = read_entries(&path).unwrap();
+        assert_eq!(entries[1].status, IndexStatus::Failed);
+        assert_eq!(entries[1].local_path, None);
+        assert_eq!(entries[1].error.as_deref(), Some("HTTP 404 Not Found"));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,221 @@
+use miette::{Result, miette};
+use url::Url;
+
+use crate::mirror::start_tags;
+
+/// Languages [`detect_language`] can tell from the text of a page, with
+/// words that are common in it and rare elsewhere.
+const STOP_WORDS: [(&str, &[&str]); 7] = [
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "is", "that", "with", "for", "this", "are", "was",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "das", "ist", "nicht", "mit", "von", "sich", "auf", "für",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "des", "est", "une", "pour", "dans", "pas", "sur",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "del", "por", "una", "con", "para", "es", "como",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "di", "che", "della", "per", "non", "sono", "gli", "è", "anche", "nel",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "os", "do", "da", "não", "uma", "com", "em", "dos", "é", "mais",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "het", "een", "van", "en", "niet", "dat", "zijn", "op", "voor", "met", "ook",
+        ],
+    ),
+];
+
+/// Language codes [`language_from_url`] recognizes in host names and paths,
+/// limited to common ones so a path like `/js/` isn't taken for one.
+const URL_LANGUAGES: [&str; 33] = [
+    "ar", "bg", "bn", "ca", "cs", "da", "de", "el", "en", "es", "fa", "fi", "fr", "he", "hi", "hu",
+    "it", "ja", "ko", "nb", "nl", "no", "pl", "pt", "ro", "ru", "sk", "sv", "th", "tr", "uk", "vi",
+    "zh",
+];
+
+/// Checks that `tag` looks like a BCP 47 language tag, e.g. `en`, `pt-BR` or
+/// `zh-Hant`.
+pub fn validate_language(tag: &str) -> Result<()> {
+    let invalid = || {
+        miette!(
+            help = "Use a BCP 47 language tag like en, pt-BR or zh-Hant",
+            "'{}' is not a valid language tag",
+            tag
+        )
+    };
+    let mut subtags = tag.split(['-', '_']);
+    let primary = subtags.next().unwrap_or_default();
+    if !matches!(primary.len(), 2 | 3 | 5..=8) || !primary.chars().all(|c| c.is_ascii_alphabetic())
+    {
+        return Err(invalid());
+    }
+    for subtag in subtags {
+        if !(1..=8).contains(&subtag.len()) || !subtag.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid());
+        }
+    }
+    Ok(())
+}
+
+/// Whether a page in the `detected` language is one in `wanted`. Only the
+/// primary languages are compared unless both name a region or script, so
+/// `en` matches `en-GB` but `en-US` doesn't.
+pub fn language_matches(detected: &str, wanted: &str) -> bool {
+    let normalize = |tag: &str| tag.trim().to_ascii_lowercase().replace('_', "-");
+    let (detected, wanted) = (normalize(detected), normalize(wanted));
+    match (detected.split_once('-'), wanted.split_once('-')) {
+        (Some(_), Some(_)) => detected == wanted,
+        _ => detected.split('-').next() == wanted.split('-').next(),
+    }
+}
+
+/// The language of an HTML page: the one it declares with `<html lang>` or
+/// a `Content-Language` meta tag, or else the one its text looks most like.
+/// `None` when neither tells.
+pub fn detect_language(html: &str) -> Option<String> {
+    let declared = start_tags(html)
+        .into_iter()
+        .find_map(|tag| match tag.name.as_str() {
+            "html" => tag
+                .value(html, "lang")
+                .or_else(|| tag.value(html, "xml:lang")),
+            "meta"
+                if tag
+                    .value(html, "http-equiv")
+                    .is_some_and(|name| name.eq_ignore_ascii_case("content-language")) =>
+            {
+                tag.value(html, "content")
+            }
+            _ => None,
+        });
+    // A meta tag can list several, the first one is the main language
+    if let Some(declared) = declared.and_then(|tags| tags.split(',').next())
+        && validate_language(declared.trim()).is_ok()
+    {
+        return Some(declared.trim().to_string());
+    }
+    guess_language(&visible_text(html))
+}
+
+/// The language of `text` by counting the stop words of each language in
+/// [`STOP_WORDS`]. `None` unless one clearly comes out ahead.
+fn guess_language(text: &str) -> Option<String> {
+    const MIN_HITS: usize = 5;
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let mut scores: Vec<(&str, usize)> = STOP_WORDS
+        .iter()
+        .map(|(language, stop_words)| {
+            let hits = words
+                .iter()
+                .filter(|word| stop_words.contains(&word.as_str()))
+                .count();
+            (*language, hits)
+        })
+        .collect();
+    scores.sort_by_key(|&(_, hits)| std::cmp::Reverse(hits));
+    let (language, best) = scores[0];
+    let second = scores[1].1;
+    // Languages share a few stop words, the winner needs a clear lead
+    (best >= MIN_HITS && best * 2 > second * 3).then(|| language.to_string())
+}
+
+/// The text of `html` outside tags, comments, scripts and styles.
+fn visible_text(html: &str) -> String {
+    let lowercase = html.to_ascii_lowercase();
+    let mut text = String::new();
+    let mut pos = 0;
+    while let Some(offset) = html[pos..].find('<') {
+        text.push_str(&html[pos..pos + offset]);
+        text.push(' ');
+        let start = pos + offset;
+        let end = if lowercase[start..].starts_with("<!--") {
+            lowercase[start..].find("-->").map(|end| start + end + 3)
+        } else if lowercase[start..].starts_with("<script") {
+            lowercase[start..]
+                .find("</script>")
+                .map(|end| start + end + 9)
+        } else if lowercase[start..].starts_with("<style") {
+            lowercase[start..]
+                .find("</style>")
+                .map(|end| start + end + 8)
+        } else {
+            html[start..].find('>').map(|end| start + end + 1)
+        };
+        match end {
+            Some(end) => pos = end,
+            None => return text,
+        }
+    }
+    text.push_str(&html[pos..]);
+    text
+}
+
+/// Guesses the language of a page from its URL alone: a `lang`, `hl` or
+/// `locale` query parameter, a first path segment like `/de/` or `/pt-br/`,
+/// or a subdomain like `fr.example.com`. `None` when the URL doesn't say.
+pub fn language_from_url(url: &str) -> Option<String> {
+    let url = Url::parse(url)
+        .or_else(|_| Url::parse(&format!("http://{}", url)))
+        .ok()?;
+
+    let from_query = url.query_pairs().find_map(|(key, value)| {
+        (matches!(key.as_ref(), "lang" | "hl" | "locale") && validate_language(&value).is_ok())
+            .then(|| value.replace('_', "-"))
+    });
+    if from_query.is_some() {
+        return from_query;
+    }
+
+    let known = |segment: &str| {
+        let segment = segment.to_ascii_lowercase().replace('_', "-");
+        let (primary, region) = match segment.split_once('-') {
+            Some((primary, region)) => (primary, Some(region)),
+            None => (segment.as_str(), None),
+        };
+        let is_region = |region: &str| region.len() == 2 && region.chars().all(char::is_alphabetic);
+        (URL_LANGUAGES.contains(&primary) && region.is_none_or(is_region)).then(|| segment.clone())
+    };
+    let from_path = url
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .and_then(known);
+    if from_path.is_some() {
+        return from_path;
+    }
+
+    let host = url.host_str()?;
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() >= 3 {
+        return known(labels[0]);
+    }
+    None
+}
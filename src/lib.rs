@@ -0,0 +1,86 @@
+//! Download archived pages from the Wayback Machine.
+//!
+//! [`get_wayback_cdx_urls`] searches the CDX API for captures of a URL and
+//! [`download_capture`] saves one of them to disk. Progress is reported
+//! through [`tracing`] events, install a subscriber to see them.
+
+mod availability;
+mod cdx;
+mod checksum;
+mod concurrency;
+mod crc32;
+mod diff;
+mod download;
+mod gzip;
+mod html_index;
+mod index;
+mod language;
+mod md5;
+mod metrics;
+mod mime;
+mod mirror;
+mod pattern;
+mod rate_limit;
+mod readme;
+mod retry;
+mod robots;
+mod s3;
+mod save;
+mod sitemap;
+mod tar;
+mod timemap;
+mod timestamp;
+mod toolbar;
+mod user_agent;
+mod verify;
+mod zip;
+
+pub use availability::{Snapshot, check_availability};
+pub use cdx::{
+    CDX_FIELDS, CdxCapture, CdxOutput, CdxQuery, DEFAULT_CAPTURE_URL_TEMPLATE, DEFAULT_CDX_API,
+    DEFAULT_CDX_FIELDS, MatchType, deduplicate_by_digest, get_cdx_page_count, get_wayback_cdx_urls,
+    latest_per_url, latest_per_url_per_day, normalize_url, status_code_filter,
+    validate_archive_url, validate_capture_url_template, validate_cdx_filter, validate_timestamp,
+};
+pub use checksum::{
+    ChecksumAlgorithm, Hasher, cdx_digest, hash_file, hash_hex, sha256_file, sha256_hex,
+    write_checksum_manifest,
+};
+pub use concurrency::{
+    ConcurrencyController, DownloadPermit, INITIAL_CONCURRENCY, MAX_CONCURRENCY, PermitDebt,
+};
+pub use crc32::{Crc32, crc32};
+pub use diff::{html_diff, unified_diff};
+pub use download::{
+    DEFAULT_USER_AGENT, DownloadOptions, FetchedCapture, FilenameRegistry, PART_FILE_SUFFIX,
+    TEMPLATE_PLACEHOLDERS, capture_filename, download_as_warc_record, download_capture,
+    download_into_tar, download_into_zip, fetch_capture, make_filename, validate_filename_template,
+};
+pub use html_index::{ArchivedFile, render_html_index};
+pub use index::{DownloadIndex, IndexEntry, IndexStatus, read_entries};
+pub use language::{detect_language, language_from_url, language_matches, validate_language};
+pub use md5::{Md5, md5};
+pub use metrics::{RunMetrics, render_metrics};
+pub use mime::{extension_for_mime, fallback_extension, parse_content_type_mapping};
+pub use mirror::{
+    DiscoveredLinks, Mirror, WaybackLink, extract_links, find_assets, mirror_capture,
+    parse_wayback_link, rewrite_links,
+};
+pub use pattern::Pattern;
+pub use rate_limit::{BandwidthLimiter, HeaderRateLimiter, RateLimiter, parse_byte_rate};
+pub use readme::{ArchiveSummary, render_readme};
+pub use retry::{Backoff, MAX_RETRY_DELAY};
+pub use robots::{RobotsTxt, fetch_archived_robots_txt};
+pub use s3::{S3Config, put_object};
+pub use save::submit_url;
+pub use sitemap::{MAX_SITEMAP_URLS, render_sitemap};
+pub use tar::TarWriter;
+pub use timemap::{DEFAULT_WAYBACK_BASE, TimemapLink, fetch_timemap, parse_timemap};
+pub use timestamp::{
+    DEFAULT_TIMESTAMP_FORMAT, format_readable, format_timestamp, format_unix_seconds, next_second,
+    now_timestamp, validate_timestamp_format,
+};
+pub use toolbar::strip_wayback_artifacts;
+pub use user_agent::UserAgentRotation;
+pub use verify::{Verification, verify_file};
+pub use zip::ZipWriter;
@@ -0,0 +1,134 @@
+use std::fmt::{self, Write};
+use std::sync::Arc;
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+use crate::progress::Progress;
+#[cfg(feature = "opentelemetry")]
+use crate::telemetry::Telemetry;
+
+/// Prints `tracing` events from noway through the progress display so they
+/// show up above the bars instead of tearing through them.
+pub struct Logger {
+    progress: Arc<Progress>,
+    max_level: Level,
+    #[cfg(feature = "opentelemetry")]
+    telemetry: Option<Arc<Telemetry>>,
+}
+
+impl Logger {
+    pub fn new(progress: Arc<Progress>, max_level: Level) -> Self {
+        Self {
+            progress,
+            max_level,
+            #[cfg(feature = "opentelemetry")]
+            telemetry: None,
+        }
+    }
+
+    /// Hands spans to `telemetry` for export, whatever the log level.
+    #[cfg(feature = "opentelemetry")]
+    pub fn with_telemetry(mut self, telemetry: Arc<Telemetry>) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        if !metadata.target().starts_with("noway") {
+            return false;
+        }
+        #[cfg(feature = "opentelemetry")]
+        if metadata.is_span() {
+            return self.telemetry.is_some();
+        }
+        *metadata.level() <= self.max_level
+    }
+
+    fn new_span(&self, _span: &Attributes<'_>) -> Id {
+        #[cfg(feature = "opentelemetry")]
+        if let Some(telemetry) = &self.telemetry {
+            return telemetry.new_span(_span);
+        }
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {
+        #[cfg(feature = "opentelemetry")]
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.record(_span, _values);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        self.progress.println(&visitor.message);
+    }
+
+    fn enter(&self, _span: &Id) {
+        #[cfg(feature = "opentelemetry")]
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.enter(_span);
+        }
+    }
+
+    fn exit(&self, _span: &Id) {
+        #[cfg(feature = "opentelemetry")]
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.exit(_span);
+        }
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    fn current_span(&self) -> tracing_core::span::Current {
+        match &self.telemetry {
+            Some(telemetry) => telemetry.current_span(),
+            None => tracing_core::span::Current::none(),
+        }
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    fn clone_span(&self, span: &Id) -> Id {
+        match &self.telemetry {
+            Some(telemetry) => telemetry.clone_span(span),
+            None => span.clone(),
+        }
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    fn try_close(&self, span: Id) -> bool {
+        match &self.telemetry {
+            Some(telemetry) => telemetry.try_close(span),
+            None => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.message, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.message, " {}={:?}", field.name(), value);
+        }
+    }
+}
@@ -1,212 +1,4576 @@
-use clap::Parser;
-use miette::{Context, IntoDiagnostic, Result};
-use reqwest::Client;
-use serde_json::Value;
+mod config;
+mod logger;
+mod progress;
+#[cfg(feature = "opentelemetry")]
+mod telemetry;
+
+use clap::{
+    ArgAction, Args as ClapArgs, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use futures::StreamExt;
+use miette::{Context, IntoDiagnostic, Result, miette};
+use noway::{
+    ArchiveSummary, ArchivedFile, Backoff, BandwidthLimiter, CDX_FIELDS, CdxCapture, CdxOutput,
+    CdxQuery, ChecksumAlgorithm, ConcurrencyController, DEFAULT_CAPTURE_URL_TEMPLATE,
+    DEFAULT_CDX_API, DEFAULT_CDX_FIELDS, DEFAULT_TIMESTAMP_FORMAT, DEFAULT_USER_AGENT,
+    DEFAULT_WAYBACK_BASE, DownloadIndex, DownloadOptions, DownloadPermit, HeaderRateLimiter,
+    INITIAL_CONCURRENCY, IndexEntry, IndexStatus, MAX_CONCURRENCY, MAX_SITEMAP_URLS, MatchType,
+    Mirror, PART_FILE_SUFFIX, Pattern, PermitDebt, RateLimiter, RobotsTxt, RunMetrics, S3Config,
+    TarWriter, TimemapLink, UserAgentRotation, Verification, ZipWriter, capture_filename,
+    check_availability, deduplicate_by_digest, detect_language, download_as_warc_record,
+    download_capture, download_into_tar, download_into_zip, fetch_archived_robots_txt,
+    fetch_capture, fetch_timemap, format_timestamp, get_cdx_page_count, get_wayback_cdx_urls,
+    hash_hex, html_diff, language_from_url, language_matches, latest_per_url,
+    latest_per_url_per_day, mirror_capture, next_second, normalize_url, now_timestamp,
+    parse_byte_rate, parse_content_type_mapping, parse_timemap, read_entries, render_html_index,
+    render_metrics, render_readme, render_sitemap, status_code_filter, submit_url, unified_diff,
+    validate_archive_url, validate_capture_url_template, validate_cdx_filter,
+    validate_filename_template, validate_language, validate_timestamp, validate_timestamp_format,
+    verify_file, write_checksum_manifest,
+};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use reqwest::{Certificate, Client, Proxy};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Semaphore;
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore, mpsc, oneshot};
+use tracing::Level;
 use url::Url;
 
-#[derive(Parser)]
-#[command(name = "noway")]
-#[command(about = "Download archived pages from the Wayback Machine")]
-struct Args {
-    #[arg(help = "The URL to fetch archived versions of")]
-    url: String,
+use logger::Logger;
+use progress::Progress;
+
+#[derive(Parser)]
+#[command(name = "noway")]
+#[command(about = "Download archived pages from the Wayback Machine")]
+#[command(subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    query: QueryArgs,
+
+    #[arg(
+        short,
+        long,
+        env = "NOWAY_OUTPUT",
+        help = "Output directory for downloaded files, the archive path with --format zip, or - for stdout"
+    )]
+    output: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        default_value = "5",
+        env = "NOWAY_CONCURRENCY",
+        help = "Maximum concurrent downloads"
+    )]
+    concurrency: usize,
+
+    #[arg(
+        long,
+        conflicts_with = "concurrency",
+        help = "Start at 5 concurrent downloads and tune it between 1 and 50: one more after 10 \
+                downloads in a row took under 2s, two fewer whenever a request times out"
+    )]
+    concurrency_auto: bool,
+
+    #[arg(
+        long,
+        long_help = "Share the --concurrency download slots between the queried URLs in turn\n\n\
+                     Without it captures are downloaded in the order their CDX pages arrive, \
+                     so with --url-file or several --match-type values the first URL with \
+                     many captures can take every slot while the others wait.",
+        help = "Download the captures of every queried URL side by side, giving each a \
+                download slot in turn"
+    )]
+    parallel_snapshots: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Idle connections kept open to each host [default: max(concurrency, 10)]",
+        long_help = "Idle connections kept open to each host for reuse \
+                     [default: max(concurrency, 10)]. Setting this too high may get requests \
+                     rate limited by the Wayback Machine"
+    )]
+    connection_pool_size: Option<usize>,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "3",
+        env = "NOWAY_RETRIES",
+        help = "Retry attempts for failed downloads"
+    )]
+    retries: u32,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "1.0",
+        value_name = "SECONDS",
+        help = "Pause before the first retry of a failed request"
+    )]
+    retry_delay: f64,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "2.0",
+        value_name = "FACTOR",
+        help = "Multiply the pause by FACTOR for every further retry, up to 5 minutes"
+    )]
+    retry_backoff_factor: f64,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Vary every retry pause randomly by up to 20% either way"
+    )]
+    retry_jitter: bool,
+
+    #[arg(
+        long,
+        value_name = "TIMESTAMP_FILE",
+        conflicts_with_all = ["from", "after_timestamp"],
+        help = "Only fetch captures newer than the timestamp in this file, updated after a fully successful run"
+    )]
+    since: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["from", "after_timestamp", "since"],
+        help = "Only fetch captures from the newest timestamp in the names of the files already in --output"
+    )]
+    since_last: bool,
+
+    #[arg(
+        long,
+        help = "Remove the Wayback Machine toolbar and scripts from downloaded HTML"
+    )]
+    strip_toolbar: bool,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        conflicts_with = "dry_run",
+        help = "Keep running and check for new captures every SECONDS"
+    )]
+    watch: Option<u64>,
+
+    #[arg(long, help = "List matching captures without downloading them")]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ReportFormat::Text,
+        help = "Print capture metadata as JSON to stdout instead of downloading"
+    )]
+    output_format: ReportFormat,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Html,
+        help = "How downloaded captures are stored"
+    )]
+    format: OutputFormat,
+
+    #[arg(
+        long,
+        help = "Skip captures that were already downloaded to the output directory"
+    )]
+    skip_existing: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Record downloads in this index file and skip content it already lists"
+    )]
+    index: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Save captures in subdirectories mirroring their original host and path"
+    )]
+    preserve_path: bool,
+
+    #[arg(
+        long,
+        help = "Leave the timestamp out of file names, numbering captures of the same URL instead"
+    )]
+    no_timestamp_prefix: bool,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with_all = ["preserve_path", "no_timestamp_prefix"],
+        long_help = "Name files after this pattern, e.g. {host}/{year}/{month}/{timestamp}_{path}.{ext}\n\n\
+                     Placeholders are {timestamp}, {year}, {month}, {day}, {host}, {path}, \
+                     {ext} and {digest}, and / starts a subdirectory. The template has to \
+                     include {timestamp} or {digest} so file names stay unique.",
+        help = "Name files after this pattern, e.g. {host}/{year}/{timestamp}_{path}.{ext}"
+    )]
+    output_template: Option<String>,
+
+    #[arg(
+        long = "content-type-map",
+        value_name = "MIME=EXT",
+        help = "Save captures of this MIME type with this extension, taking precedence over the \
+                built-in mapping, e.g. application/x-javascript=js (repeatable)"
+    )]
+    content_type_map: Vec<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "s3_bucket",
+        help = "Also download the images, scripts and stylesheets of every page and rewrite links \
+                to the local files, implies --preserve-path and --strip-toolbar"
+    )]
+    mirror: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write a CSV report of every attempted download"
+    )]
+    output_csv: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the totals of the run to this file in the OpenMetrics text format"
+    )]
+    metrics_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with = "s3_bucket",
+        long_help = "Shell command to run after every downloaded file, e.g. \
+                     'indexer add {file} --source {url}'\n\n\
+                     {file}, {url} and {timestamp} are replaced with the path of the file, the \
+                     original URL and the capture timestamp, quoted for the shell. Commands run \
+                     one at a time, and a failing one is logged without failing the download.",
+        help = "Shell command to run after every downloaded file, with {file}, {url} and \
+                {timestamp} filled in"
+    )]
+    on_success: Option<String>,
+
+    #[arg(
+        long,
+        help = "Write the response headers of every capture to <filename>.headers.json"
+    )]
+    save_headers: bool,
+
+    #[arg(
+        long,
+        help = "Write a manifest of the hashes of the downloaded files, checksums.sha256 unless \
+                --checksum-algorithm picks another hash"
+    )]
+    checksum: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ChecksumAlgorithm::Sha256,
+        requires = "checksum",
+        value_name = "ALGORITHM",
+        help = "Hash the --checksum manifest lists, written to checksums.<ALGORITHM>"
+    )]
+    checksum_algorithm: ChecksumAlgorithm,
+
+    #[arg(
+        long,
+        conflicts_with = "mirror",
+        help = "Compress every downloaded file with gzip and add .gz to its name"
+    )]
+    gzip: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "s3_bucket",
+        help = "Write an index.html linking to every downloaded capture, grouped by URL"
+    )]
+    generate_index: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "s3_bucket",
+        help = "Write a README.md describing the query, the size of the download and its most \
+                recent captures"
+    )]
+    generate_readme: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "s3_bucket",
+        help = "Write a sitemap.xml listing every downloaded HTML page with its capture time"
+    )]
+    generate_sitemap: bool,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        requires = "generate_sitemap",
+        help = "URL the output directory is served at, the <loc> of every page in sitemap.xml \
+                starts with it instead of the file:// URL of the directory"
+    )]
+    sitemap_base_url: Option<String>,
+
+    #[arg(
+        long,
+        help = "Write the same domain links of every downloaded HTML page to discovered_links.txt"
+    )]
+    extract_links: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "s3_bucket", "mirror", "checksum", "generate_index", "generate_readme",
+            "generate_sitemap", "gzip", "watch", "on_success"
+        ],
+        help = "Stream captures into a tar archive at PATH, or - for stdout, instead of \
+                saving them as files"
+    )]
+    output_tar: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "BUCKET",
+        conflicts_with_all = ["checksum", "skip_existing"],
+        help = "Upload captures to this S3 bucket instead of saving them to disk, using the AWS_* credential variables"
+    )]
+    s3_bucket: Option<String>,
+
+    #[arg(
+        long,
+        requires = "s3_bucket",
+        default_value = "",
+        value_name = "PREFIX",
+        help = "Prefix for the S3 object keys, e.g. archives/"
+    )]
+    s3_prefix: String,
+
+    #[arg(
+        long,
+        requires = "s3_bucket",
+        value_name = "URL",
+        help = "Endpoint of an S3 compatible provider other than AWS"
+    )]
+    s3_endpoint: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "30",
+        help = "Seconds to wait for a connection to be established"
+    )]
+    connect_timeout: u64,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "15",
+        help = "Seconds to wait for a request to complete"
+    )]
+    read_timeout: u64,
+
+    #[arg(
+        long,
+        default_value = "120",
+        value_name = "SECONDS",
+        help = "Seconds to wait for a CDX query to complete"
+    )]
+    timeout_cdx: u64,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "5",
+        value_name = "N",
+        help = "Maximum download requests per second to each host, 0 for no limit"
+    )]
+    rate_limit: f64,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Pause downloads until X-RateLimit-Reset when X-RateLimit-Remaining falls below \
+                --rate-limit-threshold"
+    )]
+    rate_limit_header: bool,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = "10",
+        value_name = "N",
+        help = "Requests left in X-RateLimit-Remaining below which --rate-limit-header pauses"
+    )]
+    rate_limit_threshold: u64,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "BYTES_PER_SECOND",
+        help = "Limit how fast all downloads together read, e.g. 512K or 1M"
+    )]
+    bandwidth_limit: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "NOWAY_PROXY",
+        help = "HTTP or HTTPS proxy to send all requests through"
+    )]
+    proxy: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "TOKEN",
+        help = "Send Authorization: Bearer TOKEN with every request, NOWAY_BEARER_TOKEN takes \
+                precedence"
+    )]
+    bearer_token: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "KEY",
+        help = "Send X-API-Key: KEY with every request, NOWAY_API_KEY takes precedence"
+    )]
+    api_key: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "ca_cert",
+        help = "Accept invalid and self-signed TLS certificates, like curl -k"
+    )]
+    insecure: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Also trust the CA certificates in this PEM file, for private PKI and TLS \
+                intercepting proxies"
+    )]
+    ca_cert: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "URL",
+        default_value = DEFAULT_CDX_API,
+        help = "CDX API of the archive to search, for Wayback compatible archives"
+    )]
+    cdx_api: String,
+
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "ipv6",
+        help = "Only connect over IPv4"
+    )]
+    ipv4: bool,
+
+    #[arg(long, global = true, help = "Only connect over IPv6")]
+    ipv6: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "URL",
+        conflicts_with = "capture_url_template",
+        help = "Base URL the archive serves captures under as <URL>/<timestamp>/<original url>"
+    )]
+    wayback_base: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "TEMPLATE",
+        help = "URL captures are downloaded from, with {timestamp} and {url} filled in \
+                [default: https://web.archive.org/web/{timestamp}/{url}]"
+    )]
+    capture_url_template: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "STRFTIME",
+        default_value = DEFAULT_TIMESTAMP_FORMAT,
+        long_help = "strftime style format of timestamps in file names and messages, e.g. \
+                     %Y-%m-%d_%H-%M-%S\n\n\
+                     Supported are %Y, %y, %m, %d, %e, %H, %I, %M, %S, %p, %j, %b, %B, %a, \
+                     %A, %F, %T and %%. Characters some OSes don't allow in file names, like \
+                     the : of %H:%M, become -. Metadata always keeps the CDX timestamp.",
+        help = "strftime style format of timestamps in file names and messages"
+    )]
+    timestamp_format: String,
+
+    #[arg(
+        long,
+        global = true,
+        default_value = DEFAULT_USER_AGENT,
+        help = "User-Agent header to send, \"noway\" sends noway/<version>"
+    )]
+    user_agent: String,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        help = "Take turns with the User-Agents in this file, one per line, for download requests"
+    )]
+    user_agent_rotate: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        requires = "user_agent_rotate",
+        help = "Pick a random User-Agent from --user-agent-rotate for every request"
+    )]
+    user_agent_rotate_random: bool,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = ArgAction::Count,
+        help = "Show more detail, repeat for even more"
+    )]
+    verbose: u8,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        conflicts_with = "verbose",
+        help = "Only print errors"
+    )]
+    quiet: bool,
+
+    /// Set by `noway count`, which prints how many captures match instead
+    /// of listing them.
+    #[arg(skip)]
+    counting: Option<Counting>,
+}
+
+/// Which captures to look up, shared by downloads, `noway list` and
+/// `noway count`.
+#[derive(ClapArgs)]
+struct QueryArgs {
+    #[arg(
+        required_unless_present = "url_file",
+        help = "The URL to fetch archived versions of"
+    )]
+    url: Option<String>,
+
+    #[arg(
+        short = 'f',
+        long,
+        help = "File with one URL per line to fetch archived versions of"
+    )]
+    url_file: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "prefix",
+        env = "NOWAY_MATCH_TYPE",
+        help = "Match type for URL search, repeat or separate with commas to merge several"
+    )]
+    match_type: Vec<MatchType>,
+
+    #[arg(
+        long,
+        default_value = "3",
+        value_name = "N",
+        help = "Maximum concurrent CDX queries"
+    )]
+    concurrency_cdx: usize,
+
+    #[arg(
+        long,
+        help = "Only include captures from this timestamp (YYYYMMDDHHMMSS)"
+    )]
+    from: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "TIMESTAMP",
+        conflicts_with = "from",
+        help = "Only include captures after this timestamp (YYYYMMDDHHMMSS). Sent as the CDX \
+                from = TIMESTAMP + 1 second, which includes captures from that second on"
+    )]
+    after_timestamp: Option<String>,
+
+    #[arg(
+        long,
+        help = "Only include captures up to this timestamp (YYYYMMDDHHMMSS)"
+    )]
+    to: Option<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "200",
+        value_name = "CODES",
+        help = "Comma separated archived status codes to include, or \"all\""
+    )]
+    status_codes: Vec<String>,
+
+    #[arg(
+        long = "filter",
+        value_name = "[!]FIELD:REGEX",
+        long_help = "Only include captures matching this CDX filter expression (repeatable)\n\n\
+                     FIELD is one of urlkey, timestamp, original, mimetype, statuscode, \
+                     digest, length or robotflags, and REGEX has to match all of it. A \
+                     leading ! keeps the captures that don't match instead, e.g. \
+                     !mimetype:image/.* or original:.*\\.pdf. Every filter is passed on to \
+                     the CDX API as is, like --status-codes and --mime-type.",
+        help = "Only include captures matching this CDX filter, e.g. !mimetype:image/.* or \
+                original:.*\\.pdf (repeatable)"
+    )]
+    filters: Vec<String>,
+
+    #[arg(
+        long = "mime-type",
+        value_name = "PATTERN",
+        help = "Only include captures with this MIME type, e.g. text/html (repeatable)"
+    )]
+    mime_types: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "FIELD[:N]",
+        long_help = "Let the CDX API drop adjacent captures that share a field (repeatable)\n\n\
+                     FIELD is one of urlkey, timestamp, original, mimetype, statuscode, \
+                     digest or length. With :N only the first N characters are compared, \
+                     e.g. timestamp:8 keeps one capture per day, timestamp:6 one per month \
+                     and digest skips captures whose content didn't change.",
+        help = "Let the CDX API drop adjacent captures sharing a field, e.g. digest or timestamp:8"
+    )]
+    collapse: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Only download captures whose original URL matches this pattern (repeatable)"
+    )]
+    include: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Skip captures whose original URL matches this pattern (repeatable)"
+    )]
+    exclude: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Skip captures disallowed by the most recent archived robots.txt of their host"
+    )]
+    respect_robots: bool,
+
+    #[arg(
+        long,
+        help = "Also download captures whose CDX robotflags contain X, which were excluded by \
+                robots.txt when crawled and usually only hold an error page"
+    )]
+    include_robot_excluded: bool,
+
+    #[arg(
+        long,
+        value_name = "BCP47",
+        long_help = "Only keep pages in this language, e.g. de or pt-BR\n\n\
+                     A page's language is the one it declares with <html lang> or a \
+                     Content-Language meta tag, or else is guessed from common words for en, \
+                     de, fr, es, it, pt and nl. Downloaded pages in another language are \
+                     deleted, pages whose language can't be told and other files are kept.",
+        help = "Only keep pages in this language, e.g. de or pt-BR, deleting downloaded pages \
+                in others"
+    )]
+    language: Option<String>,
+
+    #[arg(
+        long,
+        requires = "language",
+        help = "Skip captures whose URL points to another language than --language before \
+                downloading them, e.g. /fr/ or fr.example.com"
+    )]
+    language_pre_filter: bool,
+
+    #[arg(
+        long,
+        default_value = "1000",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Fetch CDX results in pages of N and start downloading as each one arrives"
+    )]
+    page_size: u32,
+
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["newest_only", "oldest_only", "closest"],
+        help = "Only fetch this page of the CDX results, counting from 0"
+    )]
+    page: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with = "page",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Stop after fetching N pages of CDX results per query, warning that the rest were \
+                left out"
+    )]
+    max_pages: Option<u32>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "FIELDS",
+        help = "Comma separated CDX columns to fetch, must include timestamp and original"
+    )]
+    fields: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Only download the earliest capture of each distinct page content"
+    )]
+    deduplicate: bool,
+
+    #[arg(
+        long,
+        help = "Only download the most recent capture of each distinct original URL in the results"
+    )]
+    dedup_url_latest: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "first_per_day",
+        help = "Only download the most recent capture of each URL per day, can be combined with \
+                --deduplicate"
+    )]
+    dedup_by_day: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["first_per_month", "first_per_year"],
+        help = "Only download the first capture of each URL per day"
+    )]
+    first_per_day: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "first_per_year",
+        help = "Only download the first capture of each URL per month"
+    )]
+    first_per_month: bool,
+
+    #[arg(long, help = "Only download the first capture of each URL per year")]
+    first_per_year: bool,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Skip captures whose archived size is larger than this"
+    )]
+    max_file_size: Option<u64>,
+
+    #[arg(
+        long,
+        conflicts_with = "oldest_only",
+        help = "Only download the most recent capture of each URL, exact queries ask the CDX API \
+                for only that one"
+    )]
+    newest_only: bool,
+
+    #[arg(
+        long,
+        help = "Only download the oldest capture of each URL, exact queries ask the CDX API for \
+                only that one"
+    )]
+    oldest_only: bool,
+
+    #[arg(
+        long,
+        value_name = "TIMESTAMP",
+        conflicts_with_all = ["newest_only", "oldest_only"],
+        help = "Only download the capture closest to this timestamp (YYYYMMDDHHMMSS), one per \
+                queried URL, needs --match-type exact"
+    )]
+    closest: Option<String>,
+
+    #[arg(short, long, help = "Only download the N most recent captures")]
+    limit: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Query the CDX API for the URLs made canonical: https, no www., a lowercase host, \
+                sorted query parameters and no fragment"
+    )]
+    normalize_url: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ORDER",
+        help = "Download the captures in this order once every CDX page is fetched, instead of \
+                in CDX order, oldest first for each URL, as the pages arrive"
+    )]
+    sort: Option<CaptureOrder>,
+}
+
+impl QueryArgs {
+    /// The period of `--first-per-day`, `--first-per-month` or
+    /// `--first-per-year`, with how many leading digits of a timestamp make
+    /// it up.
+    fn period(&self) -> Option<(&'static str, usize)> {
+        if self.first_per_day {
+            Some(("day", 8))
+        } else if self.first_per_month {
+            Some(("month", 6))
+        } else if self.first_per_year {
+            Some(("year", 4))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Ask the Wayback Machine to archive URLs now and print the new snapshots
+    Submit(SubmitArgs),
+    /// Check whether a URL has been archived, exiting with 1 if it hasn't
+    Availability(AvailabilityArgs),
+    /// Print the path of the config file, or write a template to it
+    Config(ConfigArgs),
+    /// Print the Wayback Machine URLs of matching captures without downloading them
+    List(Box<ListArgs>),
+    /// Print how many captures match without downloading them
+    Count(Box<CountArgs>),
+    /// Show how a page changed between two captures, exiting with 1 if it did
+    Diff(DiffArgs),
+    /// Print the captures of a URL listed by the archive's Memento timemap
+    Timemap(TimemapArgs),
+    /// Check downloaded files against the CDX digests of their captures, exiting with 1 if any
+    /// differ or are missing
+    Verify(VerifyArgs),
+    /// Print the captures recorded in an --index file as CSV or JSON
+    ExportIndex(ExportIndexArgs),
+}
+
+#[derive(ClapArgs)]
+struct SubmitArgs {
+    #[arg(required_unless_present = "url_file", help = "The URL to archive")]
+    url: Option<String>,
+
+    #[arg(short = 'f', long, help = "File with one URL per line to archive")]
+    url_file: Option<PathBuf>,
+}
+
+#[derive(ClapArgs)]
+struct AvailabilityArgs {
+    #[arg(help = "The URL to look up")]
+    url: String,
+
+    #[arg(
+        long,
+        help = "Find the snapshot closest to this timestamp (YYYYMMDDHHMMSS)"
+    )]
+    timestamp: Option<String>,
+}
+
+#[derive(ClapArgs)]
+struct ListArgs {
+    #[command(flatten)]
+    query: QueryArgs,
+
+    #[arg(
+        long,
+        help = "Print the captures and their CDX metadata as a JSON array"
+    )]
+    json: bool,
+}
+
+#[derive(ClapArgs)]
+struct CountArgs {
+    #[command(flatten)]
+    query: QueryArgs,
+
+    #[arg(
+        long,
+        help = "Print the number of captures of every year before the total"
+    )]
+    by_year: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Counting {
+    Total,
+    ByYear,
+}
+
+#[derive(ClapArgs)]
+struct DiffArgs {
+    #[arg(help = "The URL of the page")]
+    url: String,
+
+    #[arg(help = "Timestamp of the older capture (YYYYMMDDHHMMSS)")]
+    timestamp1: String,
+
+    #[arg(help = "Timestamp of the newer capture (YYYYMMDDHHMMSS)")]
+    timestamp2: String,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write a side-by-side HTML diff to FILE instead of printing a unified diff"
+    )]
+    html_diff: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = 3,
+        help = "Unchanged lines to show around each change"
+    )]
+    context: usize,
+}
+
+#[derive(ClapArgs)]
+struct TimemapArgs {
+    #[arg(help = "The URL to look up")]
+    url: String,
+
+    #[arg(
+        long,
+        conflicts_with = "json",
+        help = "Print the timemap as the archive sends it"
+    )]
+    raw: bool,
+
+    #[arg(long, help = "Print the links of the timemap as a JSON array")]
+    json: bool,
+}
+
+#[derive(ClapArgs)]
+struct VerifyArgs {
+    #[arg(
+        required_unless_present = "index",
+        help = "Output directory of a download, checked against its metadata.ndjson"
+    )]
+    dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "dir",
+        help = "Check the files listed in an --index file instead"
+    )]
+    index: Option<PathBuf>,
+}
+
+#[derive(ClapArgs)]
+struct ExportIndexArgs {
+    #[arg(help = "Index file written with --index")]
+    index: PathBuf,
+
+    #[arg(long, help = "Print the entries as a JSON array instead of CSV")]
+    json: bool,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Only export captures whose original URL matches the pattern"
+    )]
+    filter_url: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "TIMESTAMP",
+        help = "Only export captures downloaded at or after this timestamp (YYYYMMDDHHMMSS)"
+    )]
+    since: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "STATUS",
+        help = "Only export captures that were downloaded (success) or failed to (failed)"
+    )]
+    filter_status: Option<IndexStatus>,
+}
+
+#[derive(ClapArgs)]
+struct ConfigArgs {
+    #[arg(long, help = "Write a commented template config file")]
+    init: bool,
+
+    #[arg(long, requires = "init", help = "Replace an existing config file")]
+    force: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    /// One file per capture
+    Html,
+    /// All captures as records of a single archive.warc file
+    Warc,
+    /// All captures as entries of a single zip archive at --output
+    Zip,
+    /// One file per capture, with a JSON line on stdout for every finished download
+    Ndjson,
+}
+
+impl OutputFormat {
+    /// Whether every capture gets a file of its own in the output directory.
+    fn one_file_per_capture(self) -> bool {
+        matches!(self, Self::Html | Self::Ndjson)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CaptureOrder {
+    /// Oldest first
+    #[value(name = "timestamp_asc")]
+    TimestampAsc,
+    /// Newest first
+    #[value(name = "timestamp_desc")]
+    TimestampDesc,
+    /// By original URL, oldest first for each
+    Url,
+}
+
+impl CaptureOrder {
+    /// Sorts `captures` in this order, keeping the CDX order of captures that
+    /// compare equal.
+    fn sort(self, captures: &mut [CdxCapture]) {
+        match self {
+            CaptureOrder::TimestampAsc => captures.sort_by(|a, b| a.timestamp.cmp(&b.timestamp)),
+            CaptureOrder::TimestampDesc => captures.sort_by(|a, b| b.timestamp.cmp(&a.timestamp)),
+            CaptureOrder::Url => captures.sort_by(|a, b| {
+                (&a.original_url, &a.timestamp).cmp(&(&b.original_url, &b.timestamp))
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ReportFormat {
+    /// Human readable progress, downloads captures
+    Text,
+    /// JSON array of captures on stdout, nothing is downloaded
+    Json,
+}
+
+/// A capture as printed by `--output-format json`.
+#[derive(Serialize)]
+struct CaptureReport<'a> {
+    #[serde(flatten)]
+    capture: &'a CdxCapture,
+    wayback_url: String,
+}
+
+/// One line of `metadata.ndjson`, recording the outcome of a download
+/// together with the CDX row it came from.
+#[derive(Serialize)]
+struct MetadataRecord {
+    #[serde(flatten)]
+    capture: CdxCapture,
+    wayback_url: String,
+    status: &'static str,
+    filename: Option<String>,
+    file_size_bytes: Option<u64>,
+    download_duration_ms: u64,
+    error: Option<String>,
+}
+
+/// A line of `--format ndjson`, written as soon as a download finishes.
+#[derive(Serialize)]
+struct DownloadEvent<'a> {
+    wayback_url: &'a str,
+    original_url: &'a str,
+    timestamp: &'a str,
+    /// `success` or `failure`.
+    status: &'a str,
+    filename: Option<&'a str>,
+    size_bytes: Option<u64>,
+    error_message: Option<&'a str>,
+}
+
+/// Writes the `--format ndjson` line of a finished download. Holding the
+/// lock for the whole line keeps concurrent downloads from interleaving.
+async fn write_download_event(stdout: &Mutex<tokio::io::Stdout>, record: &MetadataRecord) {
+    let event = DownloadEvent {
+        wayback_url: &record.wayback_url,
+        original_url: &record.capture.original_url,
+        timestamp: &record.capture.timestamp,
+        status: if record.status == "success" {
+            "success"
+        } else {
+            "failure"
+        },
+        filename: record.filename.as_deref(),
+        size_bytes: record.file_size_bytes,
+        error_message: record.error.as_deref(),
+    };
+    let Ok(mut line) = serde_json::to_vec(&event) else {
+        return;
+    };
+    line.push(b'\n');
+
+    let mut stdout = stdout.lock().await;
+    // A closed pipe downstream shouldn't fail the downloads themselves
+    if let Err(e) = stdout.write_all(&line).await {
+        tracing::debug!("Failed to write download event: {}", e);
+    }
+    let _ = stdout.flush().await;
+}
+
+/// Runs the `--on-success` command for a downloaded `file`. A failing
+/// command is only logged, the file was downloaded all the same. With
+/// `--format ndjson` the command prints to stderr so stdout keeps only
+/// download events.
+async fn run_success_hook(command: &str, file: &Path, capture: &CdxCapture, ndjson: bool) {
+    let quote = if cfg!(windows) {
+        cmd_quote
+    } else {
+        shell_quote
+    };
+    let command = command
+        .replace("{file}", &quote(&file.display().to_string()))
+        .replace("{url}", &quote(&capture.original_url))
+        .replace("{timestamp}", &quote(&capture.timestamp));
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = tokio::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = tokio::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(&command).stdin(std::process::Stdio::null());
+    if ndjson {
+        shell.stdout(std::io::stderr());
+    }
+    match shell.status().await {
+        Ok(status) if status.success() => {}
+        Ok(status) => tracing::warn!(
+            "--on-success command for {} failed with {}",
+            file.display(),
+            status
+        ),
+        Err(e) => tracing::warn!(
+            "Failed to run --on-success command for {}: {}",
+            file.display(),
+            e
+        ),
+    }
+}
+
+/// Deletes a downloaded page that isn't in the `wanted` language, with its
+/// headers file, and returns the language it is in. Other files and pages
+/// whose language can't be told are kept.
+async fn remove_other_language(
+    options: &DownloadOptions,
+    filename: &str,
+    wanted: &str,
+) -> Option<String> {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|extension| extension.to_str());
+    if !matches!(extension, Some("html" | "htm")) {
+        return None;
+    }
+    let path = options.output_dir.join(filename);
+    let html = tokio::fs::read(&path).await.ok()?;
+    let detected = detect_language(&String::from_utf8_lossy(&html))?;
+    if language_matches(&detected, wanted) {
+        return None;
+    }
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        tracing::warn!("Failed to delete {}: {}", path.display(), e);
+        return None;
+    }
+    let headers = options
+        .output_dir
+        .join(format!("{}.headers.json", filename));
+    let _ = tokio::fs::remove_file(headers).await;
+    Some(detected)
+}
+
+/// Settings derived from the arguments once and shared by every batch.
+struct RunContext {
+    args: Args,
+    client: Arc<Client>,
+    options: Arc<DownloadOptions>,
+    progress: Arc<Progress>,
+    urls: Vec<String>,
+    match_types: Vec<MatchType>,
+    cdx_limit: Option<i64>,
+    /// `filter` expressions of every CDX query, with the status codes.
+    filters: Vec<String>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    output_dir: String,
+    /// Archive captures are added to with `--format zip`.
+    zip_path: Option<PathBuf>,
+    /// `--output -` writes capture bodies to stdout instead of files.
+    to_stdout: bool,
+    interrupted: Arc<AtomicBool>,
+    archives: Arc<OpenArchives>,
+}
+
+/// The archives the running batch writes to. A second Ctrl+C exits without
+/// waiting for the batch, so it closes them first to leave them readable.
+/// Only weak references are kept, the batch still finishes them itself.
+#[derive(Default)]
+struct OpenArchives {
+    tar: std::sync::Mutex<Weak<Mutex<TarWriter>>>,
+    zip: std::sync::Mutex<Weak<Mutex<ZipWriter>>>,
+    warc_file: std::sync::Mutex<Weak<Mutex<File>>>,
+}
+
+impl OpenArchives {
+    /// Waits for the entries being written to finish, then ends the tar and
+    /// zip archives and flushes the WARC file.
+    async fn close(&self) {
+        let tar = self.tar.lock().unwrap().upgrade();
+        if let Some(tar) = tar
+            && let Err(e) = tar.lock().await.close()
+        {
+            tracing::error!("{:?}", e);
+        }
+        let zip = self.zip.lock().unwrap().upgrade();
+        if let Some(zip) = zip
+            && let Err(e) = zip.lock().await.close()
+        {
+            tracing::error!("{:?}", e);
+        }
+        let warc_file = self.warc_file.lock().unwrap().upgrade();
+        if let Some(warc_file) = warc_file
+            && let Err(e) = warc_file.lock().await.flush().await
+        {
+            tracing::error!("Failed to write WARC file: {}", e);
+        }
+    }
+}
+
+/// What happened to the captures of a batch that was downloaded.
+struct BatchOutcome {
+    total: usize,
+    failed: usize,
+    pending: usize,
+    /// Captures saved, not counting skipped or failed ones.
+    downloaded: usize,
+    bytes: u64,
+    /// Rows the CDX API returned, before any filter.
+    cdx_results: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Settings from the config file become the defaults of their options,
+    // so anything given on the command line still wins
+    let mut command = config::add_env_vars(Args::command());
+    if let Some(path) = config::config_path() {
+        command = config::apply_defaults(command, &path)?;
+    }
+    let mut args = Args::from_arg_matches(&command.get_matches()).unwrap_or_else(|e| e.exit());
+
+    // `noway list` runs the same queries as a download with --dry-run or
+    // --output-format json, but keeps stdout to the captures alone
+    let listing = match args
+        .command
+        .take_if(|command| matches!(command, Command::List(_) | Command::Count(_)))
+    {
+        Some(Command::List(list)) => {
+            args.query = list.query;
+            args.dry_run = !list.json;
+            args.output_format = if list.json {
+                ReportFormat::Json
+            } else {
+                ReportFormat::Text
+            };
+            true
+        }
+        Some(Command::Count(count)) => {
+            args.query = count.query;
+            args.dry_run = true;
+            args.counting = Some(if count.by_year {
+                Counting::ByYear
+            } else {
+                Counting::Total
+            });
+            // Only ask for the columns the filters look at, the rows aren't
+            // printed
+            if args.query.fields.is_empty() {
+                args.query.fields = vec!["timestamp".to_string(), "original".to_string()];
+                if args.query.deduplicate {
+                    args.query.fields.push("digest".to_string());
+                }
+                if args.query.max_file_size.is_some() {
+                    args.query.fields.push("length".to_string());
+                }
+            }
+            true
+        }
+        _ => false,
+    };
+
+    let json_output = args.output_format == ReportFormat::Json;
+    let to_stdout = args.output.as_deref() == Some("-");
+    let mut progress = Progress::new(args.concurrency);
+    if json_output
+        || to_stdout
+        || listing
+        || matches!(args.format, OutputFormat::Ndjson)
+        || args.output_tar.as_deref() == Some("-")
+    {
+        progress = progress.log_to_stderr();
+    }
+    if args.quiet {
+        progress = progress.hidden();
+    }
+    let progress = Arc::new(progress);
+
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => Level::ERROR,
+        (false, 0) => Level::INFO,
+        (false, 1) => Level::DEBUG,
+        (false, _) => Level::TRACE,
+    };
+    let logger = Logger::new(Arc::clone(&progress), level);
+    #[cfg(feature = "opentelemetry")]
+    let telemetry = Arc::new(telemetry::Telemetry::from_env());
+    #[cfg(feature = "opentelemetry")]
+    let logger = logger.with_telemetry(Arc::clone(&telemetry));
+    tracing::subscriber::set_global_default(logger).into_diagnostic()?;
+
+    #[cfg(feature = "opentelemetry")]
+    telemetry.spawn_exporter();
+    let result = run(args, progress, listing).await;
+    // Only logged, the spans not making it out doesn't change the outcome
+    #[cfg(feature = "opentelemetry")]
+    if let Err(e) = telemetry.flush().await {
+        tracing::warn!("{}", e);
+    }
+    result
+}
+
+/// Runs the subcommand, or the downloads without one.
+async fn run(args: Args, progress: Arc<Progress>, listing: bool) -> Result<()> {
+    let json_output = args.output_format == ReportFormat::Json;
+    let to_stdout = args.output.as_deref() == Some("-");
+
+    match &args.command {
+        Some(Command::Submit(submit)) => return run_submit(&args, submit).await,
+        Some(Command::Availability(availability)) => {
+            return run_availability(&args, availability).await;
+        }
+        Some(Command::Config(config)) => return run_config(config),
+        Some(Command::Diff(diff)) => return run_diff(&args, diff).await,
+        Some(Command::Verify(verify)) => return run_verify(verify).await,
+        Some(Command::ExportIndex(export)) => return run_export_index(export),
+        Some(Command::Timemap(timemap)) => return run_timemap(&args, timemap).await,
+        Some(Command::List(_) | Command::Count(_)) | None => {}
+    }
+
+    if listing && args.watch.is_some() {
+        return Err(miette!(
+            help = "Run the command again to see new captures",
+            "noway list and noway count can't be combined with --watch"
+        ));
+    }
+    if args.watch.is_some() && json_output {
+        return Err(miette!(
+            help = "Use --output-format text with --watch",
+            "--watch can't be combined with --output-format json"
+        ));
+    }
+    if to_stdout
+        && (!matches!(args.format, OutputFormat::Html)
+            || args.s3_bucket.is_some()
+            || args.watch.is_some()
+            || args.checksum
+            || args.save_headers
+            || args.mirror
+            || args.generate_index
+            || args.generate_readme
+            || args.generate_sitemap
+            || args.gzip
+            || args.extract_links)
+    {
+        return Err(miette!(
+            help = "Write to a directory with --output <DIR> to use these options",
+            "--output - can't be combined with --format warc/zip/ndjson, --s3-bucket, --watch, \
+             --checksum, --save-headers, --mirror, --generate-index, --generate-readme, \
+             --generate-sitemap, --gzip or --extract-links"
+        ));
+    }
+
+    if args.output_tar.is_some() && (to_stdout || !matches!(args.format, OutputFormat::Html)) {
+        return Err(miette!(
+            help = "Reports of the run go to --output <DIR>, the captures to --output-tar",
+            "--output-tar only works with --format html and an output directory"
+        ));
+    }
+
+    let mut from = args.query.from.clone();
+    if let Some(after) = &args.query.after_timestamp {
+        validate_timestamp(after).context("Invalid --after-timestamp value")?;
+        if after.len() != 14 {
+            return Err(miette!(
+                help = "Use --from for captures from the start of a year, month or day",
+                "--after-timestamp needs a complete timestamp (YYYYMMDDHHMMSS), got '{}'",
+                after
+            ));
+        }
+        // The CDX API has no exclusive bound, and timestamps have whole
+        // seconds
+        from = Some(next_second(after));
+    }
+    if let Some(since) = &args.since
+        && since.exists()
+    {
+        let content = fs::read_to_string(since)
+            .into_diagnostic()
+            .context(format!("Failed to read --since file: {}", since.display()))?;
+        let timestamp = content.trim().to_string();
+        validate_timestamp(&timestamp)
+            .context(format!("Invalid timestamp in {}", since.display()))?;
+        tracing::info!(
+            "Fetching captures since {}",
+            format_timestamp(&timestamp, &args.timestamp_format)
+        );
+        from = Some(timestamp);
+    }
+    if args.since_last {
+        let Some(output) = args.output.as_deref().filter(|output| *output != "-") else {
+            return Err(miette!(
+                help = "Pass the directory of the earlier run with --output <DIR>",
+                "--since-last needs an output directory to look in"
+            ));
+        };
+        if !args.format.one_file_per_capture() || args.s3_bucket.is_some() {
+            return Err(miette!(
+                help = "Use --format html without --s3-bucket, or --since with a state file",
+                "--since-last only works with files saved to the output directory"
+            ));
+        }
+        if args.timestamp_format != DEFAULT_TIMESTAMP_FORMAT {
+            return Err(miette!(
+                help = "Leave out --timestamp-format, or use --since with a state file",
+                "--since-last looks for timestamps in file names in the default --timestamp-format"
+            ));
+        }
+        match latest_file_timestamp(Path::new(output))? {
+            Some(timestamp) => {
+                tracing::info!(
+                    "Fetching captures since {}, the newest in {}",
+                    format_timestamp(&timestamp, &args.timestamp_format),
+                    output
+                );
+                from = Some(timestamp);
+            }
+            None if Path::new(output).exists() => tracing::warn!(
+                "No file in {} has a timestamp in its name, fetching all captures",
+                output
+            ),
+            None => {}
+        }
+    }
+
+    if let Some(from) = &from {
+        validate_timestamp(from).context("Invalid --from value")?;
+    }
+    if let Some(to) = &args.query.to {
+        validate_timestamp(to).context("Invalid --to value")?;
+    }
+    if let Some(closest) = &args.query.closest {
+        validate_timestamp(closest).context("Invalid --closest value")?;
+        // The CDX API sorts by distance and returns a single row for the whole
+        // query, not one per URL under a prefix, host or domain
+        if args.query.match_type.iter().any(|&m| m != MatchType::Exact) {
+            return Err(miette!(
+                help = "Add --match-type exact, --closest picks one capture per queried URL",
+                "--closest only works with --match-type exact"
+            ));
+        }
+    }
+
+    let mut filters = Vec::new();
+    if !args.query.status_codes.iter().any(|code| code == "all") {
+        for code in &args.query.status_codes {
+            if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(miette!(
+                    help = "Use three digit codes like 200,301,404 or \"all\"",
+                    "Invalid --status-codes value: {}",
+                    code
+                ));
+            }
+        }
+        filters.push(status_code_filter(&args.query.status_codes));
+    }
+    for filter in &args.query.filters {
+        validate_cdx_filter(filter).context("Invalid --filter value")?;
+        filters.push(filter.clone());
+    }
+
+    let fields = &args.query.fields;
+    if let Some(field) = fields
+        .iter()
+        .find(|field| !CDX_FIELDS.contains(&field.as_str()))
+    {
+        return Err(miette!(
+            help = format!("Valid fields are {}", CDX_FIELDS.join(", ")),
+            "Unknown --fields value: {}",
+            field
+        ));
+    }
+    for required in ["timestamp", "original"] {
+        if !fields.is_empty() && !fields.iter().any(|field| field == required) {
+            return Err(miette!(
+                help = format!(
+                    "Add {} to --fields, e.g. --fields timestamp,original,digest",
+                    required
+                ),
+                "--fields has to include {}",
+                required
+            ));
+        }
+    }
+
+    if args.s3_bucket.is_some() && !args.format.one_file_per_capture() {
+        return Err(miette!(
+            help = "Use --format html with --s3-bucket",
+            "--s3-bucket only works with --format html"
+        ));
+    }
+    if args.mirror && !args.format.one_file_per_capture() {
+        return Err(miette!(
+            help = "Use --format html with --mirror",
+            "--mirror only works with --format html"
+        ));
+    }
+    if let Some(language) = &args.query.language {
+        validate_language(language).context("Invalid --language value")?;
+        if !args.format.one_file_per_capture()
+            || args.s3_bucket.is_some()
+            || args.gzip
+            || args.output_tar.is_some()
+        {
+            return Err(miette!(
+                help = "Use --format html without --s3-bucket, --gzip and --output-tar with \
+                        --language",
+                "--language reads the pages saved to the output directory"
+            ));
+        }
+    }
+    if args.on_success.is_some() && !args.format.one_file_per_capture() {
+        return Err(miette!(
+            help = "Use --format html with --on-success",
+            "--on-success runs on files saved to the output directory"
+        ));
+    }
+    if let Some(template) = &args.output_template {
+        validate_filename_template(template)?;
+        if template.contains("{digest}")
+            && !args.query.fields.is_empty()
+            && !args.query.fields.iter().any(|field| field == "digest")
+        {
+            return Err(miette!(
+                help = "Add digest to --fields",
+                "--output-template uses {{digest}} but --fields doesn't fetch it"
+            ));
+        }
+    }
+    if args.gzip && !args.format.one_file_per_capture() {
+        return Err(miette!(
+            help = "Use --format html with --gzip, zip archives are compressed already",
+            "--gzip only works with --format html"
+        ));
+    }
+    if args.generate_index && matches!(args.format, OutputFormat::Warc) {
+        return Err(miette!(
+            help = "Use --format html or zip with --generate-index",
+            "--generate-index can't link to records inside a WARC file"
+        ));
+    }
+    if args.generate_readme && matches!(args.format, OutputFormat::Warc) {
+        return Err(miette!(
+            help = "Use --format html or zip with --generate-readme",
+            "--generate-readme can't link to records inside a WARC file"
+        ));
+    }
+    if args.generate_sitemap && !args.format.one_file_per_capture() {
+        return Err(miette!(
+            help = "Use --format html or ndjson with --generate-sitemap",
+            "--generate-sitemap can only point to pages saved as files of their own"
+        ));
+    }
+    if let Some(base) = &args.sitemap_base_url {
+        parse_sitemap_base_url(base)?;
+    }
+    validate_archive_url(&args.cdx_api).context("Invalid --cdx-api value")?;
+    let capture_url_template = capture_url_template(&args)?;
+    validate_timestamp_format(&args.timestamp_format)
+        .context("Invalid --timestamp-format value")?;
+    let content_type_map = args
+        .content_type_map
+        .iter()
+        .map(|mapping| {
+            parse_content_type_mapping(mapping).context("Invalid --content-type-map value")
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+    if args.watch.is_some() && matches!(args.format, OutputFormat::Zip) {
+        return Err(miette!(
+            help = "Use --format html or warc with --watch",
+            "--watch can't add to a zip archive that was already written"
+        ));
+    }
+    let s3 = args
+        .s3_bucket
+        .clone()
+        .map(|bucket| S3Config::from_env(bucket, args.s3_prefix.clone(), args.s3_endpoint.clone()))
+        .transpose()?;
+
+    let include = args
+        .query
+        .include
+        .iter()
+        .map(|pattern| Pattern::new(pattern).context("Invalid --include pattern"))
+        .collect::<Result<Vec<_>>>()?;
+    let exclude = args
+        .query
+        .exclude
+        .iter()
+        .map(|pattern| Pattern::new(pattern).context("Invalid --exclude pattern"))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut urls = collect_urls(args.query.url.as_deref(), args.query.url_file.as_deref())?;
+    if args.query.normalize_url {
+        for url in &mut urls {
+            let normalized = normalize_url(url)?;
+            tracing::info!("Querying {} for {}", normalized, url);
+            *url = normalized;
+        }
+    }
+    let (client, user_agent) = build_client(&args)?;
+    let read_timeout = Duration::from_secs(args.read_timeout);
+
+    // Repeats anywhere in the list would query and download the same captures twice
+    let mut match_types = args.query.match_type.clone();
+    let mut seen = HashSet::new();
+    match_types.retain(|&match_type| seen.insert(match_type));
+
+    // The CDX API returns the first or, for negative values, the last N of
+    // the whole query, which are only the captures of a single URL for exact
+    // ones. The others pick a capture of each URL from all of them.
+    let query = &args.query;
+    let exact = match_types
+        .iter()
+        .all(|&match_type| match_type == MatchType::Exact);
+    let cdx_limit = match (
+        query.newest_only,
+        query.oldest_only || query.closest.is_some(),
+    ) {
+        _ if !exact => None,
+        (true, _) => Some(-1),
+        (_, true) => Some(1),
+        _ => None,
+    };
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        let mut generator = names::Generator::default();
+        generator.next().unwrap()
+    });
+
+    // A zip archive goes to --output itself, the other files of the run are
+    // written next to it
+    let (output_dir, zip_path) = match args.format {
+        OutputFormat::Zip => {
+            let mut zip_path = PathBuf::from(&output);
+            if zip_path
+                .extension()
+                .is_none_or(|extension| extension != "zip")
+            {
+                zip_path = PathBuf::from(format!("{}.zip", output));
+            }
+            let output_dir = match zip_path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.display().to_string(),
+                _ => ".".to_string(),
+            };
+            (output_dir, Some(zip_path))
+        }
+        _ => (output, None),
+    };
+
+    let options = Arc::new(DownloadOptions {
+        output_dir: PathBuf::from(&output_dir),
+        retries: args.retries,
+        backoff: backoff(&args)?,
+        strip_toolbar: args.strip_toolbar || args.mirror,
+        timeout: read_timeout,
+        preserve_path: args.preserve_path || args.mirror,
+        user_agent,
+        user_agent_rotation: user_agent_rotation(&args)?,
+        rate_limiter: (args.rate_limit > 0.0).then(|| Arc::new(RateLimiter::new(args.rate_limit))),
+        header_rate_limiter: args
+            .rate_limit_header
+            .then(|| Arc::new(HeaderRateLimiter::new(args.rate_limit_threshold))),
+        bandwidth_limiter: bandwidth_limiter(&args)?,
+        timeouts: Arc::default(),
+        s3,
+        save_headers: args.save_headers,
+        timestamp_prefix: !args.no_timestamp_prefix,
+        filenames: Arc::default(),
+        filename_template: args.output_template.clone(),
+        gzip: args.gzip,
+        discovered_links: args.extract_links.then(Arc::default),
+        capture_url_template,
+        timestamp_format: args.timestamp_format.clone(),
+        content_type_map,
+    });
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let shutdown = Arc::new(Notify::new());
+    let archives = Arc::new(OpenArchives::default());
+    tokio::spawn({
+        let interrupted = Arc::clone(&interrupted);
+        let shutdown = Arc::clone(&shutdown);
+        let archives = Arc::clone(&archives);
+        let watching = args.watch.is_some();
+        async move {
+            shutdown_signal().await;
+            if watching {
+                tracing::warn!("Stopping after the current batch. Press Ctrl+C again to quit now.");
+            } else {
+                tracing::warn!(
+                    "Interrupted, finishing running downloads. Press Ctrl+C again to quit now."
+                );
+            }
+            interrupted.store(true, Ordering::SeqCst);
+            shutdown.notify_one();
+            shutdown_signal().await;
+            // Exiting skips every destructor, so the archives are ended here
+            archives.close().await;
+            std::process::exit(130);
+        }
+    });
+
+    let mut index = args.index.as_deref().map(DownloadIndex::open).transpose()?;
+    let context = RunContext {
+        args,
+        client: Arc::new(client),
+        options,
+        progress,
+        urls,
+        match_types,
+        cdx_limit,
+        filters,
+        include,
+        exclude,
+        output_dir,
+        zip_path,
+        to_stdout,
+        interrupted,
+        archives,
+    };
+    let args = &context.args;
+
+    // Captures downloaded by earlier batches of a --watch run
+    let mut seen = HashSet::new();
+    let started = Instant::now();
+    let mut metrics = RunMetrics {
+        urls: context.urls.clone(),
+        match_types: context.match_types.clone(),
+        ..RunMetrics::default()
+    };
+    loop {
+        // Captures archived while this batch is in progress are picked up next time
+        let run_started = now_timestamp();
+        let outcome = run_batch(&context, &from, &mut index, &mut seen).await?;
+
+        // Rewritten after every batch, so a --watch run has the totals so far
+        if let Some(outcome) = &outcome
+            && let Some(path) = &args.metrics_file
+        {
+            metrics.add(&RunMetrics {
+                downloads: outcome.downloaded,
+                failed: outcome.failed,
+                bytes: outcome.bytes,
+                cdx_results: outcome.cdx_results,
+                ..RunMetrics::default()
+            });
+            metrics.duration = started.elapsed();
+            write_metrics_file(path, &metrics)?;
+            tracing::info!("Metrics written to {}", path.display());
+        }
+
+        if let Some(outcome) = &outcome
+            && outcome.failed == 0
+            && outcome.pending == 0
+            && let Some(since) = &args.since
+        {
+            write_since_file(since, &run_started)?;
+            from = Some(run_started);
+        }
+
+        let interrupted = context.interrupted.load(Ordering::SeqCst);
+        let Some(interval) = args.watch else {
+            if let Some(outcome) = &outcome
+                && interrupted
+            {
+                tracing::warn!(
+                    "Interrupted — {} downloads completed, {} pending",
+                    outcome.total - outcome.failed - outcome.pending,
+                    outcome.pending
+                );
+                std::process::exit(130);
+            }
+            return Ok(());
+        };
+
+        if interrupted {
+            tracing::info!("Stopped watching for new captures.");
+            return Ok(());
+        }
+
+        tracing::info!("Checking for new captures again in {}s.", interval);
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(interval)) => {}
+            _ = shutdown.notified() => {
+                tracing::info!("Stopped watching for new captures.");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Submits every URL of `noway submit` to the save API, one at a time, and
+/// prints the resulting snapshot URLs to stdout.
+async fn run_submit(args: &Args, submit: &SubmitArgs) -> Result<()> {
+    let urls = collect_urls(submit.url.as_deref(), submit.url_file.as_deref())?;
+    let (client, user_agent) = build_client(args)?;
+    let rate_limiter = (args.rate_limit > 0.0).then(|| RateLimiter::new(args.rate_limit));
+    let backoff = backoff(args)?;
+
+    let mut failed = 0;
+    for url in &urls {
+        tracing::info!("Submitting {} to the Wayback Machine", url);
+        let submitted = submit_url(
+            &client,
+            url,
+            args.retries,
+            backoff,
+            rate_limiter.as_ref(),
+            &user_agent,
+        );
+        match submitted.await {
+            Ok(snapshot) => println!("{}", snapshot),
+            Err(e) => {
+                tracing::error!("Failed to submit {}: {}", url, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(miette!(
+            "{} of {} URLs could not be submitted",
+            failed,
+            urls.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Prints the snapshot `noway availability` finds, exiting with 1 when the
+/// URL hasn't been archived.
+async fn run_availability(args: &Args, availability: &AvailabilityArgs) -> Result<()> {
+    if let Some(timestamp) = &availability.timestamp {
+        validate_timestamp(timestamp).context("Invalid --timestamp value")?;
+    }
+    validate_timestamp_format(&args.timestamp_format)
+        .context("Invalid --timestamp-format value")?;
+    let (client, user_agent) = build_client(args)?;
+
+    let snapshot = check_availability(
+        &client,
+        &availability.url,
+        availability.timestamp.as_deref(),
+        &user_agent,
+    )
+    .await?;
+    match snapshot {
+        Some(snapshot) => {
+            println!(
+                "Available: {} archived at {}",
+                availability.url,
+                format_timestamp(&snapshot.timestamp, &args.timestamp_format)
+            );
+            println!("{}", snapshot.url);
+            Ok(())
+        }
+        None => {
+            println!("Not available: no snapshot of {}", availability.url);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Fetches the two captures `noway diff` compares at once and prints a
+/// unified diff of them, or writes the HTML one. Exits with 1 when they
+/// differ, like `diff`.
+async fn run_diff(args: &Args, diff: &DiffArgs) -> Result<()> {
+    validate_timestamp(&diff.timestamp1).context("Invalid first timestamp")?;
+    validate_timestamp(&diff.timestamp2).context("Invalid second timestamp")?;
+    let (client, user_agent) = build_client(args)?;
+    let options = DownloadOptions {
+        capture_url_template: capture_url_template(args)?,
+        retries: args.retries,
+        backoff: backoff(args)?,
+        strip_toolbar: true,
+        timeout: Duration::from_secs(args.read_timeout),
+        user_agent,
+        user_agent_rotation: user_agent_rotation(args)?,
+        rate_limiter: (args.rate_limit > 0.0).then(|| Arc::new(RateLimiter::new(args.rate_limit))),
+        header_rate_limiter: args
+            .rate_limit_header
+            .then(|| Arc::new(HeaderRateLimiter::new(args.rate_limit_threshold))),
+        bandwidth_limiter: bandwidth_limiter(args)?,
+        ..DownloadOptions::default()
+    };
+
+    let capture = |timestamp: &str| CdxCapture {
+        url_key: None,
+        timestamp: timestamp.to_string(),
+        original_url: diff.url.clone(),
+        mime_type: None,
+        status_code: None,
+        digest: None,
+        length: None,
+        robot_flags: None,
+    };
+    let (old, new) = (capture(&diff.timestamp1), capture(&diff.timestamp2));
+    let (old_fetched, new_fetched) = tokio::try_join!(
+        async {
+            fetch_capture(&client, &old, &options)
+                .await
+                .context(format!("Failed to download {}", options.capture_url(&old)))
+        },
+        async {
+            fetch_capture(&client, &new, &options)
+                .await
+                .context(format!("Failed to download {}", options.capture_url(&new)))
+        },
+    )?;
+
+    let old_body = String::from_utf8_lossy(&old_fetched.body);
+    let new_body = String::from_utf8_lossy(&new_fetched.body);
+    let changed = old_body != new_body;
+    let (old_label, new_label) = (options.capture_url(&old), options.capture_url(&new));
+    if let Some(path) = &diff.html_diff {
+        let html = html_diff(&old_body, &new_body, &old_label, &new_label);
+        fs::write(path, html)
+            .into_diagnostic()
+            .context(format!("Failed to write HTML diff: {}", path.display()))?;
+        tracing::info!("Wrote side-by-side diff to {}", path.display());
+    } else {
+        let unified = unified_diff(&old_body, &new_body, &old_label, &new_label, diff.context);
+        print!("{}", unified);
+    }
+
+    if changed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Checks the files of a download against the CDX digests of their captures
+/// for `noway verify`, hashing them in parallel, and prints the ones that
+/// differ or are missing. Exits with 1 if there are any.
+async fn run_verify(verify: &VerifyArgs) -> Result<()> {
+    let files = match (&verify.index, &verify.dir) {
+        (Some(index), _) => read_entries(index)?
+            .into_iter()
+            .filter_map(|entry| Some((entry.local_path?, entry.digest)))
+            .collect(),
+        (None, Some(dir)) => {
+            let metadata_file = dir.join("metadata.ndjson");
+            let records = fs::read_to_string(&metadata_file)
+                .into_diagnostic()
+                .context(format!(
+                    "Failed to read {}, is {} the output directory of a download?",
+                    metadata_file.display(),
+                    dir.display()
+                ))?;
+            verified_files(dir, &records)
+        }
+        (None, None) => unreachable!("clap requires a directory or --index"),
+    };
+
+    let mut checks = Vec::new();
+    let mut without_digest = 0;
+    for (path, digest) in files {
+        let Some(digest) = digest else {
+            without_digest += 1;
+            continue;
+        };
+        checks.push(tokio::task::spawn_blocking(move || {
+            let verification = verify_file(&path, &digest);
+            (path, verification)
+        }));
+    }
+
+    let (mut matched, mut mismatched, mut missing, mut stripped) = (0, 0, 0, 0);
+    for check in checks {
+        let (path, verification) = check.await.into_diagnostic()?;
+        match verification? {
+            Verification::Match => matched += 1,
+            Verification::MatchWithoutArtifacts => {
+                matched += 1;
+                stripped += 1;
+            }
+            Verification::Mismatch => {
+                mismatched += 1;
+                println!("MISMATCH {}", path.display());
+            }
+            Verification::Missing => {
+                missing += 1;
+                println!("MISSING {}", path.display());
+            }
+        }
+    }
+
+    tracing::info!(
+        "{} files match their CDX digest, {} don't and {} are missing",
+        matched,
+        mismatched,
+        missing
+    );
+    if stripped > 0 {
+        tracing::info!(
+            "{} of them only without the Wayback toolbar and scripts",
+            stripped
+        );
+    }
+    if without_digest > 0 {
+        tracing::info!("Skipped {} files without a CDX digest", without_digest);
+    }
+    if mismatched > 0 {
+        tracing::warn!(
+            "The CDX digest is of the capture as first archived, HTML pages with links rewritten \
+             by the Wayback Machine can't match it"
+        );
+    }
+    if mismatched > 0 || missing > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Prints the timemap `noway timemap` fetches: as is, as JSON, or one line
+/// per capture with its timestamp followed by a summary.
+async fn run_timemap(args: &Args, timemap: &TimemapArgs) -> Result<()> {
+    validate_timestamp_format(&args.timestamp_format)
+        .context("Invalid --timestamp-format value")?;
+    let wayback_base = match &args.wayback_base {
+        Some(base) => {
+            validate_archive_url(base).context("Invalid --wayback-base value")?;
+            base.as_str()
+        }
+        None => DEFAULT_WAYBACK_BASE,
+    };
+    let (client, user_agent) = build_client(args)?;
+
+    let body = fetch_timemap(&client, &timemap.url, wayback_base, &user_agent).await?;
+    if timemap.raw {
+        print!("{}", body);
+        return Ok(());
+    }
+    let links = parse_timemap(&body);
+    if timemap.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&links).into_diagnostic()?
+        );
+        return Ok(());
+    }
+
+    let mementos: Vec<&TimemapLink> = links.iter().filter(|link| link.is_memento()).collect();
+    let (Some(first), Some(last)) = (mementos.first(), mementos.last()) else {
+        println!("No captures of {} in the timemap", timemap.url);
+        return Ok(());
+    };
+    let when = |link: &TimemapLink| match &link.timestamp {
+        Some(timestamp) => format_timestamp(timestamp, &args.timestamp_format),
+        None => link.datetime.clone().unwrap_or_default(),
+    };
+    for memento in &mementos {
+        println!("{}  {}", when(memento), memento.url);
+    }
+    let original = links
+        .iter()
+        .find(|link| link.rel.split_whitespace().any(|rel| rel == "original"))
+        .map_or(timemap.url.as_str(), |link| link.url.as_str());
+    println!(
+        "{} captures of {} from {} to {}",
+        mementos.len(),
+        original,
+        when(first),
+        when(last)
+    );
+    Ok(())
+}
+
+/// The URL template captures are downloaded from, after `--wayback-base` or
+/// `--capture-url-template`.
+fn capture_url_template(args: &Args) -> Result<String> {
+    let template = match (&args.wayback_base, &args.capture_url_template) {
+        (Some(base), _) => format!("{}/{{timestamp}}/{{url}}", base.trim_end_matches('/')),
+        (None, Some(template)) => template.clone(),
+        (None, None) => return Ok(DEFAULT_CAPTURE_URL_TEMPLATE.to_string()),
+    };
+    validate_capture_url_template(&template).context(if args.wayback_base.is_some() {
+        "Invalid --wayback-base value"
+    } else {
+        "Invalid --capture-url-template value"
+    })?;
+    Ok(template)
+}
+
+/// Prints where the config file is read from, or writes the template there
+/// with `--init`.
+fn run_config(config: &ConfigArgs) -> Result<()> {
+    let path = config::config_path().ok_or_else(|| {
+        miette!(
+            help = "Set HOME or XDG_CONFIG_HOME",
+            "Couldn't find the config directory"
+        )
+    })?;
+    if !config.init {
+        println!("{}", path.display());
+        return Ok(());
+    }
+
+    if path.exists() && !config.force {
+        return Err(miette!(
+            help = "Pass --force to replace it with the template",
+            "Config file already exists: {}",
+            path.display()
+        ));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .into_diagnostic()
+            .context(format!(
+                "Failed to create config directory: {}",
+                parent.display()
+            ))?;
+    }
+    fs::write(&path, config::TEMPLATE)
+        .into_diagnostic()
+        .context(format!("Failed to write config file: {}", path.display()))?;
+    println!("Wrote config template to {}", path.display());
+    Ok(())
+}
+
+/// The URL given on the command line followed by the ones in `url_file`.
+fn collect_urls(url: Option<&str>, url_file: Option<&Path>) -> Result<Vec<String>> {
+    let mut urls = Vec::new();
+    if let Some(url) = url {
+        urls.push(url.to_string());
+    }
+    if let Some(url_file) = url_file {
+        let content = fs::read_to_string(url_file)
+            .into_diagnostic()
+            .context(format!("Failed to read URL file: {}", url_file.display()))?;
+        urls.extend(read_url_list(&content));
+    }
+    Ok(urls)
+}
+
+/// Address connections are made from with `--ipv4` or `--ipv6`. Binding to
+/// the unspecified address of one family lets the OS pick the interface but
+/// only connect to addresses of that family.
+fn local_address(ipv4: bool, ipv6: bool) -> Option<IpAddr> {
+    if ipv4 {
+        Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    } else if ipv6 {
+        Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+    } else {
+        None
+    }
+}
+
+/// HTTP client with the timeouts and proxy from `args`, along with the
+/// User-Agent of `--user-agent` for its requests to send.
+fn build_client(args: &Args) -> Result<(Client, String)> {
+    let user_agent = if args.user_agent == "noway" {
+        format!("noway/{}", env!("CARGO_PKG_VERSION"))
+    } else {
+        args.user_agent.clone()
+    };
+
+    // Idle connections kept per host unless --connection-pool-size is given
+    const MIN_CONNECTION_POOL_SIZE: usize = 10;
+    const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+    let pool_size = args
+        .connection_pool_size
+        .unwrap_or(if args.concurrency_auto {
+            MAX_CONCURRENCY
+        } else {
+            args.concurrency.max(MIN_CONNECTION_POOL_SIZE)
+        });
+    let mut client = Client::builder()
+        .connect_timeout(Duration::from_secs(args.connect_timeout))
+        .pool_max_idle_per_host(pool_size)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        // Logs every read and write on the connections at the trace level
+        .connection_verbose(args.verbose >= 2)
+        .local_address(local_address(args.ipv4, args.ipv6));
+
+    if let Some(proxy) = &args.proxy {
+        let proxy_url = Url::parse(proxy)
+            .into_diagnostic()
+            .context(format!("Invalid --proxy URL: {}", proxy))?;
+        if !matches!(proxy_url.scheme(), "http" | "https") {
+            return Err(miette!(
+                help = "Use a URL like http://proxy.example.com:8080",
+                "Unsupported proxy scheme: {}",
+                proxy_url.scheme()
+            ));
+        }
+        client = client.proxy(Proxy::all(proxy_url).into_diagnostic()?);
+    }
+
+    if let Some(path) = &args.ca_cert {
+        let pem = fs::read(path)
+            .into_diagnostic()
+            .context(format!("Failed to read --ca-cert file: {}", path.display()))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .into_diagnostic()
+            .context(format!("Invalid --ca-cert file: {}", path.display()))?;
+        if certificates.is_empty() {
+            return Err(miette!(
+                help = "The file should hold PEM encoded certificates, -----BEGIN CERTIFICATE-----",
+                "No certificates in --ca-cert file: {}",
+                path.display()
+            ));
+        }
+        for certificate in certificates {
+            client = client.add_root_certificate(certificate);
+        }
+    }
+    if args.insecure {
+        // Printed even with --quiet, nothing else says the connection isn't
+        // verified
+        eprintln!(
+            "WARNING: --insecure turns off TLS certificate verification. Anyone between you \
+             and the archive can read and change the captures you download."
+        );
+        client = client.danger_accept_invalid_certs(true);
+    }
+
+    client = client.default_headers(auth_headers(args)?);
+
+    let client = client.build().into_diagnostic()?;
+    Ok((client, user_agent))
+}
+
+/// The limiter of `--bandwidth-limit`, if it was given.
+fn bandwidth_limiter(args: &Args) -> Result<Option<Arc<BandwidthLimiter>>> {
+    let Some(limit) = &args.bandwidth_limit else {
+        return Ok(None);
+    };
+    let bytes_per_second = parse_byte_rate(limit).context("Invalid --bandwidth-limit value")?;
+    Ok(Some(Arc::new(BandwidthLimiter::new(bytes_per_second))))
+}
+
+/// The User-Agents of `--user-agent-rotate`.
+fn user_agent_rotation(args: &Args) -> Result<Option<Arc<UserAgentRotation>>> {
+    let Some(path) = &args.user_agent_rotate else {
+        return Ok(None);
+    };
+    let list = fs::read_to_string(path).into_diagnostic().context(format!(
+        "Failed to read --user-agent-rotate file: {}",
+        path.display()
+    ))?;
+    let rotation = UserAgentRotation::parse(&list, args.user_agent_rotate_random).context(
+        format!("Invalid --user-agent-rotate file: {}", path.display()),
+    )?;
+    Ok(Some(Arc::new(rotation)))
+}
+
+/// The pauses between retries of `--retry-delay`, `--retry-backoff-factor`
+/// and `--retry-jitter`.
+fn backoff(args: &Args) -> Result<Backoff> {
+    Backoff::new(
+        args.retry_delay,
+        args.retry_backoff_factor,
+        args.retry_jitter,
+    )
+    .context("Invalid --retry-delay or --retry-backoff-factor value")
+}
+
+/// The `Authorization` and `X-API-Key` headers of `--bearer-token` and
+/// `--api-key`. Unlike for other options their environment variables win
+/// over the command line, so a token kept there is the one that is used.
+fn auth_headers(args: &Args) -> Result<HeaderMap> {
+    let secret = |name: &str, flag: &Option<String>| {
+        std::env::var(name)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .or_else(|| flag.clone())
+    };
+    let header = |option: &str, value: String| {
+        let mut value = HeaderValue::from_str(&value).map_err(|_| {
+            miette!(
+                help = "Tokens and keys can only hold visible ASCII characters and spaces",
+                "Invalid {} value",
+                option
+            )
+        })?;
+        // Keeps it out of debug logs of the requests
+        value.set_sensitive(true);
+        Ok::<_, miette::Report>(value)
+    };
+
+    let mut headers = HeaderMap::new();
+    if let Some(token) = secret("NOWAY_BEARER_TOKEN", &args.bearer_token) {
+        let value = header("--bearer-token", format!("Bearer {}", token))?;
+        headers.insert(AUTHORIZATION, value);
+    }
+    if let Some(key) = secret("NOWAY_API_KEY", &args.api_key) {
+        headers.insert("x-api-key", header("--api-key", key)?);
+    }
+    Ok(headers)
+}
+
+/// Queries the CDX API and downloads every matching capture that isn't in
+/// `seen` yet. Returns `None` when captures were only reported, with
+/// `--dry-run` or `--output-format json`.
+async fn run_batch(
+    context: &RunContext,
+    from: &Option<String>,
+    index: &mut Option<DownloadIndex>,
+    seen: &mut HashSet<String>,
+) -> Result<Option<BatchOutcome>> {
+    let (sender, pages) = mpsc::channel(context.args.query.concurrency_cdx.max(1));
+    let fetch = async move {
+        if let Err(e) = fetch_captures(context, from, &sender).await {
+            let _ = sender.send(Err(e)).await;
+        }
+    };
+    let ((), outcome) = tokio::join!(fetch, download_pages(context, pages, index, seen));
+    outcome
+}
+
+/// A page of CDX results and the URL they were queried for.
+struct CdxPage {
+    url: Arc<str>,
+    captures: Vec<CdxCapture>,
+}
+
+/// The `fl` columns to ask the CDX API for. Captures flagged `X` are only
+/// skipped when `robotflags` comes back, and the default columns leave it
+/// out, so it is added unless `--include-robot-excluded` keeps them anyway.
+fn cdx_fields(query: &QueryArgs) -> Vec<String> {
+    let mut fields = query.fields.clone();
+    if !query.include_robot_excluded {
+        if fields.is_empty() {
+            fields = DEFAULT_CDX_FIELDS.map(str::to_string).to_vec();
+        }
+        if !fields.iter().any(|field| field == "robotflags") {
+            fields.push("robotflags".to_string());
+        }
+    }
+    fields
+}
+
+/// Sends the captures of every CDX query to `pages`, a page at a time.
+async fn fetch_captures(
+    context: &RunContext,
+    from: &Option<String>,
+    pages: &mpsc::Sender<Result<CdxPage>>,
+) -> Result<()> {
+    let args = &context.args;
+    let client = &context.client;
+    // Single capture queries have nothing to paginate
+    let paginate = context.cdx_limit.is_none();
+
+    // The CDX API collapses neighbouring rows, which are only all captures
+    // of the same URL for exact queries. Archives that ignore collapse, and
+    // the other match types, are left to the filter.
+    let mut collapse = args.query.collapse.clone();
+    if let Some((_, digits)) = args.query.period()
+        && context
+            .match_types
+            .iter()
+            .all(|&match_type| match_type == MatchType::Exact)
+    {
+        collapse.push(format!("timestamp:{}", digits));
+    }
+    // Only the first, oldest, row of every URL. Like the period above, the
+    // filter keeps the oldest capture itself for archives that don't collapse.
+    if args.query.oldest_only && context.cdx_limit.is_none() {
+        collapse.push("urlkey".to_string());
+    }
+
+    // Queries share their own semaphore so a long URL list doesn't compete
+    // with the downloads for permits
+    let cdx_semaphore = Semaphore::new(args.query.concurrency_cdx.max(1));
+    let url_results = context.urls.iter().map(|url| async {
+        let queries: Vec<_> = context
+            .match_types
+            .iter()
+            .map(|&match_type| CdxQuery {
+                cdx_api: args.cdx_api.clone(),
+                url: url.clone(),
+                match_type,
+                from: from.clone(),
+                to: args.query.to.clone(),
+                filters: context.filters.clone(),
+                mime_types: args.query.mime_types.clone(),
+                collapse: collapse.clone(),
+                timeout: Some(Duration::from_secs(args.timeout_cdx)),
+                output: CdxOutput::Json,
+                limit: context.cdx_limit,
+                closest: args.query.closest.clone(),
+                fields: cdx_fields(&args.query),
+                page: args.query.page,
+                page_size: paginate.then_some(args.query.page_size),
+                user_agent: context.options.user_agent.clone(),
+            })
+            .collect();
+
+        if paginate {
+            futures::future::try_join_all(queries.iter().map(|query| {
+                let concurrency = args.query.concurrency_cdx.max(1);
+                let max_pages = args.query.max_pages;
+                fetch_pages(client, query, max_pages, concurrency, &cdx_semaphore, pages)
+            }))
+            .await?;
+            return Ok(());
+        }
+
+        let results = futures::future::try_join_all(queries.iter().map(|query| async {
+            let _permit = cdx_semaphore.acquire().await.unwrap();
+            get_wayback_cdx_urls(client, query).await
+        }))
+        .await?;
+        let mut url_captures: Vec<_> = results.into_iter().flatten().collect();
+
+        // Every match type returns its own single capture, keep the one wanted
+        if url_captures.len() > 1 {
+            url_captures.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+            if let Some(closest) = &args.query.closest {
+                let target = timestamp_number(closest);
+                let nearest = url_captures
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, capture)| {
+                        timestamp_number(&capture.timestamp).abs_diff(target)
+                    })
+                    .map(|(i, _)| i)
+                    .unwrap_or_default();
+                url_captures.swap(0, nearest);
+                url_captures.truncate(1);
+            } else if args.query.newest_only {
+                url_captures.drain(..url_captures.len() - 1);
+            } else {
+                url_captures.truncate(1);
+            }
+        }
+        let page = CdxPage {
+            url: url.as_str().into(),
+            captures: url_captures,
+        };
+        pages
+            .send(Ok(page))
+            .await
+            .map_err(|_| miette!("Stopped fetching captures"))
+    });
+    futures::future::try_join_all(url_results).await?;
+
+    Ok(())
+}
+
+/// Fetches every page of `query`, or only `query.page` when set, and sends
+/// them to `pages` in order. Stops after `max_pages` pages.
+///
+/// Once the page count is known up to `concurrency` pages are fetched at a
+/// time, and a page that arrives early waits for the ones before it.
+async fn fetch_pages(
+    client: &Client,
+    query: &CdxQuery,
+    max_pages: Option<u32>,
+    concurrency: usize,
+    semaphore: &Semaphore,
+    pages: &mpsc::Sender<Result<CdxPage>>,
+) -> Result<()> {
+    let page_numbers = match query.page {
+        Some(page) => page..page + 1,
+        None => {
+            let _permit = semaphore.acquire().await.unwrap();
+            let count = get_cdx_page_count(client, query).await?;
+            match max_pages {
+                Some(max_pages) if count > max_pages => {
+                    tracing::warn!(
+                        "Only fetching the first {} of {} pages of archived URLs for {}, \
+                         the results are truncated",
+                        max_pages,
+                        count,
+                        query.url
+                    );
+                    0..max_pages
+                }
+                _ => {
+                    tracing::info!(
+                        "Fetching {} pages of archived URLs for {}",
+                        count,
+                        query.url
+                    );
+                    0..count
+                }
+            }
+        }
+    };
+
+    // Buffering only `concurrency` pages ahead keeps a slow page from
+    // piling up the ones after it in memory
+    let mut fetched = futures::stream::iter(page_numbers)
+        .map(|page| async move {
+            let query = CdxQuery {
+                page: Some(page),
+                ..query.clone()
+            };
+            let _permit = semaphore.acquire().await.unwrap();
+            get_wayback_cdx_urls(client, &query).await
+        })
+        .buffered(concurrency);
+    let url: Arc<str> = query.url.as_str().into();
+    while let Some(captures) = fetched.next().await {
+        let page = CdxPage {
+            url: Arc::clone(&url),
+            captures: captures?,
+        };
+        pages
+            .send(Ok(page))
+            .await
+            .map_err(|_| miette!("Stopped fetching captures"))?;
+    }
+
+    Ok(())
+}
 
-    #[arg(short, long, help = "Output directory for downloaded files")]
-    output: Option<String>,
+/// Filters the captures arriving on `pages` and downloads them.
+///
+/// Downloads start with the first page, unless `--limit`, `--sort`,
+/// `--mirror` or reporting the captures needs all of them first.
+async fn download_pages(
+    context: &RunContext,
+    mut pages: mpsc::Receiver<Result<CdxPage>>,
+    index: &mut Option<DownloadIndex>,
+    seen: &mut HashSet<String>,
+) -> Result<Option<BatchOutcome>> {
+    let args = &context.args;
+    let json_output = args.output_format == ReportFormat::Json;
+    let report_only = args.dry_run || json_output;
+    let use_index = index.is_some();
 
-    #[arg(
-        short,
-        long,
-        default_value = "prefix",
-        help = "Match type for URL search"
-    )]
-    match_type: String,
+    // Without a server side limit the newest capture of a URL can be on any
+    // page of the results
+    let newest_per_url =
+        args.query.dedup_url_latest || (args.query.newest_only && context.cdx_limit.is_none());
+    if args.query.limit.is_some()
+        || args.query.sort.is_some()
+        || newest_per_url
+        || args.query.dedup_by_day
+        || args.mirror
+        || report_only
+        || context.to_stdout
+    {
+        let mut filter = CaptureFilter::new(context, seen, index.as_ref(), false);
+        let mut captures = Vec::new();
+        // The URL every capture was queried for, to take turns by
+        let mut sources: HashMap<(String, String), Arc<str>> = HashMap::new();
+        while let Some(page) = pages.recv().await {
+            let page = page?;
+            filter.load_robots(&page.captures).await;
+            let kept = filter.apply(page.captures);
+            if args.parallel_snapshots {
+                for capture in &kept {
+                    let id = (capture.timestamp.clone(), capture.original_url.clone());
+                    sources.insert(id, Arc::clone(&page.url));
+                }
+            }
+            captures.extend(kept);
+        }
+        if args.query.dedup_by_day {
+            // Before --deduplicate, so a day whose last capture has the
+            // content of an earlier day is dropped rather than kept earlier
+            let before = captures.len();
+            captures = latest_per_url_per_day(captures);
+            filter.same_day = before - captures.len();
+        }
+        if args.query.deduplicate {
+            let before = captures.len();
+            captures = deduplicate_by_digest(captures);
+            filter.duplicates = before - captures.len();
+        }
+        if newest_per_url {
+            // The captures of a URL can be spread over several pages
+            let before = captures.len();
+            captures = latest_per_url(captures);
+            filter.older = before - captures.len();
+        }
+        filter.log_summary();
 
-    #[arg(
-        short,
-        long,
-        default_value = "5",
-        help = "Maximum concurrent downloads"
-    )]
-    concurrency: usize,
+        if let Some(limit) = args.query.limit {
+            // CDX results come back oldest first, keep the most recent ones
+            captures.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+            let excess = captures.len().saturating_sub(limit);
+            captures.drain(..excess);
+        }
+        if let Some(order) = args.query.sort {
+            order.sort(&mut captures);
+        }
+
+        if let Some(counting) = args.counting {
+            print_count(&captures, counting);
+            return Ok(None);
+        }
+
+        if captures.is_empty() {
+            tracing::info!("No archived URLs found.");
+            return Ok((!report_only).then_some(BatchOutcome {
+                total: 0,
+                failed: 0,
+                pending: 0,
+                downloaded: 0,
+                bytes: 0,
+                cdx_results: filter.received,
+            }));
+        }
+
+        let total = captures.len();
+
+        if json_output {
+            let report: Vec<_> = captures
+                .iter()
+                .map(|capture| CaptureReport {
+                    capture,
+                    wayback_url: context.options.capture_url(capture),
+                })
+                .collect();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&report).into_diagnostic()?
+            );
+            return Ok(None);
+        }
+
+        if args.dry_run {
+            for capture in &captures {
+                println!("{}", context.options.capture_url(capture));
+            }
+            tracing::info!("Found {} archived URLs.", total);
+            return Ok(None);
+        }
+
+        tracing::info!("Found {} archived URLs.", total);
+        if context.to_stdout {
+            let mut outcome = write_to_stdout(context, &captures).await?;
+            outcome.cdx_results = filter.received;
+            return Ok(Some(outcome));
+        }
+        // Links between pages can only be rewritten knowing every page
+        let mirror = args
+            .mirror
+            .then(|| Mirror::new(&captures, &context.options));
+        let mut downloader = Downloader::new(context, use_index, mirror).await?;
+        downloader.queue(captures, |capture| {
+            let id = (capture.timestamp.clone(), capture.original_url.clone());
+            sources.get(&id).cloned().unwrap_or_else(|| Arc::from(""))
+        });
+        let (skipped, received) = (filter.skipped(), filter.received);
+        let mut outcome = downloader.finish(index, seen, skipped).await?;
+        outcome.cdx_results = received;
+        return Ok(Some(outcome));
+    }
+
+    let mut filter = CaptureFilter::new(context, seen, index.as_ref(), args.query.deduplicate);
+    let mut downloader = None;
+    let mut error = None;
+    while let Some(page) = pages.recv().await {
+        let (url, captures) = match page {
+            Ok(page) => {
+                filter.load_robots(&page.captures).await;
+                (page.url, filter.apply(page.captures))
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        if captures.is_empty() {
+            continue;
+        }
+
+        if downloader.is_none() {
+            downloader = Some(Downloader::new(context, use_index, None).await?);
+        }
+        if let Some(downloader) = &mut downloader {
+            downloader.queue(captures, |_| Arc::clone(&url));
+        }
+
+        // Captures on pages that weren't fetched yet can't be listed as
+        // pending, so an interrupted run stops at the current page
+        if args.watch.is_none() && context.interrupted.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+    drop(pages);
+    filter.log_summary();
+
+    let Some(downloader) = downloader else {
+        if let Some(error) = error {
+            return Err(error);
+        }
+        tracing::info!("No archived URLs found.");
+        return Ok(Some(BatchOutcome {
+            total: 0,
+            failed: 0,
+            pending: 0,
+            downloaded: 0,
+            bytes: 0,
+            cdx_results: filter.received,
+        }));
+    };
+
+    tracing::info!("Found {} archived URLs.", downloader.total);
+    let (skipped, received) = (filter.skipped(), filter.received);
+    let mut outcome = downloader.finish(index, seen, skipped).await?;
+    outcome.cdx_results = received;
+    match error {
+        Some(error) => Err(error),
+        None => Ok(Some(outcome)),
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+/// Prints the number of `captures` for `noway count`, after the number of
+/// every year they were captured in with `--by-year`.
+fn print_count(captures: &[CdxCapture], counting: Counting) {
+    if let Counting::ByYear = counting {
+        let mut years: std::collections::BTreeMap<&str, usize> = Default::default();
+        for capture in captures {
+            *years
+                .entry(capture.timestamp.get(..4).unwrap_or("????"))
+                .or_default() += 1;
+        }
+        for (year, count) in years {
+            println!("{} {}", year, count);
+        }
+        println!("total {}", captures.len());
+    } else {
+        println!("{}", captures.len());
+    }
+}
 
-    let output_dir = args.output.unwrap_or_else(|| {
-        let mut generator = names::Generator::default();
-        generator.next().unwrap()
-    });
+/// Downloads `captures` one after another for `--output -` and writes their
+/// bodies to stdout, without creating any files.
+async fn write_to_stdout(context: &RunContext, captures: &[CdxCapture]) -> Result<BatchOutcome> {
+    let total = captures.len();
+    if total > 1 {
+        tracing::warn!(
+            "{} captures match and all of them are written to stdout, use --limit 1 or \
+             --newest-only for a single one",
+            total
+        );
+    }
 
-    fs::create_dir_all(&output_dir)
-        .into_diagnostic()
-        .context(format!("Failed to create output directory: {}", output_dir))?;
+    let mut stdout = tokio::io::stdout();
+    let mut failed = 0;
+    let mut pending = 0;
+    let mut bytes = 0;
+    for (i, capture) in captures.iter().enumerate() {
+        if context.interrupted.load(Ordering::SeqCst) {
+            pending = total - i;
+            break;
+        }
+        match fetch_capture(&context.client, capture, &context.options).await {
+            Ok(fetched) => {
+                stdout.write_all(&fetched.body).await.into_diagnostic()?;
+                stdout.flush().await.into_diagnostic()?;
+                bytes += fetched.body.len() as u64;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to download {}: {}",
+                    context.options.capture_url(capture),
+                    e
+                );
+                failed += 1;
+            }
+        }
+    }
 
-    println!(
-        "Fetching archived URLs for {} using CDX API",
-        args.url
-    );
+    Ok(BatchOutcome {
+        total,
+        failed,
+        pending,
+        downloaded: total - failed - pending,
+        bytes,
+        cdx_results: 0,
+    })
+}
 
-    let client = Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()
-        .into_diagnostic()?;
+/// Drops captures that shouldn't be downloaded, a CDX page at a time.
+struct CaptureFilter<'a> {
+    context: &'a RunContext,
+    seen: &'a HashSet<String>,
+    index: Option<&'a DownloadIndex>,
+    /// Rows the CDX API returned, before any filter.
+    received: usize,
+    /// Only keep the first capture of every digest.
+    deduplicate: bool,
+    wayback_urls: HashSet<String>,
+    digests: HashSet<String>,
+    /// robots.txt rules of every host seen with `--respect-robots`, `None`
+    /// when it has no archived robots.txt.
+    robots: HashMap<String, Option<RobotsTxt>>,
+    by_pattern: usize,
+    duplicates: usize,
+    too_large: usize,
+    indexed: usize,
+    disallowed: usize,
+    /// Captures flagged `X` in their `robotflags`.
+    robot_excluded: usize,
+    other_language: usize,
+    /// Captures with a newer one of the same URL, with `--dedup-url-latest`,
+    /// or `--newest-only` for queries that aren't exact.
+    older: usize,
+    /// Captures with a newer one of the same URL that day, with
+    /// `--dedup-by-day`.
+    same_day: usize,
+    /// URL and period of every capture kept with `--first-per-day`,
+    /// `--first-per-month` or `--first-per-year`.
+    periods: HashSet<(String, String)>,
+    same_period: usize,
+    /// Every URL a capture was kept of with `--oldest-only`.
+    oldest: HashSet<String>,
+    /// Captures with an older one of the same URL, with `--oldest-only`.
+    newer: usize,
+}
 
-    let capture_urls = get_wayback_cdx_urls(&client, &args.url, &args.match_type).await?;
+impl<'a> CaptureFilter<'a> {
+    fn new(
+        context: &'a RunContext,
+        seen: &'a HashSet<String>,
+        index: Option<&'a DownloadIndex>,
+        deduplicate: bool,
+    ) -> Self {
+        Self {
+            context,
+            seen,
+            index,
+            received: 0,
+            deduplicate,
+            wayback_urls: HashSet::new(),
+            digests: HashSet::new(),
+            robots: HashMap::new(),
+            by_pattern: 0,
+            duplicates: 0,
+            too_large: 0,
+            indexed: 0,
+            disallowed: 0,
+            robot_excluded: 0,
+            other_language: 0,
+            older: 0,
+            same_day: 0,
+            periods: HashSet::new(),
+            same_period: 0,
+            oldest: HashSet::new(),
+            newer: 0,
+        }
+    }
 
-    if capture_urls.is_empty() {
-        println!("No archived URLs found.");
-        return Ok(());
+    /// Fetches the robots.txt of the hosts in `captures` not seen before,
+    /// with `--respect-robots`.
+    async fn load_robots(&mut self, captures: &[CdxCapture]) {
+        if !self.context.args.query.respect_robots {
+            return;
+        }
+        let hosts: HashSet<String> = captures
+            .iter()
+            .filter_map(|capture| robots_location(&capture.original_url))
+            .map(|(host, _)| host)
+            .filter(|host| !self.robots.contains_key(host))
+            .collect();
+        let context = self.context;
+        let fetched = futures::future::join_all(hosts.into_iter().map(|host| async move {
+            let robots = fetch_archived_robots_txt(
+                &context.client,
+                &host,
+                &context.args.cdx_api,
+                &context.options,
+            )
+            .await;
+            (host, robots)
+        }))
+        .await;
+        for (host, robots) in fetched {
+            let robots = robots.unwrap_or_else(|e| {
+                // Nothing is skipped for a host without rules
+                tracing::warn!("Failed to fetch archived robots.txt of {}: {}", host, e);
+                None
+            });
+            self.robots.insert(host, robots);
+        }
     }
 
-    let total = capture_urls.len();
-    println!("Found {} archived URLs.", total);
+    fn apply(&mut self, mut captures: Vec<CdxCapture>) -> Vec<CdxCapture> {
+        let context = self.context;
+        self.received += captures.len();
 
-    let semaphore = Arc::new(Semaphore::new(args.concurrency));
-    let client = Arc::new(client);
-    let output_dir = Arc::new(output_dir);
-    let failed_urls = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+        let options = &context.options;
+        if context.match_types.len() > 1 {
+            // The match types overlap, e.g. prefix includes the exact URL
+            captures.retain(|capture| self.wayback_urls.insert(options.capture_url(capture)));
+        }
 
-    let tasks: Vec<_> = capture_urls
-        .into_iter()
-        .enumerate()
-        .map(|(i, url)| {
-            let semaphore = Arc::clone(&semaphore);
-            let client = Arc::clone(&client);
-            let output_dir = Arc::clone(&output_dir);
-            let failed_urls = Arc::clone(&failed_urls);
-
-            tokio::spawn(async move {
-                let _permit = semaphore.acquire().await.unwrap();
-                println!("Downloading {}/{}: {}", i + 1, total, url);
-
-                match download_html(&client, &url, &output_dir).await {
-                    Ok(filename) => {
-                        println!("Successfully downloaded: {}", filename);
+        if !self.seen.is_empty() {
+            captures.retain(|capture| !self.seen.contains(&options.capture_url(capture)));
+        }
+
+        let include = &context.include;
+        let exclude = &context.exclude;
+        if !include.is_empty() || !exclude.is_empty() {
+            let before = captures.len();
+            captures.retain(|capture| {
+                let url = &capture.original_url;
+                (include.is_empty() || include.iter().any(|pattern| pattern.is_match(url)))
+                    && !exclude.iter().any(|pattern| pattern.is_match(url))
+            });
+            self.by_pattern += before - captures.len();
+        }
+
+        if self.deduplicate {
+            // Pages list the captures of every URL oldest first, so this
+            // keeps the earliest one of each content
+            let before = captures.len();
+            captures.retain(|capture| {
+                capture
+                    .digest
+                    .as_ref()
+                    .is_none_or(|digest| self.digests.insert(digest.clone()))
+            });
+            self.duplicates += before - captures.len();
+        }
+
+        if let Some(max_file_size) = context.args.query.max_file_size {
+            // Rows without a usable length are kept, their size is unknown
+            let before = captures.len();
+            captures.retain(|capture| capture.length.is_none_or(|length| length <= max_file_size));
+            self.too_large += before - captures.len();
+        }
+
+        if let Some(index) = self.index {
+            let before = captures.len();
+            captures.retain(|capture| {
+                !capture
+                    .digest
+                    .as_deref()
+                    .is_some_and(|digest| index.contains_digest(digest))
+            });
+            self.indexed += before - captures.len();
+        }
+
+        if !context.args.query.include_robot_excluded {
+            // robotflags is always fetched for this, see cdx_fields
+            let before = captures.len();
+            captures.retain(|capture| !capture.robot_excluded());
+            self.robot_excluded += before - captures.len();
+        }
+
+        if context.args.query.respect_robots {
+            let before = captures.len();
+            captures.retain(|capture| {
+                let Some((host, path)) = robots_location(&capture.original_url) else {
+                    return true;
+                };
+                self.robots
+                    .get(&host)
+                    .and_then(Option::as_ref)
+                    .is_none_or(|robots| robots.is_allowed(&path))
+            });
+            self.disallowed += before - captures.len();
+        }
+
+        if let Some((_, digits)) = context.args.query.period() {
+            // Like --deduplicate this relies on pages listing every URL
+            // oldest first
+            let before = captures.len();
+            captures.retain(|capture| {
+                let period = capture
+                    .timestamp
+                    .get(..digits)
+                    .unwrap_or(&capture.timestamp);
+                self.periods
+                    .insert((capture.original_url.clone(), period.to_string()))
+            });
+            self.same_period += before - captures.len();
+        }
+
+        if context.args.query.oldest_only {
+            // Pages list the captures of every URL oldest first as well
+            let before = captures.len();
+            captures.retain(|capture| self.oldest.insert(capture.original_url.clone()));
+            self.newer += before - captures.len();
+        }
+
+        if context.args.query.language_pre_filter
+            && let Some(language) = &context.args.query.language
+        {
+            // Most URLs don't say, those are left to the check after downloading
+            let before = captures.len();
+            captures.retain(|capture| {
+                language_from_url(&capture.original_url)
+                    .is_none_or(|detected| language_matches(&detected, language))
+            });
+            self.other_language += before - captures.len();
+        }
+
+        captures
+    }
+
+    /// Captures dropped because their content was downloaded before, in this
+    /// batch or an earlier run.
+    fn skipped(&self) -> usize {
+        self.duplicates + self.indexed
+    }
+
+    /// Logs how many captures each of the enabled filters dropped.
+    fn log_summary(&self) {
+        let context = self.context;
+        if !context.include.is_empty() || !context.exclude.is_empty() {
+            tracing::info!(
+                "Filtered out {} captures by --include/--exclude.",
+                self.by_pattern
+            );
+        }
+        if context.args.query.deduplicate {
+            tracing::info!(
+                "Removed {} captures with duplicate content.",
+                self.duplicates
+            );
+        }
+        if let Some(max_file_size) = context.args.query.max_file_size {
+            tracing::info!(
+                "Skipped {} captures larger than {} bytes.",
+                self.too_large,
+                max_file_size
+            );
+        }
+        if self.index.is_some() {
+            tracing::info!("Skipped {} captures already in the index.", self.indexed);
+        }
+        if context.args.query.respect_robots {
+            tracing::info!(
+                "Skipped {} captures disallowed by robots.txt.",
+                self.disallowed
+            );
+        }
+        // On by default, so only worth a line when it skipped something
+        if self.robot_excluded > 0 {
+            tracing::info!(
+                "Skipped {} captures excluded by robots.txt at crawl time, use \
+                 --include-robot-excluded to download them.",
+                self.robot_excluded
+            );
+        }
+        if let Some((period, _)) = context.args.query.period() {
+            tracing::info!(
+                "Skipped {} captures of a URL with an earlier one the same {}.",
+                self.same_period,
+                period
+            );
+        }
+        if context.args.query.dedup_url_latest
+            || (context.args.query.newest_only && context.cdx_limit.is_none())
+        {
+            tracing::info!("Skipped {} older captures of the same URL.", self.older);
+        }
+        if context.args.query.oldest_only {
+            tracing::info!("Skipped {} newer captures of the same URL.", self.newer);
+        }
+        if context.args.query.dedup_by_day {
+            tracing::info!(
+                "Skipped {} earlier captures of a URL the same day.",
+                self.same_day
+            );
+        }
+        if context.args.query.language_pre_filter {
+            tracing::info!(
+                "Skipped {} captures whose URL points to another language.",
+                self.other_language
+            );
+        }
+    }
+}
+
+/// The host robots.txt rules of `url` come from, with its port, and the
+/// path and query they are matched against.
+fn robots_location(url: &str) -> Option<(String, String)> {
+    let url = Url::parse(url)
+        .or_else(|_| Url::parse(&format!("http://{}", url)))
+        .ok()?;
+    let mut host = url.host_str()?.to_ascii_lowercase();
+    if let Some(port) = url.port() {
+        host.push_str(&format!(":{}", port));
+    }
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    Some((host, path))
+}
+
+/// Downloads captures in the background as they are queued, and writes the
+/// reports of the batch once all of them are done.
+struct Downloader<'a> {
+    context: &'a RunContext,
+    /// Captures queued so far.
+    total: usize,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+    use_index: bool,
+    semaphore: Arc<Semaphore>,
+    /// Where tasks wait for [`share_permits`] to give them a permit with
+    /// `--parallel-snapshots`, instead of taking one from `semaphore`.
+    turns: Option<mpsc::UnboundedSender<Turn>>,
+    /// Permits `--concurrency-auto` takes back as downloads finish.
+    permit_debt: Option<Arc<PermitDebt>>,
+    /// Tells the `--concurrency-auto` controller how long every download
+    /// took.
+    finished: Option<mpsc::UnboundedSender<Duration>>,
+    failed: Arc<Mutex<Vec<CdxCapture>>>,
+    pending: Arc<Mutex<Vec<CdxCapture>>>,
+    metadata: Arc<Mutex<Vec<MetadataRecord>>>,
+    downloaded: Arc<Mutex<Vec<String>>>,
+    indexed: Arc<Mutex<Vec<IndexEntry>>>,
+    warc_file: Option<Arc<Mutex<File>>>,
+    zip: Option<Arc<Mutex<ZipWriter>>>,
+    /// Archive captures are streamed into with `--output-tar`.
+    tar: Option<Arc<Mutex<TarWriter>>>,
+    zip_checksums: Arc<Mutex<String>>,
+    mirror: Option<Arc<Mirror>>,
+    /// Where `--format ndjson` writes a line for every finished download.
+    ndjson: Option<Arc<Mutex<tokio::io::Stdout>>>,
+    /// Lets one `--on-success` command run at a time.
+    hook_semaphore: Arc<Semaphore>,
+    started: Instant,
+    bytes_written: Arc<AtomicU64>,
+    skipped_existing: Arc<AtomicUsize>,
+    /// Pages deleted for being in another language than `--language`.
+    other_language: Arc<AtomicUsize>,
+}
+
+impl<'a> Downloader<'a> {
+    /// Creates the output directory and archive files and shows the
+    /// progress bars.
+    async fn new(context: &'a RunContext, use_index: bool, mirror: Option<Mirror>) -> Result<Self> {
+        let args = &context.args;
+        let output_dir = &context.output_dir;
+        fs::create_dir_all(output_dir)
+            .into_diagnostic()
+            .context(format!("Failed to create output directory: {}", output_dir))?;
+
+        let zip = match &context.zip_path {
+            Some(path) => Some(Arc::new(Mutex::new(ZipWriter::create(path)?))),
+            None => None,
+        };
+        let tar = match args.output_tar.as_deref() {
+            Some("-") => Some(Arc::new(Mutex::new(TarWriter::stdout()))),
+            Some(path) => Some(Arc::new(Mutex::new(TarWriter::create(Path::new(path))?))),
+            None => None,
+        };
+
+        let warc_file = match args.format {
+            OutputFormat::Html | OutputFormat::Zip | OutputFormat::Ndjson => None,
+            OutputFormat::Warc => {
+                let path = context.options.output_dir.join("archive.warc");
+                let file = File::options()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await
+                    .into_diagnostic()
+                    .context(format!("Failed to open WARC file: {}", path.display()))?;
+                Some(Arc::new(Mutex::new(file)))
+            }
+        };
+
+        let concurrency = if args.concurrency_auto {
+            INITIAL_CONCURRENCY
+        } else {
+            args.concurrency
+        };
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let turns = args.parallel_snapshots.then(|| {
+            let (sender, turns) = mpsc::unbounded_channel();
+            tokio::spawn(share_permits(Arc::clone(&semaphore), turns));
+            sender
+        });
+        let permit_debt = args
+            .concurrency_auto
+            .then(|| Arc::new(PermitDebt::default()));
+        let finished = permit_debt.as_ref().map(|debt| {
+            let (sender, finished) = mpsc::unbounded_channel();
+            let controller = ConcurrencyController::new(
+                Arc::clone(&semaphore),
+                Arc::clone(debt),
+                Arc::clone(&context.options.timeouts),
+            );
+            tokio::spawn(controller.run(finished));
+            sender
+        });
+
+        *context.archives.tar.lock().unwrap() =
+            tar.as_ref().map(Arc::downgrade).unwrap_or_default();
+        *context.archives.zip.lock().unwrap() =
+            zip.as_ref().map(Arc::downgrade).unwrap_or_default();
+        *context.archives.warc_file.lock().unwrap() =
+            warc_file.as_ref().map(Arc::downgrade).unwrap_or_default();
+
+        context.progress.begin(0);
+
+        Ok(Self {
+            context,
+            total: 0,
+            tasks: Vec::new(),
+            use_index,
+            semaphore,
+            turns,
+            permit_debt,
+            finished,
+            failed: Arc::new(Mutex::new(Vec::new())),
+            pending: Arc::new(Mutex::new(Vec::new())),
+            metadata: Arc::new(Mutex::new(Vec::new())),
+            downloaded: Arc::new(Mutex::new(Vec::new())),
+            indexed: Arc::new(Mutex::new(Vec::new())),
+            warc_file,
+            zip,
+            tar,
+            zip_checksums: Arc::new(Mutex::new(args.checksum_algorithm.manifest_header())),
+            mirror: mirror.map(Arc::new),
+            ndjson: matches!(args.format, OutputFormat::Ndjson)
+                .then(|| Arc::new(Mutex::new(tokio::io::stdout()))),
+            hook_semaphore: Arc::new(Semaphore::new(1)),
+            started: Instant::now(),
+            bytes_written: Arc::new(AtomicU64::new(0)),
+            skipped_existing: Arc::new(AtomicUsize::new(0)),
+            other_language: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Starts downloading `captures` as soon as download permits free up.
+    /// `source` names the URL each was queried for, which takes turns with
+    /// the others under `--parallel-snapshots`.
+    fn queue(&mut self, captures: Vec<CdxCapture>, source: impl Fn(&CdxCapture) -> Arc<str>) {
+        let context = self.context;
+        let args = &context.args;
+        self.total += captures.len();
+        let total = self.total;
+        context.progress.set_total(total);
+
+        let use_index = self.use_index;
+        let skip_existing = args.skip_existing;
+        let checksum = args.checksum.then_some(args.checksum_algorithm);
+        // A --watch run finishes its current batch when told to stop
+        let drain_on_interrupt = args.watch.is_none();
+
+        for capture in captures {
+            let i = self.tasks.len();
+            let semaphore = Arc::clone(&self.semaphore);
+            let turns = self.turns.clone();
+            let permit_debt = self.permit_debt.clone();
+            let finished = self.finished.clone();
+            let source = source(&capture);
+            let client = Arc::clone(&context.client);
+            let options = Arc::clone(&context.options);
+            let failed = Arc::clone(&self.failed);
+            let pending = Arc::clone(&self.pending);
+            let interrupted = Arc::clone(&context.interrupted);
+            let metadata = Arc::clone(&self.metadata);
+            let downloaded = Arc::clone(&self.downloaded);
+            let indexed = Arc::clone(&self.indexed);
+            let progress = Arc::clone(&context.progress);
+            let warc_file = self.warc_file.clone();
+            let ndjson = self.ndjson.clone();
+            let on_success = args.on_success.clone();
+            let hook_semaphore = Arc::clone(&self.hook_semaphore);
+            let zip = self.zip.clone();
+            let tar = self.tar.clone();
+            let zip_checksums = Arc::clone(&self.zip_checksums);
+            let mirror = self.mirror.clone();
+            let bytes_written = Arc::clone(&self.bytes_written);
+            let skipped_existing = Arc::clone(&self.skipped_existing);
+            let language = args.query.language.clone();
+            let other_language = Arc::clone(&self.other_language);
+
+            self.tasks.push(tokio::spawn(async move {
+                let permit = match turns {
+                    Some(turns) => {
+                        let (sender, turn) = oneshot::channel();
+                        turns.send((source, sender)).unwrap();
+                        turn.await.unwrap()
+                    }
+                    None => semaphore.acquire_owned().await.unwrap(),
+                };
+                let _permit = DownloadPermit::new(permit, permit_debt);
+                // After a shutdown signal running downloads finish, queued
+                // ones are only recorded as pending
+                if drain_on_interrupt && interrupted.load(Ordering::SeqCst) {
+                    pending.lock().await.push(capture);
+                    return;
+                }
+                let url = options.capture_url(&capture);
+                let slot = progress.start(format!("Downloading {}/{}: {}", i + 1, total, url));
+
+                let existing =
+                    (skip_existing && warc_file.is_none() && zip.is_none() && tar.is_none())
+                        .then(|| capture_filename(&capture, &options))
+                        .filter(|filename| options.output_dir.join(filename).exists());
+
+                let skipped = existing.is_some();
+                let started = Instant::now();
+                // Along with the file name, the number of bytes written for it
+                let download = async {
+                    if let Some(filename) = existing {
+                        Ok((filename, 0))
+                    } else if let Some(zip) = &zip {
+                        match download_into_zip(&client, &capture, zip, &options).await {
+                            Ok(fetched) => {
+                                if let Some(algorithm) = checksum {
+                                    // Captures never touch the disk, so hash them now
+                                    let hash = hash_hex(&fetched.body, algorithm);
+                                    let line = format!("{}  {}\n", hash, fetched.filename);
+                                    zip_checksums.lock().await.push_str(&line);
+                                }
+                                let size = fetched.body.len() as u64;
+                                Ok((fetched.filename, size))
+                            }
+                            Err(e) => Err(e),
+                        }
+                    } else if let Some(tar) = &tar {
+                        download_into_tar(&client, &capture, tar, &options)
+                            .await
+                            .map(|fetched| (fetched.filename, fetched.body.len() as u64))
+                    } else if let Some(warc_file) = &warc_file {
+                        download_as_warc_record(&client, &capture, warc_file, &options)
+                            .await
+                            .map(|size| ("archive.warc".to_string(), size))
+                    } else if let Some(mirror) = &mirror {
+                        mirror_capture(&client, &capture, mirror, &options).await
+                    } else {
+                        download_capture(&client, &capture, &options).await
+                    }
+                };
+                #[cfg(feature = "opentelemetry")]
+                let span = tracing::info_span!(
+                    "download",
+                    url = %capture.original_url,
+                    timestamp = %capture.timestamp,
+                    status_code = tracing::field::Empty,
+                    bytes = tracing::field::Empty,
+                    error = tracing::field::Empty,
+                );
+                #[cfg(feature = "opentelemetry")]
+                let download = tracing::Instrument::instrument(download, span.clone());
+                let result = download.await;
+                if let Some(finished) = &finished
+                    && !skipped
+                {
+                    let _ = finished.send(started.elapsed());
+                }
+                #[cfg(feature = "opentelemetry")]
+                match &result {
+                    Ok((_, size)) => span.record("bytes", size),
+                    Err(e) => span.record("error", tracing::field::display(e)),
+                };
+
+                // The language of a page is only known once it is saved
+                if let (Ok((filename, _)), Some(language)) = (&result, &language)
+                    && !skipped
+                    && let Some(detected) =
+                        remove_other_language(&options, filename, language).await
+                {
+                    other_language.fetch_add(1, Ordering::Relaxed);
+                    progress
+                        .set_message(slot, format!("Deleted page in {}: {}", detected, filename));
+                    progress.finish(slot);
+                    return;
+                }
+
+                let download_duration_ms = started.elapsed().as_millis() as u64;
+                match result {
+                    Ok((filename, size)) => {
+                        bytes_written.fetch_add(size, Ordering::Relaxed);
+                        if skipped {
+                            skipped_existing.fetch_add(1, Ordering::Relaxed);
+                            progress
+                                .set_message(slot, format!("Skipping existing file: {}", filename));
+                        } else {
+                            progress.set_message(
+                                slot,
+                                format!("Successfully downloaded: {}", filename),
+                            );
+                            downloaded.lock().await.push(filename.clone());
+                            if use_index {
+                                let local_path = match &options.s3 {
+                                    Some(s3) => PathBuf::from(format!(
+                                        "s3://{}/{}",
+                                        s3.bucket,
+                                        s3.key(&filename)
+                                    )),
+                                    None => options.output_dir.join(&filename),
+                                };
+                                indexed.lock().await.push(IndexEntry {
+                                    wayback_url: url.clone(),
+                                    original_url: capture.original_url.clone(),
+                                    timestamp: capture.timestamp.clone(),
+                                    digest: capture.digest.clone(),
+                                    local_path: Some(
+                                        std::path::absolute(&local_path).unwrap_or(local_path),
+                                    ),
+                                    downloaded_at: now_timestamp(),
+                                    status: IndexStatus::Success,
+                                    error: None,
+                                });
+                            }
+                        }
+                        // The WARC file holds every capture, its size says nothing
+                        // about this one, and uploads aren't on disk at all
+                        let file_size_bytes = if skipped {
+                            tokio::fs::metadata(options.output_dir.join(&filename))
+                                .await
+                                .ok()
+                                .map(|metadata| metadata.len())
+                        } else if warc_file.is_none() && options.s3.is_none() {
+                            Some(size)
+                        } else {
+                            None
+                        };
+                        let record = MetadataRecord {
+                            capture,
+                            wayback_url: url,
+                            status: "success",
+                            filename: Some(filename),
+                            file_size_bytes,
+                            download_duration_ms,
+                            error: None,
+                        };
+                        if let Some(ndjson) = &ndjson {
+                            write_download_event(ndjson, &record).await;
+                        }
+                        if let Some(command) = &on_success
+                            && !skipped
+                            && let Some(filename) = &record.filename
+                        {
+                            let file = options.output_dir.join(filename);
+                            let file = std::path::absolute(&file).unwrap_or(file);
+                            let _permit = hook_semaphore.acquire().await.unwrap();
+                            run_success_hook(command, &file, &record.capture, ndjson.is_some())
+                                .await;
+                        }
+                        metadata.lock().await.push(record);
                     }
                     Err(e) => {
-                        println!("Failed to download {}: {}", url, e);
-                        failed_urls.lock().await.push(url);
+                        tracing::error!("Failed to download {}: {}", url, e);
+                        failed.lock().await.push(capture.clone());
+                        if use_index {
+                            indexed.lock().await.push(IndexEntry {
+                                wayback_url: url.clone(),
+                                original_url: capture.original_url.clone(),
+                                timestamp: capture.timestamp.clone(),
+                                digest: capture.digest.clone(),
+                                local_path: None,
+                                downloaded_at: now_timestamp(),
+                                status: IndexStatus::Failed,
+                                error: Some(e.to_string()),
+                            });
+                        }
+                        let record = MetadataRecord {
+                            capture,
+                            wayback_url: url,
+                            status: "failed",
+                            filename: None,
+                            file_size_bytes: None,
+                            download_duration_ms,
+                            error: Some(e.to_string()),
+                        };
+                        if let Some(ndjson) = &ndjson {
+                            write_download_event(ndjson, &record).await;
+                        }
+                        metadata.lock().await.push(record);
                     }
                 }
-            })
-        })
-        .collect();
 
-    for task in tasks {
-        let _ = task.await;
+                progress.finish(slot);
+            }));
+        }
     }
 
-    let failed_urls = failed_urls.lock().await;
-    if !failed_urls.is_empty() {
-        let log_file = PathBuf::from(&*output_dir).join("failed_urls.txt");
-        let failed_content = failed_urls.join("\n");
-        fs::write(&log_file, failed_content).into_diagnostic()?;
-        println!(
-            "Some URLs failed to download. Check {} for details.",
-            log_file.display()
+    /// Waits for every queued download and writes the metadata, reports,
+    /// index entries and retry script of the batch. `deduplicated` captures
+    /// were left out before they were queued and count as skipped.
+    async fn finish(
+        self,
+        index: &mut Option<DownloadIndex>,
+        seen: &mut HashSet<String>,
+        deduplicated: usize,
+    ) -> Result<BatchOutcome> {
+        let Downloader {
+            context,
+            total,
+            tasks,
+            failed,
+            pending,
+            metadata,
+            downloaded,
+            indexed,
+            zip,
+            tar,
+            zip_checksums,
+            started,
+            bytes_written,
+            skipped_existing,
+            other_language,
+            ..
+        } = self;
+        let args = &context.args;
+        let options = &context.options;
+        let progress = &context.progress;
+        let output_dir = &context.output_dir;
+        let checksum = args.checksum;
+
+        for task in tasks {
+            let _ = task.await;
+        }
+        progress.clear_bars();
+
+        // Ended first, so a failure below doesn't leave it unfinished
+        if let Some(tar) = tar {
+            let tar = Arc::try_unwrap(tar)
+                .map_err(|_| miette!("Downloads were still running"))?
+                .into_inner();
+            let name = tar.finish()?;
+            tracing::info!("Tar archive written to {}", name);
+        }
+
+        let metadata = metadata.lock().await;
+        let mut metadata_content = String::new();
+        for record in metadata.iter() {
+            metadata_content.push_str(&serde_json::to_string(record).into_diagnostic()?);
+            metadata_content.push('\n');
+        }
+        let metadata_file = options.output_dir.join("metadata.ndjson");
+        if let Some(zip) = &zip {
+            let mut zip = zip.lock().await;
+            zip.add_file(
+                "metadata.ndjson",
+                metadata_content.as_bytes(),
+                &now_timestamp(),
+            )?;
+            if checksum {
+                let manifest = zip_checksums.lock().await;
+                let name = args.checksum_algorithm.manifest_filename();
+                zip.add_file(&name, manifest.as_bytes(), &now_timestamp())?;
+            }
+        } else if args.watch.is_some() {
+            // Every batch of a --watch run adds to the records of the earlier ones
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&metadata_file)
+                .into_diagnostic()?;
+            file.write_all(metadata_content.as_bytes())
+                .into_diagnostic()?;
+        } else {
+            fs::write(&metadata_file, &metadata_content).into_diagnostic()?;
+        }
+
+        if args.generate_index || args.generate_readme || args.generate_sitemap {
+            // A --watch run lists the captures of every batch so far
+            let records = if args.watch.is_some() {
+                fs::read_to_string(&metadata_file).into_diagnostic()?
+            } else {
+                metadata_content
+            };
+            let files = archived_files(&records);
+            if args.generate_index {
+                let page = render_html_index(&files);
+                if let Some(zip) = &zip {
+                    zip.lock()
+                        .await
+                        .add_file("index.html", page.as_bytes(), &now_timestamp())?;
+                } else {
+                    let path = options.output_dir.join("index.html");
+                    fs::write(&path, page)
+                        .into_diagnostic()
+                        .context(format!("Failed to write {}", path.display()))?;
+                    tracing::info!("Index written to {}", path.display());
+                }
+            }
+            if args.generate_readme {
+                let summary = ArchiveSummary {
+                    urls: context.urls.clone(),
+                    match_types: context.match_types.clone(),
+                    filters: selection_options(args),
+                    created: now_timestamp(),
+                    size: format_bytes(files.iter().filter_map(|file| file.size_bytes).sum()),
+                };
+                let readme = render_readme(&summary, &files);
+                if let Some(zip) = &zip {
+                    zip.lock()
+                        .await
+                        .add_file("README.md", readme.as_bytes(), &now_timestamp())?;
+                } else {
+                    let path = options.output_dir.join("README.md");
+                    fs::write(&path, readme)
+                        .into_diagnostic()
+                        .context(format!("Failed to write {}", path.display()))?;
+                    tracing::info!("README written to {}", path.display());
+                }
+            }
+            if args.generate_sitemap {
+                let base_url = match &args.sitemap_base_url {
+                    Some(base) => parse_sitemap_base_url(base)?,
+                    None => {
+                        let dir = fs::canonicalize(&options.output_dir).into_diagnostic()?;
+                        Url::from_directory_path(&dir).map_err(|_| {
+                            miette!("Can't turn {} into a file:// URL", dir.display())
+                        })?
+                    }
+                };
+                let sitemap = render_sitemap(&files, &base_url);
+                let pages = sitemap.matches("<url>").count();
+                if pages > MAX_SITEMAP_URLS {
+                    tracing::warn!(
+                        "The sitemap lists {} pages, search engines only read the first {}",
+                        pages,
+                        MAX_SITEMAP_URLS
+                    );
+                }
+                let path = options.output_dir.join("sitemap.xml");
+                fs::write(&path, sitemap)
+                    .into_diagnostic()
+                    .context(format!("Failed to write {}", path.display()))?;
+                tracing::info!("Sitemap written to {}", path.display());
+            }
+        }
+
+        if let Some(links) = &options.discovered_links {
+            // Links are collected over the whole run, so a --watch batch
+            // rewrites the list with its own added
+            let list = links.to_url_list();
+            if let Some(zip) = &zip {
+                zip.lock().await.add_file(
+                    "discovered_links.txt",
+                    list.as_bytes(),
+                    &now_timestamp(),
+                )?;
+            } else {
+                let path = options.output_dir.join("discovered_links.txt");
+                fs::write(&path, list)
+                    .into_diagnostic()
+                    .context(format!("Failed to write {}", path.display()))?;
+                tracing::info!("Discovered links written to {}", path.display());
+            }
+        }
+
+        seen.extend(
+            metadata
+                .iter()
+                .filter(|record| record.status == "success")
+                .map(|record| record.wayback_url.clone()),
         );
+
+        if let Some(csv_path) = &args.output_csv {
+            write_csv_report(csv_path, &metadata)?;
+            tracing::info!("Download report written to {}", csv_path.display());
+        }
+
+        if let Some(index) = index {
+            index.append(&indexed.lock().await)?;
+        }
+
+        if let Some(zip) = zip {
+            let zip = Arc::try_unwrap(zip)
+                .map_err(|_| miette!("Downloads were still running"))?
+                .into_inner();
+            let path = zip.finish()?;
+            tracing::info!("Archive written to {}", path.display());
+        } else if args.checksum {
+            let mut downloaded = downloaded.lock().await.clone();
+            downloaded.sort();
+            downloaded.dedup();
+            let comment = options
+                .gzip
+                .then_some("Files are gzip compressed, the hashes are of the compressed .gz files");
+            let manifest = write_checksum_manifest(
+                &options.output_dir,
+                &downloaded,
+                args.checksum_algorithm,
+                comment,
+            )
+            .await?;
+            tracing::info!("Checksums written to {}", manifest.display());
+        }
+
+        let failed = failed.lock().await;
+        let pending = pending.lock().await;
+        let mut not_downloaded = failed.clone();
+        not_downloaded.extend(pending.iter().cloned());
+        if !not_downloaded.is_empty() {
+            let log_file = options.output_dir.join("failed_urls.txt");
+            let failed_urls: Vec<_> = not_downloaded
+                .iter()
+                .map(|capture| options.capture_url(capture))
+                .collect();
+            fs::write(&log_file, failed_urls.join("\n")).into_diagnostic()?;
+            let script = write_retry_script(
+                output_dir,
+                context.zip_path.as_deref(),
+                args,
+                &not_downloaded,
+            )?;
+            tracing::warn!(
+                "Some URLs were not downloaded. Check {} for details or run {} to retry them.",
+                log_file.display(),
+                script.display()
+            );
+        }
+
+        let bytes = bytes_written.load(Ordering::Relaxed);
+        let other_language = other_language.load(Ordering::Relaxed);
+        let skipped = skipped_existing.load(Ordering::Relaxed) + other_language;
+        if pending.is_empty() {
+            let elapsed = started.elapsed();
+            if let Some(language) = &args.query.language {
+                tracing::info!(
+                    "Deleted {} pages in another language than {}.",
+                    other_language,
+                    language
+                );
+            }
+            tracing::info!(
+                "Download completed: {} downloaded, {} skipped, {} failed, {} in {:.1}s ({}/s), \
+                 saved to {}",
+                total - failed.len() - skipped,
+                skipped + deduplicated,
+                failed.len(),
+                format_bytes(bytes),
+                elapsed.as_secs_f64(),
+                format_bytes((bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64),
+                output_dir
+            );
+        }
+
+        Ok(BatchOutcome {
+            total,
+            failed: failed.len(),
+            pending: pending.len(),
+            downloaded: total - failed.len() - pending.len() - skipped,
+            bytes,
+            cdx_results: 0,
+        })
     }
+}
 
-    println!("Download completed.");
-    Ok(())
+/// A download task waiting for a permit, with the URL its capture was
+/// queried for.
+type Turn = (Arc<str>, oneshot::Sender<OwnedSemaphorePermit>);
+
+/// Hands out the permits of `semaphore` to the tasks waiting on `turns`,
+/// one queried URL after the other, until every sender is gone.
+async fn share_permits(semaphore: Arc<Semaphore>, mut turns: mpsc::UnboundedReceiver<Turn>) {
+    // Queried URLs in the order they first asked, with their waiting tasks
+    let mut waiting: Vec<(Arc<str>, VecDeque<oneshot::Sender<OwnedSemaphorePermit>>)> = Vec::new();
+    let mut next = 0;
+    let add = |waiting: &mut Vec<(Arc<str>, VecDeque<_>)>, (source, task): Turn| match waiting
+        .iter_mut()
+        .find(|(url, _)| *url == source)
+    {
+        Some((_, tasks)) => tasks.push_back(task),
+        None => waiting.push((source, VecDeque::from([task]))),
+    };
+
+    loop {
+        if waiting.iter().all(|(_, tasks)| tasks.is_empty()) {
+            match turns.recv().await {
+                Some(turn) => add(&mut waiting, turn),
+                None => return,
+            }
+        }
+        let permit = Arc::clone(&semaphore).acquire_owned().await.unwrap();
+        // URLs that asked while every permit was taken get a turn too
+        while let Ok(turn) = turns.try_recv() {
+            add(&mut waiting, turn);
+        }
+
+        let Some(i) = (0..waiting.len())
+            .map(|offset| (next + offset) % waiting.len())
+            .find(|&i| !waiting[i].1.is_empty())
+        else {
+            continue;
+        };
+        next = i + 1;
+        if let Some(task) = waiting[i].1.pop_front() {
+            // A task that is gone hands the permit back by dropping it
+            let _ = task.send(permit);
+        }
+    }
 }
 
-async fn get_wayback_cdx_urls(
-    client: &Client,
-    base_url: &str,
-    match_type: &str,
-) -> Result<Vec<String>> {
-    let encoded_url = urlencoding::encode(base_url);
-    let cdx_api_url = format!(
-        "https://web.archive.org/cdx/search/cdx?url={}&matchType={}&filter=statuscode:200&output=json",
-        encoded_url, match_type
-    );
+/// The paths inside `dir` of the successful downloads among the
+/// `metadata.ndjson` lines in `records` with their CDX digests, each listed
+/// once.
+fn verified_files(dir: &Path, records: &str) -> Vec<(PathBuf, Option<String>)> {
+    let mut listed = HashSet::new();
+    let mut files = Vec::new();
+    for line in records.lines() {
+        let Ok(record) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if record["status"] != "success" {
+            continue;
+        }
+        if let Some(filename) = record["filename"].as_str()
+            && listed.insert(filename.to_string())
+        {
+            let digest = record["digest"].as_str().map(str::to_string);
+            files.push((dir.join(filename), digest));
+        }
+    }
+    files
+}
+
+/// The files of the successful downloads among the `metadata.ndjson` lines
+/// in `records`, each listed once.
+fn archived_files(records: &str) -> Vec<ArchivedFile> {
+    let mut listed = HashSet::new();
+    let mut files = Vec::new();
+    for line in records.lines() {
+        let Ok(record) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if record["status"] != "success" {
+            continue;
+        }
+        if let (Some(original_url), Some(timestamp), Some(filename)) = (
+            record["original_url"].as_str(),
+            record["timestamp"].as_str(),
+            record["filename"].as_str(),
+        ) && listed.insert(filename.to_string())
+        {
+            files.push(ArchivedFile {
+                original_url: original_url.to_string(),
+                timestamp: timestamp.to_string(),
+                filename: filename.to_string(),
+                size_bytes: record["file_size_bytes"].as_u64(),
+            });
+        }
+    }
+    files
+}
 
-    let response = client
-        .get(&cdx_api_url)
-        .send()
-        .await
+/// A `--sitemap-base-url` value, which has to be absolute for the locations
+/// in the sitemap to be.
+fn parse_sitemap_base_url(base: &str) -> Result<Url> {
+    let url = Url::parse(base)
         .into_diagnostic()
-        .context("Failed to fetch CDX API")?;
+        .context(format!("Invalid --sitemap-base-url value: {}", base))?;
+    if !matches!(url.scheme(), "http" | "https" | "file") {
+        return Err(miette!(
+            help = "Use a URL like https://mirror.example.org/archive/",
+            "Unsupported --sitemap-base-url scheme: {}",
+            url.scheme()
+        ));
+    }
+    Ok(url)
+}
+
+/// The options of `args` that decide which captures are downloaded, the way
+/// they were given on the command line, e.g. `--from 2020`.
+fn selection_options(args: &Args) -> Vec<String> {
+    selected_options(args)
+        .into_iter()
+        .map(|(option, value)| match value {
+            Some(value) => format!("{} {}", option, value),
+            None => option.to_string(),
+        })
+        .collect()
+}
+
+/// [`selection_options`] as options and their values, if they take one.
+fn selected_options(args: &Args) -> Vec<(&'static str, Option<String>)> {
+    let query = &args.query;
+    let mut options = vec![("--status-codes", Some(query.status_codes.join(",")))];
+    let values = [
+        ("--from", query.from.iter().collect::<Vec<_>>()),
+        ("--after-timestamp", query.after_timestamp.iter().collect()),
+        ("--to", query.to.iter().collect()),
+        ("--filter", query.filters.iter().collect()),
+        ("--mime-type", query.mime_types.iter().collect()),
+        ("--collapse", query.collapse.iter().collect()),
+        ("--include", query.include.iter().collect()),
+        ("--exclude", query.exclude.iter().collect()),
+        ("--closest", query.closest.iter().collect()),
+        ("--language", query.language.iter().collect()),
+    ];
+    for (option, values) in values {
+        options.extend(
+            values
+                .into_iter()
+                .map(|value| (option, Some(value.clone()))),
+        );
+    }
+    if let Some(limit) = query.limit {
+        options.push(("--limit", Some(limit.to_string())));
+    }
+    if let Some(max_file_size) = query.max_file_size {
+        options.push(("--max-file-size", Some(max_file_size.to_string())));
+    }
+    if let Some((period, _)) = query.period() {
+        options.push((
+            match period {
+                "day" => "--first-per-day",
+                "month" => "--first-per-month",
+                _ => "--first-per-year",
+            },
+            None,
+        ));
+    }
+    let flags = [
+        ("--newest-only", query.newest_only),
+        ("--oldest-only", query.oldest_only),
+        ("--deduplicate", query.deduplicate),
+        ("--dedup-url-latest", query.dedup_url_latest),
+        ("--dedup-by-day", query.dedup_by_day),
+        ("--respect-robots", query.respect_robots),
+        ("--include-robot-excluded", query.include_robot_excluded),
+    ];
+    options.extend(
+        flags
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(flag, _)| (flag, None)),
+    );
+    options
+}
+
+/// The options of `args` that decide how and where downloaded captures are
+/// saved, other than `--output` and the archives, as options and their
+/// values.
+fn output_options(args: &Args) -> Vec<(&'static str, Option<String>)> {
+    let mut options = Vec::new();
+    let format = match args.format {
+        OutputFormat::Html => None,
+        OutputFormat::Warc => Some("warc"),
+        OutputFormat::Zip => Some("zip"),
+        OutputFormat::Ndjson => Some("ndjson"),
+    };
+    if let Some(format) = format {
+        options.push(("--format", Some(format.to_string())));
+    }
+    if let Some(template) = &args.output_template {
+        options.push(("--output-template", Some(template.clone())));
+    }
+    if args.timestamp_format != DEFAULT_TIMESTAMP_FORMAT {
+        options.push(("--timestamp-format", Some(args.timestamp_format.clone())));
+    }
+    for mapping in &args.content_type_map {
+        options.push(("--content-type-map", Some(mapping.clone())));
+    }
+    if let Some(index) = &args.index {
+        options.push(("--index", Some(index.display().to_string())));
+    }
+    if let Some(bucket) = &args.s3_bucket {
+        options.push(("--s3-bucket", Some(bucket.clone())));
+        if !args.s3_prefix.is_empty() {
+            options.push(("--s3-prefix", Some(args.s3_prefix.clone())));
+        }
+        if let Some(endpoint) = &args.s3_endpoint {
+            options.push(("--s3-endpoint", Some(endpoint.clone())));
+        }
+    }
+    let flags = [
+        ("--strip-toolbar", args.strip_toolbar),
+        ("--preserve-path", args.preserve_path),
+        ("--no-timestamp-prefix", args.no_timestamp_prefix),
+        ("--mirror", args.mirror),
+        ("--save-headers", args.save_headers),
+        ("--gzip", args.gzip),
+    ];
+    options.extend(
+        flags
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(flag, _)| (flag, None)),
+    );
+    options
+}
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+fn read_url_list(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Writes a script next to `failed_urls.txt` that re-runs noway for exactly
+/// the captures that failed, one exact match query per capture. The options
+/// that selected and shaped the captures are passed on, so a retry saves
+/// them the way the first attempt would have. Archives aren't written over:
+/// a `--format zip` run retries into `<name>_retry.zip` next to `zip_path`,
+/// and `--output-tar` into `<name>_retry.tar`.
+fn write_retry_script(
+    output_dir: &str,
+    zip_path: Option<&Path>,
+    args: &Args,
+    failed: &[CdxCapture],
+) -> Result<PathBuf> {
+    let (name, mut script, newline, quote): (_, _, _, fn(&str) -> String) = if cfg!(windows) {
+        (
+            "retry_failed.bat",
+            "@echo off\r\n".to_string(),
+            "\r\n",
+            batch_quote,
+        )
+    } else {
+        (
+            "retry_failed.sh",
+            "#!/bin/sh\n".to_string(),
+            "\n",
+            shell_quote,
+        )
+    };
+
+    // Captures of another archive have to be looked up there again
+    let mut archive = String::new();
+    if args.cdx_api != DEFAULT_CDX_API {
+        archive.push_str(&format!(" --cdx-api {}", quote(&args.cdx_api)));
+    }
+    if let Some(base) = &args.wayback_base {
+        archive.push_str(&format!(" --wayback-base {}", quote(base)));
+    }
+    if let Some(template) = &args.capture_url_template {
+        archive.push_str(&format!(" --capture-url-template {}", quote(template)));
+    }
 
-    let data: Vec<Vec<Value>> = response
-        .json()
-        .await
+    let output = match zip_path {
+        Some(zip_path) => retry_archive_path(&zip_path.display().to_string(), ".zip"),
+        None => output_dir.to_string(),
+    };
+    // The capture is named by its timestamp, the options that pick among
+    // captures by time would only get in the way
+    let replaced = [
+        "--from",
+        "--to",
+        "--after-timestamp",
+        "--closest",
+        "--limit",
+        "--newest-only",
+        "--oldest-only",
+        "--first-per-day",
+        "--first-per-month",
+        "--first-per-year",
+    ];
+    let mut options = String::new();
+    let selected = selected_options(args)
+        .into_iter()
+        .filter(|(option, _)| !replaced.contains(option));
+    for (option, value) in selected.chain(output_options(args)) {
+        options.push(' ');
+        options.push_str(option);
+        if let Some(value) = value {
+            options.push(' ');
+            options.push_str(&quote(&value));
+        }
+    }
+    match args.output_tar.as_deref() {
+        Some("-") => options.push_str(" --output-tar -"),
+        Some(path) => options.push_str(&format!(
+            " --output-tar {}",
+            quote(&retry_archive_path(path, ".tar"))
+        )),
+        None => {}
+    }
+
+    for capture in failed {
+        script.push_str(&format!(
+            "noway {} --match-type exact --from {} --to {} --output {} --concurrency {}{}{}{}",
+            quote(&capture.original_url),
+            capture.timestamp,
+            capture.timestamp,
+            quote(&output),
+            args.concurrency,
+            options,
+            archive,
+            newline
+        ));
+    }
+
+    let path = PathBuf::from(output_dir).join(name);
+    fs::write(&path, script)
         .into_diagnostic()
-        .context("Failed to parse CDX JSON")?;
+        .context(format!("Failed to write {}", path.display()))?;
 
-    if data.len() <= 1 {
-        println!("No captures found in CDX API response.");
-        return Ok(Vec::new());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).into_diagnostic()?;
     }
 
-    let headers = &data[0];
-    let timestamp_idx = headers
-        .iter()
-        .position(|h| h.as_str() == Some("timestamp"))
-        .context("timestamp field not found")?;
-    let original_url_idx = headers
-        .iter()
-        .position(|h| h.as_str() == Some("original"))
-        .context("original field not found")?;
+    Ok(path)
+}
+
+/// `archive.zip` becomes `archive_retry.zip`, for an archive of the
+/// `extension` given.
+fn retry_archive_path(path: &str, extension: &str) -> String {
+    match path.strip_suffix(extension) {
+        Some(stem) => format!("{}_retry{}", stem, extension),
+        None => format!("{}_retry{}", path, extension),
+    }
+}
 
-    let mut capture_urls = Vec::new();
-    for row in data.iter().skip(1) {
-        let timestamp = row[timestamp_idx].as_str().context("Invalid timestamp")?;
-        let original_url = row[original_url_idx].as_str().context("Invalid URL")?;
-        let capture_url = format!("https://web.archive.org/web/{}/{}", timestamp, original_url);
-        capture_urls.push(capture_url);
+/// Prints the entries of an index file that pass the filters of `export`
+/// to stdout.
+fn run_export_index(export: &ExportIndexArgs) -> Result<()> {
+    let filter_url = export
+        .filter_url
+        .as_deref()
+        .map(Pattern::new)
+        .transpose()
+        .context("Invalid --filter-url pattern")?;
+    if let Some(since) = &export.since {
+        validate_timestamp(since).context("Invalid --since value")?;
     }
 
-    Ok(capture_urls)
+    let entries: Vec<IndexEntry> = read_entries(&export.index)?
+        .into_iter()
+        .filter(|entry| {
+            filter_url
+                .as_ref()
+                .is_none_or(|pattern| pattern.is_match(&entry.original_url))
+        })
+        .filter(|entry| {
+            export
+                .filter_status
+                .is_none_or(|status| entry.status == status)
+        })
+        // Prefixes like 2024 sort before every timestamp they start
+        .filter(|entry| {
+            export
+                .since
+                .as_ref()
+                .is_none_or(|since| entry.downloaded_at.as_str() >= since.as_str())
+        })
+        .collect();
+
+    if export.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&entries).into_diagnostic()?
+        );
+        return Ok(());
+    }
+    let mut csv = String::from(
+        "wayback_url,original_url,timestamp,digest,local_path,downloaded_at,status,error\r\n",
+    );
+    for entry in &entries {
+        let fields = [
+            entry.wayback_url.clone(),
+            entry.original_url.clone(),
+            entry.timestamp.clone(),
+            entry.digest.clone().unwrap_or_default(),
+            entry
+                .local_path
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            entry.downloaded_at.clone(),
+            entry.status.as_str().to_string(),
+            entry.error.clone().unwrap_or_default(),
+        ];
+        let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    print!("{}", csv);
+    Ok(())
 }
 
-async fn download_html(client: &Client, url: &str, output_dir: &str) -> Result<String> {
-    let response = client
-        .get(url)
-        .header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36")
-        .timeout(std::time::Duration::from_secs(15))
-        .send()
-        .await
+/// Writes the `--output-csv` report. The report is written to a temporary
+/// file first and renamed into place, so a partial report never replaces an
+/// older one.
+fn write_csv_report(path: &Path, records: &[MetadataRecord]) -> Result<()> {
+    let mut csv = String::from(
+        "wayback_url,original_url,timestamp,status,filename,file_size_bytes,download_duration_ms\r\n",
+    );
+    for record in records {
+        let fields = [
+            record.wayback_url.clone(),
+            record.capture.original_url.clone(),
+            record.capture.timestamp.clone(),
+            record.status.to_string(),
+            record.filename.clone().unwrap_or_default(),
+            record
+                .file_size_bytes
+                .map(|size| size.to_string())
+                .unwrap_or_default(),
+            record.download_duration_ms.to_string(),
+        ];
+        let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    fs::write(&temp_path, csv)
         .into_diagnostic()
-        .context("Failed to fetch URL")?;
+        .context(format!("Failed to write {}", temp_path.display()))?;
+    fs::rename(&temp_path, path)
+        .into_diagnostic()
+        .context(format!("Failed to write {}", path.display()))
+}
 
-    let html = response
-        .text()
-        .await
+/// Writes the `--metrics-file`, through a temporary file like the CSV
+/// report so collectors reading it never see half of it.
+fn write_metrics_file(path: &Path, metrics: &RunMetrics) -> Result<()> {
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    fs::write(&temp_path, render_metrics(metrics))
         .into_diagnostic()
-        .context("Failed to read response")?;
-
-    let timestamp = url
-        .split("/web/")
-        .nth(1)
-        .and_then(|s| s.split('/').next())
-        .unwrap_or("unknown");
-
-    let parsed_url = Url::parse(url).into_diagnostic()?;
-    let path = parsed_url
-        .path()
-        .replace(['/', ':'], "_");
-    let filename = format!("{}_{}.html", timestamp, path);
-    let filepath = PathBuf::from(output_dir).join(&filename);
-
-    let mut file = File::create(&filepath)
-        .await
+        .context(format!("Failed to write {}", temp_path.display()))?;
+    fs::rename(&temp_path, path)
         .into_diagnostic()
-        .context("Failed to create file")?;
-    file.write_all(html.as_bytes())
-        .await
+        .context(format!("Failed to write {}", path.display()))
+}
+
+/// Quotes a CSV field as RFC 4180 describes when it contains a delimiter,
+/// quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Formats a byte count for people, e.g. `1.5 MB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// A timestamp as a number that sorts and roughly measures distance like
+/// the time it stands for, with missing digits filled in with zeros.
+fn timestamp_number(timestamp: &str) -> u64 {
+    format!("{:0<14}", timestamp).parse().unwrap_or_default()
+}
+
+/// The newest `YYYYMMDDHHMMSS` timestamp in the names of the files in `dir`
+/// and its subdirectories, `None` when there is none or `dir` doesn't exist.
+fn latest_file_timestamp(dir: &Path) -> Result<Option<String>> {
+    let mut latest: Option<String> = None;
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(e)
+                    .into_diagnostic()
+                    .context(format!("Failed to read directory: {}", dir.display()));
+            }
+        };
+        for entry in entries {
+            let entry = entry.into_diagnostic()?;
+            if entry.file_type().into_diagnostic()?.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Downloads that didn't finish don't count
+            if name.ends_with(PART_FILE_SUFFIX) {
+                continue;
+            }
+            // Runs of exactly 14 digits, other numbers in the name aren't
+            // capture times
+            for digits in name.split(|c: char| !c.is_ascii_digit()) {
+                if digits.len() == 14
+                    && is_capture_time(digits)
+                    && latest.as_deref().is_none_or(|latest| digits > latest)
+                {
+                    latest = Some(digits.to_string());
+                }
+            }
+        }
+    }
+    Ok(latest)
+}
+
+/// Whether 14 digits read as a plausible `YYYYMMDDHHMMSS` capture time.
+fn is_capture_time(digits: &str) -> bool {
+    let number = |range: std::ops::Range<usize>| digits[range].parse::<u32>().unwrap_or_default();
+    (1990..=2200).contains(&number(0..4))
+        && (1..=12).contains(&number(4..6))
+        && (1..=31).contains(&number(6..8))
+        && number(8..10) < 24
+        && number(10..12) < 60
+        && number(12..14) < 61
+}
+
+fn write_since_file(path: &PathBuf, timestamp: &str) -> Result<()> {
+    fs::write(path, format!("{}\n", timestamp))
         .into_diagnostic()
-        .context("Failed to write file")?;
+        .context(format!("Failed to write --since file: {}", path.display()))
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn batch_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('%', "%%").replace('"', "\"\""))
+}
+
+/// Quotes a value for `cmd /C`, which unlike batch files leaves `%` alone.
+fn cmd_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_args(args: &[&str]) -> QueryArgs {
+        let args = Args::try_parse_from(["noway", "example.com"].iter().chain(args)).unwrap();
+        args.query
+    }
+
+    fn failed_capture() -> CdxCapture {
+        CdxCapture {
+            url_key: None,
+            timestamp: "20200101000000".to_string(),
+            original_url: "https://example.com/missing".to_string(),
+            mime_type: None,
+            status_code: Some("404".to_string()),
+            digest: None,
+            length: None,
+            robot_flags: None,
+        }
+    }
+
+    /// The line of the retry script of a run with `args`.
+    fn retry_line(name: &str, args: &[&str]) -> String {
+        let dir = std::env::temp_dir().join(format!("noway-retry-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let args = Args::try_parse_from(["noway", "example.com"].iter().chain(args)).unwrap();
+        let zip_path = matches!(args.format, OutputFormat::Zip).then(|| dir.join("archive.zip"));
+        let output_dir = dir.display().to_string();
+        let path = write_retry_script(&output_dir, zip_path.as_deref(), &args, &[failed_capture()])
+            .unwrap();
+        let script = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        script.lines().nth(1).unwrap().replace(&output_dir, "OUT")
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retries_a_capture_with_an_exact_query() {
+        assert_eq!(
+            retry_line("plain", &[]),
+            "noway 'https://example.com/missing' --match-type exact --from 20200101000000 \
+             --to 20200101000000 --output 'OUT' --concurrency 5 --status-codes '200'"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retries_with_the_options_that_selected_and_shaped_the_capture() {
+        let line = retry_line(
+            "options",
+            &[
+                "--status-codes",
+                "200,404",
+                "--from",
+                "2019",
+                "--newest-only",
+                "--filter",
+                "mimetype:text/html",
+                "--format",
+                "ndjson",
+                "--strip-toolbar",
+                "--output-template",
+                "{host}/{timestamp}_{path}.{ext}",
+                "--timestamp-format",
+                "%Y-%m-%d",
+            ],
+        );
+        assert_eq!(
+            line,
+            "noway 'https://example.com/missing' --match-type exact --from 20200101000000 \
+             --to 20200101000000 --output 'OUT' --concurrency 5 --status-codes '200,404' \
+             --filter 'mimetype:text/html' --format 'ndjson' \
+             --output-template '{host}/{timestamp}_{path}.{ext}' --timestamp-format '%Y-%m-%d' \
+             --strip-toolbar"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retries_into_new_archives() {
+        let line = retry_line("zip", &["--format", "zip"]);
+        assert!(
+            line.contains(" --output 'OUT/archive_retry.zip' "),
+            "{}",
+            line
+        );
+        assert!(line.contains(" --format 'zip'"), "{}", line);
+
+        let line = retry_line("tar", &["--output-tar", "captures.tar"]);
+        assert!(
+            line.ends_with(" --output-tar 'captures_retry.tar'"),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn asks_for_robot_flags_with_the_default_columns() {
+        let fields = cdx_fields(&query_args(&[]));
+        assert_eq!(
+            fields,
+            [
+                "urlkey",
+                "timestamp",
+                "original",
+                "mimetype",
+                "statuscode",
+                "digest",
+                "length",
+                "robotflags"
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_robot_flags_to_the_fields_asked_for() {
+        let fields = cdx_fields(&query_args(&["--fields", "timestamp,original"]));
+        assert_eq!(fields, ["timestamp", "original", "robotflags"]);
+
+        let fields = cdx_fields(&query_args(&["--fields", "robotflags,timestamp,original"]));
+        assert_eq!(fields, ["robotflags", "timestamp", "original"]);
+    }
+
+    #[test]
+    fn leaves_out_robot_flags_when_keeping_excluded_captures() {
+        assert!(cdx_fields(&query_args(&["--include-robot-excluded"])).is_empty());
+        let fields = cdx_fields(&query_args(&[
+            "--include-robot-excluded",
+            "--fields",
+            "timestamp,original",
+        ]));
+        assert_eq!(fields, ["timestamp", "original"]);
+    }
+
+    #[test]
+    fn binds_to_the_ipv4_unspecified_address_with_ipv4() {
+        assert_eq!(
+            local_address(true, false),
+            Some("0.0.0.0".parse::<IpAddr>().unwrap())
+        );
+    }
 
-    Ok(filename)
+    #[test]
+    fn binds_to_the_ipv6_unspecified_address_with_ipv6() {
+        assert_eq!(
+            local_address(false, true),
+            Some("::".parse::<IpAddr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn leaves_the_address_to_the_os_without_either() {
+        assert_eq!(local_address(false, false), None);
+    }
 }
@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use crate::cdx::MatchType;
+
+/// Totals of a run written by [`render_metrics`].
+#[derive(Debug, Clone, Default)]
+pub struct RunMetrics {
+    /// URLs the CDX API was queried for.
+    pub urls: Vec<String>,
+    pub match_types: Vec<MatchType>,
+    /// Captures saved, not counting skipped or failed ones.
+    pub downloads: usize,
+    pub failed: usize,
+    /// Size of the files written.
+    pub bytes: u64,
+    pub duration: Duration,
+    /// Rows the CDX API returned, before any filter.
+    pub cdx_results: usize,
+}
+
+impl RunMetrics {
+    /// Adds the totals of `other` to these, keeping the labels.
+    pub fn add(&mut self, other: &RunMetrics) {
+        self.downloads += other.downloads;
+        self.failed += other.failed;
+        self.bytes += other.bytes;
+        self.cdx_results += other.cdx_results;
+    }
+}
+
+/// `metrics` in the OpenMetrics text format, e.g. for the textfile collector
+/// of the Prometheus node exporter. Every sample is labelled with the queried
+/// URLs and match types, comma separated when there are several.
+pub fn render_metrics(metrics: &RunMetrics) -> String {
+    let match_types: Vec<&str> = metrics.match_types.iter().map(|m| m.as_str()).collect();
+    let labels = format!(
+        "{{url=\"{}\",match_type=\"{}\"}}",
+        escape(&metrics.urls.join(",")),
+        escape(&match_types.join(","))
+    );
+
+    let families = [
+        (
+            "noway_downloads",
+            "counter",
+            "Captures downloaded successfully.",
+            metrics.downloads.to_string(),
+        ),
+        (
+            "noway_downloads_failed",
+            "gauge",
+            "Captures that failed to download.",
+            metrics.failed.to_string(),
+        ),
+        (
+            "noway_bytes_downloaded",
+            "counter",
+            "Bytes of the files written.",
+            metrics.bytes.to_string(),
+        ),
+        (
+            "noway_duration_seconds",
+            "gauge",
+            "Seconds the run took.",
+            format!("{:.3}", metrics.duration.as_secs_f64()),
+        ),
+        (
+            "noway_cdx_results",
+            "counter",
+            "Captures the CDX API returned, before filtering.",
+            metrics.cdx_results.to_string(),
+        ),
+    ];
+
+    let mut text = String::new();
+    for (name, kind, help, value) in families {
+        // Samples of a counter family carry the _total suffix
+        let suffix = if kind == "counter" { "_total" } else { "" };
+        text.push_str(&format!("# TYPE {} {}\n", name, kind));
+        text.push_str(&format!("# HELP {} {}\n", name, help));
+        text.push_str(&format!("{}{}{} {}\n", name, suffix, labels, value));
+    }
+    text.push_str("# EOF\n");
+    text
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
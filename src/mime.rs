@@ -0,0 +1,165 @@
+use miette::{Result, miette};
+
+/// File extension (without the dot) for a MIME type, or `None` if it isn't
+/// one noway knows about. Parameters like `; charset=utf-8` are ignored.
+pub fn extension_for_mime(mime: &str) -> Option<&'static str> {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    let extension = match essence.to_ascii_lowercase().as_str() {
+        "text/html" | "application/xhtml+xml" => "html",
+        "text/css" => "css",
+        "application/javascript" | "application/x-javascript" | "text/javascript" => "js",
+        "application/json" => "json",
+        "text/plain" => "txt",
+        "text/xml" | "application/xml" => "xml",
+        "application/rss+xml" => "rss",
+        "application/atom+xml" => "atom",
+        "image/png" => "png",
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/gif" => "gif",
+        "image/svg+xml" => "svg",
+        "image/webp" => "webp",
+        "image/x-icon" | "image/vnd.microsoft.icon" => "ico",
+        "application/pdf" => "pdf",
+        "font/woff" | "application/font-woff" => "woff",
+        "font/woff2" => "woff2",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+/// Extension for a MIME type [`extension_for_mime`] doesn't know: `json` or
+/// `xml` for types with that suffix like `application/ld+json`, `txt` for other
+/// text, `bin` for other images, audio, video, fonts and applications, and
+/// `html` when the type is missing or none of those, like the `unk` of some
+/// CDX rows.
+pub fn fallback_extension(mime: &str) -> &'static str {
+    let essence = mime
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let Some((kind, subtype)) = essence.split_once('/') else {
+        return "html";
+    };
+    if subtype.ends_with("+json") {
+        "json"
+    } else if subtype.ends_with("+xml") {
+        "xml"
+    } else if kind == "text" {
+        "txt"
+    } else if matches!(kind, "image" | "audio" | "video" | "font" | "application") {
+        "bin"
+    } else {
+        "html"
+    }
+}
+
+/// Parses a `--content-type-map` value like `application/x-javascript=js`
+/// into the lowercase MIME type and the extension, without a leading dot.
+pub fn parse_content_type_mapping(mapping: &str) -> Result<(String, String)> {
+    let invalid = || {
+        miette!(
+            help = "Use MIME=EXTENSION, e.g. application/x-javascript=js",
+            "'{}' is not a valid content type mapping",
+            mapping
+        )
+    };
+    let (mime, extension) = mapping.split_once('=').ok_or_else(invalid)?;
+    let mime = mime.trim().to_ascii_lowercase();
+    let extension = extension.trim().trim_start_matches('.');
+    let (kind, subtype) = mime.split_once('/').ok_or_else(invalid)?;
+    if kind.is_empty()
+        || subtype.is_empty()
+        || extension.is_empty()
+        || !extension
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_+".contains(c))
+    {
+        return Err(invalid());
+    }
+    Ok((mime, extension.to_string()))
+}
+
+/// Whether a MIME type is text that can be post-processed as HTML.
+pub fn is_html(mime: &str) -> bool {
+    extension_for_mime(mime) == Some("html")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_known_types() {
+        assert_eq!(extension_for_mime("application/json"), Some("json"));
+        assert_eq!(extension_for_mime("text/plain"), Some("txt"));
+        assert_eq!(extension_for_mime("image/png"), Some("png"));
+        assert_eq!(extension_for_mime("text/html"), Some("html"));
+    }
+
+    #[test]
+    fn ignores_parameters_and_case() {
+        assert_eq!(
+            extension_for_mime("Application/JSON; charset=utf-8"),
+            Some("json")
+        );
+        assert_eq!(
+            extension_for_mime(" text/plain ;charset=us-ascii"),
+            Some("txt")
+        );
+    }
+
+    #[test]
+    fn leaves_unknown_types_to_the_fallback() {
+        assert_eq!(extension_for_mime("application/ld+json"), None);
+        assert_eq!(extension_for_mime("text/csv"), None);
+        assert_eq!(extension_for_mime("unk"), None);
+        assert_eq!(extension_for_mime(""), None);
+    }
+
+    #[test]
+    fn falls_back_on_the_structured_syntax_suffix() {
+        assert_eq!(fallback_extension("application/ld+json"), "json");
+        assert_eq!(
+            fallback_extension("application/problem+json; charset=utf-8"),
+            "json"
+        );
+        assert_eq!(fallback_extension("application/vnd.example+xml"), "xml");
+        assert_eq!(fallback_extension("APPLICATION/SOAP+XML"), "xml");
+    }
+
+    #[test]
+    fn falls_back_on_txt_for_other_text() {
+        assert_eq!(fallback_extension("text/csv"), "txt");
+        assert_eq!(fallback_extension("text/x-markdown; charset=utf-8"), "txt");
+    }
+
+    #[test]
+    fn falls_back_on_bin_for_binary_types() {
+        assert_eq!(fallback_extension("application/octet-stream"), "bin");
+        assert_eq!(fallback_extension("image/avif"), "bin");
+        assert_eq!(fallback_extension("audio/mpeg"), "bin");
+        assert_eq!(fallback_extension("video/mp4"), "bin");
+        assert_eq!(fallback_extension("font/otf"), "bin");
+    }
+
+    #[test]
+    fn falls_back_on_html_without_a_type() {
+        assert_eq!(fallback_extension(""), "html");
+        assert_eq!(fallback_extension("unk"), "html");
+        assert_eq!(fallback_extension("warc/revisit"), "html");
+    }
+
+    #[test]
+    fn parses_content_type_mappings() {
+        assert_eq!(
+            parse_content_type_mapping("Application/X-Javascript=.js").unwrap(),
+            ("application/x-javascript".to_string(), "js".to_string())
+        );
+        assert!(parse_content_type_mapping("application/json").is_err());
+        assert!(parse_content_type_mapping("json=js").is_err());
+        assert!(parse_content_type_mapping("application/json=").is_err());
+        assert!(parse_content_type_mapping("application/json=a/b").is_err());
+    }
+}
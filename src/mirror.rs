@@ -0,0 +1,636 @@
+use miette::Result;
+use reqwest::Client;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OnceCell};
+use url::Url;
+
+use crate::cdx::CdxCapture;
+use crate::download::{DownloadOptions, capture_filename, fetch_capture, write_capture};
+use crate::mime::is_html;
+
+/// A link from an archived page to another archived resource, e.g.
+/// `/web/20230115000000im_/https://example.com/logo.png`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaybackLink {
+    pub timestamp: String,
+    pub original_url: String,
+}
+
+/// Parses the links the Wayback Machine rewrites archived pages to use:
+/// `https://web.archive.org/web/<timestamp>[flags]/<original url>` along
+/// with the protocol relative `//web.archive.org/web/...` and root relative
+/// `/web/...` forms.
+pub fn parse_wayback_link(link: &str) -> Option<WaybackLink> {
+    let link = link.trim();
+    let path = [
+        "https://web.archive.org",
+        "http://web.archive.org",
+        "//web.archive.org",
+        "",
+    ]
+    .iter()
+    .find_map(|prefix| link.strip_prefix(prefix))?;
+    let (stamp, original) = path.strip_prefix("/web/")?.split_once('/')?;
+
+    // Flags like `im_` or `cs_` tell the Wayback Machine how to serve it
+    let digits = stamp.bytes().take_while(u8::is_ascii_digit).count();
+    let flags = &stamp[digits..];
+    let valid_flags = flags.is_empty()
+        || flags
+            .strip_suffix('_')
+            .is_some_and(|flags| flags.bytes().all(|b| b.is_ascii_alphabetic()));
+    if !(1..=14).contains(&digits) || !valid_flags {
+        return None;
+    }
+
+    let original_url = if original.contains("://") {
+        original.to_string()
+    } else {
+        format!("http://{}", original)
+    };
+    Some(WaybackLink {
+        timestamp: stamp[..digits].to_string(),
+        original_url,
+    })
+}
+
+/// Images, scripts, stylesheets and icons `html` loads from the Wayback
+/// Machine, each listed once.
+pub fn find_assets(html: &str) -> Vec<WaybackLink> {
+    let mut assets = Vec::new();
+    for tag in start_tags(html) {
+        let attribute = match tag.name.as_str() {
+            "img" | "script" | "source" | "embed" | "audio" | "video" => "src",
+            "link"
+                if tag.value(html, "rel").is_some_and(|rel| {
+                    let rel = rel.to_ascii_lowercase();
+                    rel.contains("stylesheet") || rel.contains("icon")
+                }) =>
+            {
+                "href"
+            }
+            _ => continue,
+        };
+        if let Some(link) = tag
+            .value(html, attribute)
+            .and_then(|value| parse_wayback_link(&value.replace("&amp;", "&")))
+            && !assets.contains(&link)
+        {
+            assets.push(link);
+        }
+    }
+    assets
+}
+
+/// Absolute URLs of the `<a href>` links in `html`, archived at
+/// `page_url`, that point to the same domain as it, each listed once and
+/// without fragments. Links the Wayback Machine rewrote are turned back into
+/// the original ones, relative links are resolved against `page_url`.
+pub fn extract_links(html: &str, page_url: &str) -> Vec<String> {
+    let Ok(mut page) = Url::parse(page_url) else {
+        return Vec::new();
+    };
+    page.set_fragment(None);
+    let domain = page
+        .host_str()
+        .map(|host| host.trim_start_matches("www.").to_string());
+
+    let mut links = Vec::new();
+    for tag in start_tags(html) {
+        if tag.name != "a" {
+            continue;
+        }
+        let Some(href) = tag
+            .value(html, "href")
+            .map(|href| href.replace("&amp;", "&"))
+        else {
+            continue;
+        };
+        let resolved = match parse_wayback_link(&href) {
+            Some(link) => Url::parse(&link.original_url),
+            None => page.join(href.trim()),
+        };
+        let Ok(mut url) = resolved else {
+            continue;
+        };
+        let same_domain = url
+            .host_str()
+            .map(|host| host.trim_start_matches("www."))
+            .is_some_and(|host| Some(host) == domain.as_deref());
+        if !matches!(url.scheme(), "http" | "https") || !same_domain {
+            continue;
+        }
+        url.set_fragment(None);
+        let link = url.to_string();
+        // Anchors within the page lead back to it
+        if url != page && !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
+
+/// Links collected from every HTML capture downloaded in a run with
+/// `options.discovered_links`, in the order they were found.
+#[derive(Debug, Default)]
+pub struct DiscoveredLinks {
+    state: std::sync::Mutex<(HashSet<String>, Vec<String>)>,
+}
+
+impl DiscoveredLinks {
+    /// Adds the links [`extract_links`] finds in `html` that weren't
+    /// collected yet.
+    pub fn add(&self, html: &str, page_url: &str) {
+        let links = extract_links(html, page_url);
+        let mut state = self.state.lock().unwrap();
+        let (seen, ordered) = &mut *state;
+        for link in links {
+            if seen.insert(link.clone()) {
+                ordered.push(link);
+            }
+        }
+    }
+
+    /// One link per line, the format `--url-file` reads.
+    pub fn to_url_list(&self) -> String {
+        let state = self.state.lock().unwrap();
+        state.1.iter().map(|link| format!("{}\n", link)).collect()
+    }
+}
+
+/// Replaces the Wayback Machine links in the `href` and `src` attributes of
+/// a page saved as `page` (relative to the output directory) with relative
+/// paths to the files `local_path` returns for them. Links it returns
+/// `None` for are left alone.
+pub fn rewrite_links(
+    html: &str,
+    page: &str,
+    local_path: impl Fn(&WaybackLink) -> Option<String>,
+) -> String {
+    let mut rewritten = String::with_capacity(html.len());
+    let mut copied = 0;
+    for tag in start_tags(html) {
+        for (name, range) in &tag.attributes {
+            if name != "href" && name != "src" {
+                continue;
+            }
+            let value = html[range.clone()].replace("&amp;", "&");
+            let Some(link) = parse_wayback_link(&value) else {
+                continue;
+            };
+            let Some(target) = local_path(&link) else {
+                continue;
+            };
+
+            let mut replacement = relative_path(page, &target);
+            if let Some((_, fragment)) = link.original_url.split_once('#') {
+                replacement.push('#');
+                replacement.push_str(fragment);
+            }
+            rewritten.push_str(&html[copied..range.start]);
+            rewritten.push_str(&replacement);
+            copied = range.end;
+        }
+    }
+    rewritten.push_str(&html[copied..]);
+    rewritten
+}
+
+/// Path from the file `from` to the file `to`, both relative to the same
+/// directory and separated by `/`, percent encoded for use in a link.
+fn relative_path(from: &str, to: &str) -> String {
+    let mut from_dir: Vec<&str> = from.split('/').collect();
+    from_dir.pop();
+    let to: Vec<&str> = to.split('/').collect();
+
+    let common = from_dir
+        .iter()
+        .zip(&to)
+        .take_while(|(from, to)| from == to)
+        .count();
+    let mut parts = vec!["..".to_string(); from_dir.len() - common];
+    parts.extend(
+        to[common..]
+            .iter()
+            .map(|segment| urlencoding::encode(segment).into_owned()),
+    );
+    parts.join("/")
+}
+
+/// Identifies the same page across captures and links: the host without
+/// `www.`, the path without a trailing slash and the query.
+fn url_key(url: &str) -> String {
+    let Ok(url) = Url::parse(url) else {
+        return url.to_string();
+    };
+    let host = url.host_str().unwrap_or_default();
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    let path = url.path().trim_end_matches('/');
+    match url.query() {
+        Some(query) => format!("{}{}?{}", host, path, query),
+        None => format!("{}{}", host, path),
+    }
+}
+
+/// An HTML start tag with the byte ranges of its attribute values.
+pub(crate) struct StartTag {
+    pub(crate) name: String,
+    attributes: Vec<(String, Range<usize>)>,
+}
+
+impl StartTag {
+    pub(crate) fn value<'a>(&self, html: &'a str, name: &str) -> Option<&'a str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, range)| &html[range.clone()])
+    }
+}
+
+/// The start tags of `html` in order, with lowercase tag and attribute
+/// names. Comments and the contents of `<script>` and `<style>` are skipped.
+pub(crate) fn start_tags(html: &str) -> Vec<StartTag> {
+    let bytes = html.as_bytes();
+    let len = bytes.len();
+    let skip_whitespace = |pos: &mut usize| {
+        while *pos < len && bytes[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        }
+    };
+
+    let mut tags = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = html[pos..].find('<') {
+        pos += offset + 1;
+        if html[pos..].starts_with("!--") {
+            pos = html[pos..].find("-->").map_or(len, |end| pos + end + 3);
+            continue;
+        }
+
+        let name_len = bytes[pos..]
+            .iter()
+            .take_while(|b| b.is_ascii_alphanumeric())
+            .count();
+        if name_len == 0 {
+            // End tags, doctypes and a stray `<` in text
+            continue;
+        }
+        let name = html[pos..pos + name_len].to_ascii_lowercase();
+        pos += name_len;
+
+        let mut attributes = Vec::new();
+        loop {
+            while pos < len && (bytes[pos].is_ascii_whitespace() || bytes[pos] == b'/') {
+                pos += 1;
+            }
+            if pos >= len || bytes[pos] == b'>' {
+                break;
+            }
+
+            let name_start = pos;
+            while pos < len
+                && !bytes[pos].is_ascii_whitespace()
+                && !matches!(bytes[pos], b'=' | b'>' | b'/')
+            {
+                pos += 1;
+            }
+            let attribute = html[name_start..pos].to_ascii_lowercase();
+            skip_whitespace(&mut pos);
+            if pos >= len || bytes[pos] != b'=' {
+                continue;
+            }
+
+            pos += 1;
+            skip_whitespace(&mut pos);
+            let value = match bytes.get(pos) {
+                Some(&quote @ (b'"' | b'\'')) => {
+                    let start = pos + 1;
+                    let end = html[start..]
+                        .find(quote as char)
+                        .map_or(len, |end| start + end);
+                    pos = (end + 1).min(len);
+                    start..end
+                }
+                _ => {
+                    let start = pos;
+                    while pos < len && !bytes[pos].is_ascii_whitespace() && bytes[pos] != b'>' {
+                        pos += 1;
+                    }
+                    start..pos
+                }
+            };
+            attributes.push((attribute, value));
+        }
+
+        // Script and style contents aren't markup, a `<` in them means nothing
+        if name == "script" || name == "style" {
+            let closing = format!("</{}", name);
+            pos = html[pos..]
+                .to_ascii_lowercase()
+                .find(&closing)
+                .map_or(len, |end| pos + end);
+        }
+
+        tags.push(StartTag { name, attributes });
+    }
+    tags
+}
+
+/// State shared by every download of a `--mirror` run: where each capture
+/// of the run is saved, and the assets that were already downloaded.
+pub struct Mirror {
+    pages: HashMap<String, String>,
+    assets: Mutex<HashMap<String, Arc<OnceCell<Option<String>>>>>,
+}
+
+impl Mirror {
+    /// Links between the pages of `captures` lead to the most recent
+    /// capture of each one.
+    pub fn new(captures: &[CdxCapture], options: &DownloadOptions) -> Self {
+        let mut latest: HashMap<String, &CdxCapture> = HashMap::new();
+        for capture in captures {
+            let key = url_key(&capture.original_url);
+            if latest
+                .get(&key)
+                .is_none_or(|other| other.timestamp < capture.timestamp)
+            {
+                latest.insert(key, capture);
+            }
+        }
+
+        Self {
+            pages: latest
+                .into_iter()
+                .map(|(key, capture)| (key, capture_filename(capture, options)))
+                .collect(),
+            assets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Downloads an asset unless that already happened and returns the file
+    /// it was saved as, or `None` if it couldn't be downloaded. Pages asking
+    /// for an asset that is still downloading wait for it.
+    async fn asset(
+        &self,
+        client: &Client,
+        link: &WaybackLink,
+        options: &DownloadOptions,
+    ) -> Option<String> {
+        let cell = {
+            let mut assets = self.assets.lock().await;
+            Arc::clone(assets.entry(url_key(&link.original_url)).or_default())
+        };
+
+        cell.get_or_init(|| async {
+            let capture = CdxCapture {
+                url_key: None,
+                timestamp: link.timestamp.clone(),
+                original_url: link.original_url.clone(),
+                mime_type: None,
+                status_code: None,
+                digest: None,
+                length: None,
+                robot_flags: None,
+            };
+            let result = match fetch_capture(client, &capture, options).await {
+                Ok(fetched) => write_capture(&fetched, options)
+                    .await
+                    .map(|_| fetched.filename),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(filename) => {
+                    tracing::debug!("Downloaded asset {} as {}", link.original_url, filename);
+                    Some(filename)
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to download asset {}: {}", link.original_url, e);
+                    None
+                }
+            }
+        })
+        .await
+        .clone()
+    }
+
+    fn local_path(&self, link: &WaybackLink, assets: &HashMap<String, String>) -> Option<String> {
+        let key = url_key(&link.original_url);
+        self.pages.get(&key).or_else(|| assets.get(&key)).cloned()
+    }
+}
+
+/// Downloads a capture for `--mirror` and returns the name of the file it was
+/// saved as along with its size. HTML pages get their images, scripts and stylesheets
+/// downloaded next to them, and links to those and to the other pages of
+/// the run are rewritten to point at the local files.
+pub async fn mirror_capture(
+    client: &Client,
+    capture: &CdxCapture,
+    mirror: &Mirror,
+    options: &DownloadOptions,
+) -> Result<(String, u64)> {
+    let mut fetched = fetch_capture(client, capture, options).await?;
+
+    if is_html(&fetched.mime_type) {
+        let html = String::from_utf8_lossy(&fetched.body).into_owned();
+
+        let mut assets = HashMap::new();
+        for link in find_assets(&html) {
+            if let Some(filename) = mirror.asset(client, &link, options).await {
+                assets.insert(url_key(&link.original_url), filename);
+            }
+        }
+
+        fetched.body = rewrite_links(&html, &fetched.filename, |link| {
+            mirror.local_path(link, &assets)
+        })
+        .into_bytes();
+    }
+
+    let size = write_capture(&fetched, options).await?;
+    Ok((fetched.filename, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(timestamp: &str, original_url: &str) -> WaybackLink {
+        WaybackLink {
+            timestamp: timestamp.to_string(),
+            original_url: original_url.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_wayback_links() {
+        let logo = Some(link("20230115000000", "https://example.com/logo.png"));
+        for form in [
+            "https://web.archive.org/web/20230115000000im_/https://example.com/logo.png",
+            "http://web.archive.org/web/20230115000000im_/https://example.com/logo.png",
+            "//web.archive.org/web/20230115000000im_/https://example.com/logo.png",
+            " /web/20230115000000im_/https://example.com/logo.png ",
+            "/web/20230115000000/https://example.com/logo.png",
+        ] {
+            assert_eq!(parse_wayback_link(form), logo, "{}", form);
+        }
+
+        assert_eq!(
+            parse_wayback_link("/web/2023/example.com/a?b=c#d"),
+            Some(link("2023", "http://example.com/a?b=c#d"))
+        );
+        for invalid in [
+            "https://example.com/logo.png",
+            "/img/logo.png",
+            "https://web.archive.org/about/",
+            "/web/20230115000000",
+            "/web/im_/https://example.com/",
+            "/web/202301150000001/https://example.com/",
+            "/web/20230115000000im/https://example.com/",
+            "/web/20230115000000*_/https://example.com/",
+            "https://other.org/web/20230115000000/https://example.com/",
+        ] {
+            assert_eq!(parse_wayback_link(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn reads_start_tags() {
+        let html = concat!(
+            "<!DOCTYPE html><!-- <a href=\"/commented\"> -->",
+            "<A HREF='/one' class=x title = \"a > b\">",
+            "<script>if (a <b) {}</script>",
+            "<STYLE>p < a {}</Style>",
+            "<img src=/two alt/>",
+            "</a> 1 < 2",
+            "<input disabled value=\"unclosed",
+        );
+        let tags = start_tags(html);
+        let names: Vec<&str> = tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, ["a", "script", "style", "img", "input"]);
+        assert_eq!(tags[0].value(html, "href"), Some("/one"));
+        assert_eq!(tags[0].value(html, "class"), Some("x"));
+        assert_eq!(tags[0].value(html, "title"), Some("a > b"));
+        assert_eq!(tags[3].value(html, "src"), Some("/two"));
+        assert_eq!(tags[3].value(html, "alt"), None);
+        assert_eq!(tags[4].value(html, "value"), Some("unclosed"));
+    }
+
+    #[test]
+    fn finds_assets_once() {
+        let html = concat!(
+            "<img src=\"/web/2023im_/https://example.com/a.png\">",
+            "<img src=\"/web/2023im_/https://example.com/a.png\">",
+            "<script src=\"/web/2023js_/https://example.com/app.js?v=1&amp;x=2\"></script>",
+            "<link rel=\"Stylesheet\" href=\"/web/2023cs_/https://example.com/site.css\">",
+            "<link rel=\"shortcut icon\" href=\"/web/2023im_/https://example.com/favicon.ico\">",
+            "<link rel=\"canonical\" href=\"/web/2023/https://example.com/\">",
+            "<a href=\"/web/2023/https://example.com/page\">",
+            "<img src=\"https://cdn.example.com/not-archived.png\">",
+        );
+        assert_eq!(
+            find_assets(html),
+            [
+                link("2023", "https://example.com/a.png"),
+                link("2023", "https://example.com/app.js?v=1&x=2"),
+                link("2023", "https://example.com/site.css"),
+                link("2023", "https://example.com/favicon.ico"),
+            ]
+        );
+    }
+
+    #[test]
+    fn extracts_same_domain_links() {
+        let html = concat!(
+            "<a href=\"/web/2023/https://www.example.com/about\">",
+            "<a href=\"contact?from=home&amp;lang=en#form\">",
+            "<a href=\"../up\">",
+            "<a href=\"https://example.com/about\">",
+            "<a href=\"#top\">",
+            "<a href=\"https://other.org/\">",
+            "<a href=\"mailto:me@example.com\">",
+            "<a href=\"http://[::1\">",
+            "<link href=\"/style.css\">",
+        );
+        assert_eq!(
+            extract_links(html, "https://example.com/docs/index.html#intro"),
+            [
+                "https://www.example.com/about",
+                "https://example.com/docs/contact?from=home&lang=en",
+                "https://example.com/up",
+                "https://example.com/about",
+            ]
+        );
+        assert!(extract_links(html, "not a url").is_empty());
+    }
+
+    #[test]
+    fn collects_discovered_links_once() {
+        let links = DiscoveredLinks::default();
+        links.add("<a href=\"/a\"><a href=\"/b\">", "https://example.com/");
+        links.add("<a href=\"/b\"><a href=\"/c\">", "https://example.com/a");
+        assert_eq!(
+            links.to_url_list(),
+            "https://example.com/a\nhttps://example.com/b\nhttps://example.com/c\n"
+        );
+    }
+
+    #[test]
+    fn rewrites_links_to_local_files() {
+        let html = concat!(
+            "<a href=\"/web/2023/https://example.com/about#team\">About</a>",
+            "<img class=\"logo\" src='/web/2023im_/https://example.com/img/logo%20big.png'>",
+            "<a href=\"/web/2023/https://example.com/missing\">Missing</a>",
+            "<a href=\"https://other.org/\">Elsewhere</a>",
+            "<p>/web/2023/https://example.com/about</p>",
+        );
+        let rewritten = rewrite_links(html, "docs/index.html", |link| {
+            match link.original_url.as_str() {
+                "https://example.com/about#team" => Some("about.html".to_string()),
+                "https://example.com/img/logo%20big.png" => Some("img/logo big.png".to_string()),
+                _ => None,
+            }
+        });
+        assert_eq!(
+            rewritten,
+            concat!(
+                "<a href=\"../about.html#team\">About</a>",
+                "<img class=\"logo\" src='../img/logo%20big.png'>",
+                "<a href=\"/web/2023/https://example.com/missing\">Missing</a>",
+                "<a href=\"https://other.org/\">Elsewhere</a>",
+                "<p>/web/2023/https://example.com/about</p>",
+            )
+        );
+        assert_eq!(
+            rewrite_links("<p>text</p>", "a.html", |_| None),
+            "<p>text</p>"
+        );
+    }
+
+    #[test]
+    fn makes_relative_paths() {
+        assert_eq!(relative_path("index.html", "about.html"), "about.html");
+        assert_eq!(relative_path("index.html", "img/logo.png"), "img/logo.png");
+        assert_eq!(relative_path("a/b/page.html", "a/c.png"), "../c.png");
+        assert_eq!(relative_path("a/b/page.html", "a/b/c/d.png"), "c/d.png");
+        assert_eq!(
+            relative_path("a/page.html", "b/page.html"),
+            "../b/page.html"
+        );
+        assert_eq!(
+            relative_path("a/page.html", "x y/?.png"),
+            "../x%20y/%3F.png"
+        );
+    }
+
+    #[test]
+    fn keys_urls_by_page() {
+        assert_eq!(url_key("https://www.example.com/docs/"), "example.com/docs");
+        assert_eq!(url_key("http://example.com/docs"), "example.com/docs");
+        assert_eq!(url_key("http://example.com/?q=1"), "example.com?q=1");
+        assert_eq!(url_key("http://example.com/a#b"), "example.com/a");
+        assert_eq!(url_key("not a url"), "not a url");
+    }
+}
@@ -0,0 +1,548 @@
+use miette::{Result, miette};
+
+// Counted repetitions are expanded when compiling, so keep them small
+const MAX_REPEAT: u32 = 1000;
+
+/// A regular expression supporting the commonly used subset of the syntax:
+/// literals, `.`, character classes like `[a-z]` or `[^/]`, the `\d`, `\w`
+/// and `\s` shorthands, `^` and `$` anchors, groups with `|` alternation and
+/// the `*`, `+`, `?` and `{n,m}` quantifiers.
+///
+/// Matching runs in time linear in the input, as a Pike VM over a compiled
+/// program, so no pattern can make it backtrack exponentially.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    source: String,
+    program: Vec<Inst>,
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(Class),
+    Split(usize, usize),
+    Jump(usize),
+    Start,
+    End,
+    Match,
+}
+
+#[derive(Debug, Clone)]
+struct Class {
+    negated: bool,
+    items: Vec<ClassItem>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ClassItem {
+    Any,
+    Range(char, char),
+    Digit,
+    Word,
+    Space,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let node = parser.parse().map_err(|reason| {
+            miette!(
+                help = "Supported are literals, ., [...], \\d, \\w, \\s, ^, $, (...), | and the *, +, ? and {n,m} quantifiers",
+                "Invalid pattern '{}': {}",
+                source,
+                reason
+            )
+        })?;
+
+        let mut program = Vec::new();
+        compile(&node, &mut program);
+        program.push(Inst::Match);
+
+        Ok(Self {
+            source: source.to_string(),
+            program,
+        })
+    }
+
+    /// The pattern as it was written.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the pattern matches anywhere in `text`.
+    pub fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        let len = chars.len();
+        let mut current = Vec::new();
+        let mut seen = vec![false; self.program.len()];
+
+        for pos in 0..=len {
+            // A new thread starts at every position, the pattern isn't anchored
+            if self.add_thread(&mut current, &mut seen, 0, pos, len) {
+                return true;
+            }
+            let Some(&c) = chars.get(pos) else {
+                break;
+            };
+
+            let mut next = Vec::new();
+            let mut next_seen = vec![false; self.program.len()];
+            for &pc in &current {
+                if let Inst::Char(class) = &self.program[pc]
+                    && class.matches(c)
+                    && self.add_thread(&mut next, &mut next_seen, pc + 1, pos + 1, len)
+                {
+                    return true;
+                }
+            }
+            current = next;
+            seen = next_seen;
+        }
+
+        false
+    }
+
+    /// Follows jumps, splits and assertions from `pc`, queueing the character
+    /// instructions reached. Returns true once `Match` is reachable.
+    fn add_thread(
+        &self,
+        threads: &mut Vec<usize>,
+        seen: &mut [bool],
+        pc: usize,
+        pos: usize,
+        len: usize,
+    ) -> bool {
+        if seen[pc] {
+            return false;
+        }
+        seen[pc] = true;
+
+        match self.program[pc] {
+            Inst::Char(_) => {
+                threads.push(pc);
+                false
+            }
+            Inst::Split(first, second) => {
+                let matched = self.add_thread(threads, seen, first, pos, len);
+                self.add_thread(threads, seen, second, pos, len) || matched
+            }
+            Inst::Jump(target) => self.add_thread(threads, seen, target, pos, len),
+            Inst::Start => pos == 0 && self.add_thread(threads, seen, pc + 1, pos, len),
+            Inst::End => pos == len && self.add_thread(threads, seen, pc + 1, pos, len),
+            Inst::Match => true,
+        }
+    }
+}
+
+impl Class {
+    fn single(item: ClassItem) -> Self {
+        Self {
+            negated: false,
+            items: vec![item],
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        let found = self.items.iter().any(|item| match *item {
+            ClassItem::Any => true,
+            ClassItem::Range(low, high) => (low..=high).contains(&c),
+            ClassItem::Digit => c.is_ascii_digit(),
+            ClassItem::Word => c.is_alphanumeric() || c == '_',
+            ClassItem::Space => c.is_whitespace(),
+        });
+        found != self.negated
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse(&mut self) -> Result<Node, String> {
+        let node = self.alternation()?;
+        if self.pos < self.chars.len() {
+            return Err("unmatched ')'".to_string());
+        }
+        Ok(node)
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concat()?];
+        while self.eat('|') {
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Node::Alternate(branches)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifiers(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().ok_or("unexpected end of pattern")?;
+        self.pos += 1;
+        let node = match c {
+            '(' => {
+                // Non-capturing groups behave the same, nothing is captured
+                if self.eat('?') && !self.eat(':') {
+                    return Err("only (?:...) groups are supported".to_string());
+                }
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return Err("missing ')'".to_string());
+                }
+                inner
+            }
+            '[' => Node::Class(self.class()?),
+            '.' => Node::Class(Class::single(ClassItem::Any)),
+            '^' => Node::Start,
+            '$' => Node::End,
+            '\\' => Node::Class(self.escape()?),
+            '*' | '+' | '?' => return Err(format!("nothing to repeat before '{}'", c)),
+            _ => Node::Class(Class::single(ClassItem::Range(c, c))),
+        };
+        Ok(node)
+    }
+
+    fn quantifiers(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('{') => match self.counted() {
+                    Some(range) => range?,
+                    None => return Ok(node),
+                },
+                Some(c @ ('*' | '+' | '?')) => {
+                    self.pos += 1;
+                    match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        _ => (0, Some(1)),
+                    }
+                }
+                _ => return Ok(node),
+            };
+            // Lazy quantifiers match the same strings, only captures differ
+            self.eat('?');
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    /// Parses `{n}`, `{n,}` or `{n,m}`. A `{` that doesn't start one of those
+    /// is left alone and matched literally.
+    fn counted(&mut self) -> Option<Result<(u32, Option<u32>), String>> {
+        let rest: String = self.chars[self.pos..].iter().collect();
+        let end = rest.find('}')?;
+        let inner = &rest[1..end];
+        let (min, max) = match inner.split_once(',') {
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+            None => {
+                let count = inner.parse().ok()?;
+                (count, Some(count))
+            }
+        };
+        self.pos += rest[..=end].chars().count();
+
+        if max.is_some_and(|max| max < min) {
+            return Some(Err(format!("invalid repetition {{{}}}", inner)));
+        }
+        if max.unwrap_or(min) > MAX_REPEAT {
+            return Some(Err(format!(
+                "repetition over {} in {{{}}}",
+                MAX_REPEAT, inner
+            )));
+        }
+        Some(Ok((min, max)))
+    }
+
+    fn class(&mut self) -> Result<Class, String> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or("missing ']'")?;
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+
+            let low = if c == '\\' {
+                let escaped = self.escape()?;
+                match escaped.items.as_slice() {
+                    [ClassItem::Range(low, high)] if low == high && !escaped.negated => *low,
+                    _ if escaped.negated => {
+                        return Err("negated shorthands are not supported inside [...]".to_string());
+                    }
+                    _ => {
+                        items.extend(escaped.items);
+                        continue;
+                    }
+                }
+            } else {
+                c
+            };
+
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let high = self.peek().ok_or("missing ']'")?;
+                self.pos += 1;
+                if high < low {
+                    return Err(format!("invalid range {}-{}", low, high));
+                }
+                items.push(ClassItem::Range(low, high));
+            } else {
+                items.push(ClassItem::Range(low, low));
+            }
+        }
+        Ok(Class { negated, items })
+    }
+
+    fn escape(&mut self) -> Result<Class, String> {
+        let c = self.peek().ok_or("trailing '\\'")?;
+        self.pos += 1;
+        let (item, negated) = match c {
+            'd' => (ClassItem::Digit, false),
+            'D' => (ClassItem::Digit, true),
+            'w' => (ClassItem::Word, false),
+            'W' => (ClassItem::Word, true),
+            's' => (ClassItem::Space, false),
+            'S' => (ClassItem::Space, true),
+            'n' => (ClassItem::Range('\n', '\n'), false),
+            't' => (ClassItem::Range('\t', '\t'), false),
+            'r' => (ClassItem::Range('\r', '\r'), false),
+            _ => (ClassItem::Range(c, c), false),
+        };
+        Ok(Class {
+            negated,
+            items: vec![item],
+        })
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Class(class) => program.push(Inst::Char(class.clone())),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program);
+            }
+        }
+        Node::Alternate(branches) => {
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 == branches.len() {
+                    compile(branch, program);
+                } else {
+                    let split = program.len();
+                    program.push(Inst::Split(0, 0));
+                    compile(branch, program);
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+            }
+            for jump in jumps {
+                program[jump] = Inst::Jump(program.len());
+            }
+        }
+        Node::Repeat(node, min, max) => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(0, 0));
+                    compile(node, program);
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    for _ in *min..*max {
+                        let split = program.len();
+                        program.push(Inst::Split(0, 0));
+                        compile(node, program);
+                        program[split] = Inst::Split(split + 1, program.len());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).unwrap().is_match(text)
+    }
+
+    fn error(pattern: &str) -> String {
+        Pattern::new(pattern).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn matches_anywhere_unless_anchored() {
+        assert!(matches("blog", "https://example.com/blog/post"));
+        assert!(!matches("blog", "https://example.com/news"));
+        assert!(matches("^https://", "https://example.com"));
+        assert!(!matches("^example", "https://example.com"));
+        assert!(matches(r"\.pdf$", "/files/report.pdf"));
+        assert!(!matches(r"\.pdf$", "/files/report.pdf?download=1"));
+        assert!(matches("^$", ""));
+        assert!(!matches("^$", "a"));
+        assert!(matches("", "anything"));
+    }
+
+    #[test]
+    fn matches_classes_and_shorthands() {
+        assert!(matches("a.c", "abc"));
+        assert!(!matches("a.c", "ac"));
+        assert!(matches("^[a-c]+$", "abcabc"));
+        assert!(!matches("^[a-c]+$", "abcd"));
+        assert!(matches("^[^/]+$", "page.html"));
+        assert!(!matches("^[^/]+$", "dir/page.html"));
+        // A leading ']' and a trailing '-' are literal
+        assert!(matches("^[]x-]+$", "]-x"));
+        assert!(matches(r"^[\d_]+$", "20_24"));
+        assert!(matches(r"^\d{4}$", "2024"));
+        assert!(!matches(r"^\d{4}$", "202a"));
+        assert!(matches(r"^\w+$", "snake_case1"));
+        assert!(!matches(r"\W", "snake_case1"));
+        assert!(matches(r"a\sb", "a\tb"));
+        assert!(!matches(r"a\Sb", "a b"));
+        assert!(matches(r"\n", "line\nbreak"));
+        assert!(matches(r"^\?\*\.$", "?*."));
+    }
+
+    #[test]
+    fn matches_alternation_and_groups() {
+        assert!(matches("^(jpe?g|png)$", "jpg"));
+        assert!(matches("^(jpe?g|png)$", "jpeg"));
+        assert!(matches("^(jpe?g|png)$", "png"));
+        assert!(!matches("^(jpe?g|png)$", "gif"));
+        assert!(matches("^(?:ab)+$", "ababab"));
+        assert!(!matches("^(?:ab)+$", "aba"));
+        assert!(matches("^a|b$", "axx"));
+        assert!(matches("^a|b$", "xxb"));
+        assert!(!matches("^a|b$", "xbx"));
+        assert!(matches("^(|x)$", ""));
+    }
+
+    #[test]
+    fn matches_quantifiers() {
+        assert!(matches("^ab*c$", "ac"));
+        assert!(matches("^ab*c$", "abbbc"));
+        assert!(!matches("^ab+c$", "ac"));
+        assert!(matches("^ab?c$", "abc"));
+        assert!(!matches("^ab?c$", "abbc"));
+        assert!(matches("^a{2,3}$", "aaa"));
+        assert!(!matches("^a{2,3}$", "a"));
+        assert!(!matches("^a{2,3}$", "aaaa"));
+        assert!(matches("^a{2,}$", "aaaaaa"));
+        assert!(!matches("^a{2,}$", "a"));
+        assert!(matches("^a{0}b$", "b"));
+        assert!(matches("^a*?b+?$", "aabb"));
+        // Braces that don't hold a count are literal
+        assert!(matches("^a{x}$", "a{x}"));
+        assert!(matches("^a{,2}$", "a{,2}"));
+        assert!(matches("^{$", "{"));
+    }
+
+    #[test]
+    fn nested_repetitions_stay_linear() {
+        let text = format!("{}b", "a".repeat(5000));
+        assert!(!matches("^(a*)*$", &text));
+        assert!(!matches("^(a|aa)+$", &text));
+        assert!(matches("^(a*)*b$", &text));
+    }
+
+    #[test]
+    fn matches_unicode_characters() {
+        assert!(matches("^caf.$", "café"));
+        assert!(matches("^[à-ü]+$", "éèü"));
+        assert!(matches(r"^\w+$", "naïve"));
+    }
+
+    #[test]
+    fn keeps_the_source() {
+        assert_eq!(Pattern::new(r"^\d+$").unwrap().as_str(), r"^\d+$");
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert_eq!(error("(ab"), "Invalid pattern '(ab': missing ')'");
+        assert_eq!(error("ab)"), "Invalid pattern 'ab)': unmatched ')'");
+        assert_eq!(error("[ab"), "Invalid pattern '[ab': missing ']'");
+        assert_eq!(error("[z-a]"), "Invalid pattern '[z-a]': invalid range z-a");
+        assert_eq!(
+            error("*a"),
+            "Invalid pattern '*a': nothing to repeat before '*'"
+        );
+        assert_eq!(
+            error("a|+"),
+            "Invalid pattern 'a|+': nothing to repeat before '+'"
+        );
+        assert_eq!(error(r"a\"), r"Invalid pattern 'a\': trailing '\'");
+        assert_eq!(
+            error("(?=a)"),
+            "Invalid pattern '(?=a)': only (?:...) groups are supported"
+        );
+        assert_eq!(
+            error(r"[\D]"),
+            r"Invalid pattern '[\D]': negated shorthands are not supported inside [...]"
+        );
+        assert_eq!(
+            error("a{3,2}"),
+            "Invalid pattern 'a{3,2}': invalid repetition {3,2}"
+        );
+        assert_eq!(
+            error("a{1001}"),
+            "Invalid pattern 'a{1001}': repetition over 1000 in {1001}"
+        );
+        assert!(Pattern::new("a{1000}").is_ok());
+    }
+}
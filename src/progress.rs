@@ -0,0 +1,194 @@
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+
+const BAR_WIDTH: usize = 30;
+// Slot lines are cut short so they never wrap, which would throw off the
+// number of lines to move back up when redrawing.
+const MAX_LINE_WIDTH: usize = 76;
+
+/// Terminal progress display with an overall bar and one line per active
+/// download slot, redrawn in place on stderr.
+///
+/// Bars are only shown between [`Progress::begin`] and
+/// [`Progress::clear_bars`]. When stderr is not a terminal nothing is redrawn
+/// and every message is printed on its own line instead, so piped output
+/// stays readable.
+pub struct Progress {
+    interactive: bool,
+    hidden: bool,
+    log_to_stderr: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    visible: bool,
+    total: usize,
+    done: usize,
+    slots: Vec<Option<String>>,
+    drawn_lines: usize,
+}
+
+impl Progress {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            interactive: std::io::stderr().is_terminal(),
+            hidden: false,
+            log_to_stderr: false,
+            state: Mutex::new(State {
+                visible: false,
+                total: 0,
+                done: 0,
+                slots: vec![None; slots],
+                drawn_lines: 0,
+            }),
+        }
+    }
+
+    /// Prints messages to stderr instead of stdout when not drawing bars, to
+    /// keep stdout free for machine readable output.
+    pub fn log_to_stderr(mut self) -> Self {
+        self.log_to_stderr = true;
+        self
+    }
+
+    /// Never shows bars or per-download messages, only lines printed through
+    /// [`Progress::println`].
+    pub fn hidden(mut self) -> Self {
+        self.interactive = false;
+        self.hidden = true;
+        self
+    }
+
+    /// Shows the bars for a run of `total` downloads.
+    pub fn begin(&self, total: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.visible = true;
+        state.total = total;
+        state.done = 0;
+        if self.interactive {
+            self.draw(&mut state);
+        }
+    }
+
+    /// Changes the total of the overall bar, for runs that find more
+    /// downloads while they are already in progress.
+    pub fn set_total(&self, total: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.total = total;
+        if self.interactive {
+            self.draw(&mut state);
+        }
+    }
+
+    /// Claims a free slot for a download and shows `message` in it.
+    pub fn start(&self, message: String) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let slot = match state.slots.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                state.slots.push(None);
+                state.slots.len() - 1
+            }
+        };
+
+        if self.interactive {
+            state.slots[slot] = Some(message);
+            self.draw(&mut state);
+        } else {
+            state.slots[slot] = Some(String::new());
+            if !self.hidden {
+                self.print_line(&message);
+            }
+        }
+
+        slot
+    }
+
+    /// Updates the message shown in a slot.
+    pub fn set_message(&self, slot: usize, message: String) {
+        let mut state = self.state.lock().unwrap();
+        if self.interactive {
+            state.slots[slot] = Some(message);
+            self.draw(&mut state);
+        } else if !self.hidden {
+            self.print_line(&message);
+        }
+    }
+
+    /// Releases a slot and advances the overall bar.
+    pub fn finish(&self, slot: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.slots[slot] = None;
+        state.done += 1;
+        if self.interactive {
+            self.draw(&mut state);
+        }
+    }
+
+    /// Prints a line above the bars without disturbing them.
+    pub fn println(&self, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        if self.interactive {
+            let mut stderr = std::io::stderr().lock();
+            Self::clear(&mut stderr, &mut state);
+            let _ = writeln!(stderr, "{}", message);
+            drop(stderr);
+            self.draw(&mut state);
+        } else {
+            self.print_line(message);
+        }
+    }
+
+    /// Removes the bars so a final summary can be printed after them.
+    pub fn clear_bars(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.visible = false;
+        if self.interactive {
+            Self::clear(&mut std::io::stderr().lock(), &mut state);
+        }
+    }
+
+    fn print_line(&self, message: &str) {
+        if self.log_to_stderr {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+
+    fn clear(stderr: &mut impl Write, state: &mut State) {
+        if state.drawn_lines > 0 {
+            let _ = write!(stderr, "\x1b[{}A\x1b[J", state.drawn_lines);
+            state.drawn_lines = 0;
+        }
+    }
+
+    fn draw(&self, state: &mut State) {
+        let mut stderr = std::io::stderr().lock();
+        Self::clear(&mut stderr, state);
+        if !state.visible {
+            return;
+        }
+
+        let filled = (state.done * BAR_WIDTH)
+            .checked_div(state.total)
+            .unwrap_or(BAR_WIDTH);
+        let _ = writeln!(
+            stderr,
+            "[{}{}] {}/{}",
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            state.done,
+            state.total
+        );
+        state.drawn_lines = 1;
+
+        for message in state.slots.iter().flatten() {
+            let line: String = message.chars().take(MAX_LINE_WIDTH).collect();
+            let _ = writeln!(stderr, "  {}", line);
+            state.drawn_lines += 1;
+        }
+
+        let _ = stderr.flush();
+    }
+}
@@ -0,0 +1,318 @@
+use miette::{Result, miette};
+use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+// How long to pause when a response says the limit is almost reached but
+// not when it resets
+const DEFAULT_PAUSE: Duration = Duration::from_secs(60);
+
+// Longest pause, so a bogus reset time can't stall a run for good
+const MAX_PAUSE: Duration = Duration::from_secs(3600);
+
+/// Token bucket rate limiter with a separate bucket for every host.
+///
+/// Every bucket holds up to one second worth of requests, so short bursts are
+/// allowed while the long term average stays at `requests_per_second`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests_per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> Self {
+        Self {
+            requests_per_second,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until a request to `host` is allowed.
+    pub async fn acquire(&self, host: &str) {
+        let capacity = self.requests_per_second.max(1.0);
+        let wait = {
+            let mut buckets = self.buckets.lock().await;
+            let now = Instant::now();
+            let bucket = buckets.entry(host.to_string()).or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            });
+
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.requests_per_second).min(capacity);
+            bucket.updated = now;
+
+            // The token is taken right away, even when that leaves the bucket
+            // in debt, so concurrent callers queue up behind each other.
+            bucket.tokens -= 1.0;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.requests_per_second)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tracing::trace!("Rate limiting {} for {:?}", host, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Token bucket that limits how many bytes of response bodies all downloads
+/// together read per second.
+///
+/// Like [`RateLimiter`] the bucket holds up to one second worth of bytes.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bytes_per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second as f64;
+        Self {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` that were just read out of the bucket, waiting until
+    /// the reads are back under the limit.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens =
+                (bucket.tokens + elapsed * self.bytes_per_second).min(self.bytes_per_second);
+            bucket.updated = now;
+
+            // Chunks can be larger than the bucket, the debt they leave is
+            // paid off by waiting
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_second)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tracing::trace!("Throttling downloads for {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parses a `--bandwidth-limit` value in bytes per second, a number with an
+/// optional `K`, `M` or `G` suffix for multiples of 1024, e.g. `512K` or
+/// `1.5M`.
+pub fn parse_byte_rate(value: &str) -> Result<u64> {
+    let invalid = || {
+        miette!(
+            help = "Use a number of bytes per second like 500000, 512K or 1M",
+            "'{}' is not a valid bandwidth limit",
+            value
+        )
+    };
+    let trimmed = value.trim();
+    let trimmed = trimmed.strip_suffix(['B', 'b']).unwrap_or(trimmed);
+    let (number, multiplier) = match trimmed.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&trimmed[..i], 1024.0),
+        Some((i, 'M' | 'm')) => (&trimmed[..i], 1024.0 * 1024.0),
+        Some((i, 'G' | 'g')) => (&trimmed[..i], 1024.0 * 1024.0 * 1024.0),
+        _ => (trimmed, 1.0),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| invalid())?;
+    let bytes = number * multiplier;
+    if !bytes.is_finite() || bytes < 1.0 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+/// Pauses every request once a response reports, through
+/// `X-RateLimit-Remaining`, that fewer than `threshold` requests are left,
+/// until the time in its `X-RateLimit-Reset`.
+///
+/// Unlike [`RateLimiter`] this only slows down when the server asks for it.
+#[derive(Debug)]
+pub struct HeaderRateLimiter {
+    threshold: u64,
+    resume_at: Mutex<Option<Instant>>,
+}
+
+impl HeaderRateLimiter {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            resume_at: Mutex::new(None),
+        }
+    }
+
+    /// Waits until a pause started by [`observe`](Self::observe) is over.
+    pub async fn wait(&self) {
+        let resume_at = *self.resume_at.lock().await;
+        if let Some(resume_at) = resume_at {
+            tokio::time::sleep_until(resume_at.into()).await;
+        }
+    }
+
+    /// Starts or extends the pause when the rate limit headers of a
+    /// response say fewer than `threshold` requests are left.
+    pub async fn observe(&self, headers: &HeaderMap) {
+        let Some(remaining) = header_number(headers, "x-ratelimit-remaining") else {
+            return;
+        };
+        if remaining >= self.threshold {
+            return;
+        }
+        let pause = header_number(headers, "x-ratelimit-reset")
+            .map_or(DEFAULT_PAUSE, reset_delay)
+            .min(MAX_PAUSE);
+
+        let now = Instant::now();
+        let mut resume_at = self.resume_at.lock().await;
+        let paused = resume_at.is_some_and(|resume_at| resume_at > now);
+        if resume_at.is_none_or(|resume_at| resume_at < now + pause) {
+            *resume_at = Some(now + pause);
+        }
+        // Downloads running alongside report the same, once is enough
+        if !paused {
+            tracing::warn!(
+                "The archive allows {} more requests, pausing downloads for {}s",
+                remaining,
+                pause.as_secs()
+            );
+        }
+    }
+}
+
+fn header_number(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Time until an `X-RateLimit-Reset` of `reset`, which is either a Unix time
+/// or the seconds left depending on the server.
+fn reset_delay(reset: u64) -> Duration {
+    // Seconds left never get this large, Unix times are past it since 2001
+    const UNIX_TIME_FROM: u64 = 1_000_000_000;
+    if reset < UNIX_TIME_FROM {
+        return Duration::from_secs(reset);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Duration::from_secs(reset.saturating_sub(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn parses_byte_rates_with_suffixes() {
+        assert_eq!(parse_byte_rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_byte_rate("1.5M").unwrap(), 1024 * 1024 * 3 / 2);
+        assert_eq!(parse_byte_rate("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_byte_rate("512kb").unwrap(), 512 * 1024);
+        assert_eq!(parse_byte_rate(" 64 KB ").unwrap(), 64 * 1024);
+    }
+
+    #[test]
+    fn parses_a_bare_number_of_bytes() {
+        assert_eq!(parse_byte_rate("500000").unwrap(), 500_000);
+        assert_eq!(parse_byte_rate("1000B").unwrap(), 1000);
+    }
+
+    #[test]
+    fn rejects_invalid_byte_rates() {
+        for value in [
+            "", "K", "10X", "1T", "fast", "-5K", "0", "0K", "0.5", "inf", "NaN",
+        ] {
+            assert!(parse_byte_rate(value).is_err(), "{:?}", value);
+        }
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn reads_a_reset_in_seconds_left() {
+        assert_eq!(reset_delay(0), Duration::ZERO);
+        assert_eq!(reset_delay(30), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn reads_a_reset_as_a_unix_time() {
+        let delay = reset_delay(unix_now() + 120);
+        assert!(delay <= Duration::from_secs(120), "{:?}", delay);
+        assert!(delay >= Duration::from_secs(118), "{:?}", delay);
+        // Already passed
+        assert_eq!(reset_delay(unix_now() - 60), Duration::ZERO);
+    }
+
+    fn headers(remaining: &str, reset: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-ratelimit-remaining",
+            HeaderValue::from_str(remaining).unwrap(),
+        );
+        if let Some(reset) = reset {
+            headers.insert("x-ratelimit-reset", HeaderValue::from_str(reset).unwrap());
+        }
+        headers
+    }
+
+    async fn pause(limiter: &HeaderRateLimiter) -> Option<Duration> {
+        let resume_at = (*limiter.resume_at.lock().await)?;
+        Some(resume_at.saturating_duration_since(Instant::now()))
+    }
+
+    #[tokio::test]
+    async fn pauses_until_the_reset_below_the_threshold() {
+        let limiter = HeaderRateLimiter::new(5);
+        limiter.observe(&headers("5", Some("30"))).await;
+        assert_eq!(pause(&limiter).await, None);
+
+        limiter.observe(&headers("4", Some("30"))).await;
+        let paused = pause(&limiter).await.unwrap();
+        assert!(paused <= Duration::from_secs(30) && paused > Duration::from_secs(29));
+
+        // A shorter reset doesn't cut the pause short
+        limiter.observe(&headers("1", Some("5"))).await;
+        assert!(pause(&limiter).await.unwrap() > Duration::from_secs(29));
+    }
+
+    #[tokio::test]
+    async fn pauses_for_a_default_or_capped_time() {
+        let limiter = HeaderRateLimiter::new(5);
+        limiter.observe(&headers("0", None)).await;
+        let paused = pause(&limiter).await.unwrap();
+        assert!(paused <= DEFAULT_PAUSE && paused > DEFAULT_PAUSE - Duration::from_secs(1));
+
+        let limiter = HeaderRateLimiter::new(5);
+        let reset = (unix_now() + 10 * 3600).to_string();
+        limiter.observe(&headers("0", Some(&reset))).await;
+        assert!(pause(&limiter).await.unwrap() <= MAX_PAUSE);
+    }
+}
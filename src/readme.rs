@@ -0,0 +1,106 @@
+use crate::cdx::MatchType;
+use crate::html_index::{ArchivedFile, link};
+use crate::timestamp::format_readable;
+
+// Captures listed in the table of the README
+const RECENT_CAPTURES: usize = 10;
+
+/// What a README written by [`render_readme`] says about a download besides
+/// its files.
+#[derive(Debug, Clone)]
+pub struct ArchiveSummary {
+    /// URLs the CDX API was queried for.
+    pub urls: Vec<String>,
+    pub match_types: Vec<MatchType>,
+    /// Options the captures were selected with, as given on the command
+    /// line, e.g. `--from 2020`.
+    pub filters: Vec<String>,
+    /// When the archive was written, as a capture style timestamp.
+    pub created: String,
+    /// Size of all the files together, e.g. `1.2 MB`.
+    pub size: String,
+}
+
+/// A `README.md` describing a download of `files`: what was queried and how,
+/// when, how much was saved and a table of the most recent captures linking
+/// to their files. It only uses CommonMark, apart from the table, which
+/// renderers without table support show as plain lines.
+pub fn render_readme(summary: &ArchiveSummary, files: &[ArchivedFile]) -> String {
+    let mut readme = String::from("# Archived captures\n\n");
+    readme.push_str(&format!(
+        "Downloaded from the Wayback Machine with noway on {}.\n\n",
+        escape(&format_readable(&summary.created))
+    ));
+
+    readme.push_str("## Query\n\n");
+    let urls = summary.urls.iter().map(|url| code(url)).collect::<Vec<_>>();
+    readme.push_str(&format!("- URLs: {}\n", urls.join(", ")));
+    let match_types: Vec<String> = summary
+        .match_types
+        .iter()
+        .map(|match_type| code(match_type.as_str()))
+        .collect();
+    readme.push_str(&format!("- Match type: {}\n", match_types.join(", ")));
+    if summary.filters.is_empty() {
+        readme.push_str("- Filters: none\n");
+    } else {
+        readme.push_str("- Filters:\n");
+        for filter in &summary.filters {
+            readme.push_str(&format!("  - {}\n", code(filter)));
+        }
+    }
+
+    readme.push_str("\n## Contents\n\n");
+    readme.push_str(&format!("- Files: {}\n", files.len()));
+    readme.push_str(&format!("- Total size: {}\n", escape(&summary.size)));
+
+    let mut recent: Vec<&ArchivedFile> = files.iter().collect();
+    recent.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    recent.truncate(RECENT_CAPTURES);
+    if !recent.is_empty() {
+        readme.push_str("\n## Most recent captures\n\n");
+        readme.push_str("| Captured | Original URL | File |\n");
+        readme.push_str("| --- | --- | --- |\n");
+        for file in recent {
+            readme.push_str(&format!(
+                "| {} | {} | [{}]({}) |\n",
+                escape(&format_readable(&file.timestamp)),
+                escape(&file.original_url),
+                escape(&file.filename),
+                link(&file.filename)
+            ));
+        }
+    }
+    readme
+}
+
+/// Backslash escapes the characters of `text` that could start Markdown
+/// syntax, or end a table cell.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_[]<>|&!#".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// `text` as a code span, fenced with one backtick more than the longest
+/// run of them inside it.
+fn code(text: &str) -> String {
+    let longest = text
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    let fence = "`".repeat(longest + 1);
+    // A space on each side keeps backticks at the ends out of the fence
+    let padding = if text.starts_with('`') || text.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!("{}{}{}{}{}", fence, padding, text, padding, fence)
+}
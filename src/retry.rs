@@ -0,0 +1,137 @@
+use miette::{Result, miette};
+use rand::Rng;
+use std::time::Duration;
+
+// Share of every pause `Backoff::jitter` adds or takes away at most
+const JITTER: f64 = 0.2;
+
+/// Longest pause [`Backoff::delay`] grows to, unless the first one is
+/// longer already.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait before retrying a failed request: `delay` before the
+/// first retry, multiplied by `factor` for every one after it, up to
+/// [`MAX_RETRY_DELAY`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub delay: Duration,
+    pub factor: f64,
+    /// Vary every pause by up to 20% either way, so downloads that failed
+    /// together don't all retry at the same moment.
+    pub jitter: bool,
+}
+
+impl Backoff {
+    /// A backoff starting at `delay_seconds`, checking that the delay isn't
+    /// negative and the factor doesn't shrink the pauses.
+    pub fn new(delay_seconds: f64, factor: f64, jitter: bool) -> Result<Self> {
+        if !delay_seconds.is_finite() || delay_seconds < 0.0 {
+            return Err(miette!(
+                help = "Use a number of seconds like 1 or 0.5",
+                "Invalid retry delay: {}",
+                delay_seconds
+            ));
+        }
+        if !factor.is_finite() || factor < 1.0 {
+            return Err(miette!(
+                help = "Use 1 for the same delay before every retry, or more to wait longer \
+                        each time",
+                "Invalid retry backoff factor: {}",
+                factor
+            ));
+        }
+        Ok(Self {
+            delay: Duration::from_secs_f64(delay_seconds),
+            factor,
+            jitter,
+        })
+    }
+
+    /// The pause before retry number `retry`, counting from 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        if self.delay.is_zero() {
+            return Duration::ZERO;
+        }
+        let max = self.delay.max(MAX_RETRY_DELAY).as_secs_f64();
+        let exponent = i32::try_from(retry.saturating_sub(1)).unwrap_or(i32::MAX);
+        // Enough retries with a large factor make this infinite
+        let mut seconds = (self.delay.as_secs_f64() * self.factor.powi(exponent)).min(max);
+        if self.jitter {
+            seconds *= rand::thread_rng().gen_range(1.0 - JITTER..=1.0 + JITTER);
+        }
+        Duration::from_secs_f64(seconds.min(max))
+    }
+}
+
+impl Default for Backoff {
+    /// One second, doubled for every retry.
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(1),
+            factor: 2.0,
+            jitter: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_the_delay_before_the_first_retry() {
+        let backoff = Backoff::new(1.5, 3.0, false).unwrap();
+        assert_eq!(backoff.delay(1), Duration::from_millis(1500));
+        // Retry 0 doesn't exist, it gets the first pause too
+        assert_eq!(backoff.delay(0), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn multiplies_the_delay_for_every_retry() {
+        let backoff = Backoff::default();
+        let delays: Vec<Duration> = (1..=5).map(|retry| backoff.delay(retry)).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16].map(Duration::from_secs));
+
+        let backoff = Backoff::new(2.0, 1.0, false).unwrap();
+        assert_eq!(backoff.delay(10), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn varies_the_delay_by_up_to_a_fifth() {
+        let backoff = Backoff::new(10.0, 2.0, true).unwrap();
+        for _ in 0..1000 {
+            let delay = backoff.delay(2);
+            assert!(delay >= Duration::from_secs(16), "{:?}", delay);
+            assert!(delay <= Duration::from_secs(24), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn stops_growing_at_the_maximum() {
+        let backoff = Backoff::default();
+        assert_eq!(backoff.delay(9), Duration::from_secs(256));
+        assert_eq!(backoff.delay(10), MAX_RETRY_DELAY);
+        assert_eq!(backoff.delay(u32::MAX), MAX_RETRY_DELAY);
+
+        let backoff = Backoff::new(1.0, 1000.0, true).unwrap();
+        assert!(backoff.delay(u32::MAX) <= MAX_RETRY_DELAY);
+
+        // A longer first pause than the maximum is kept
+        let backoff = Backoff::new(600.0, 2.0, false).unwrap();
+        assert_eq!(backoff.delay(5), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn never_waits_without_a_delay() {
+        let backoff = Backoff::new(0.0, 2.0, true).unwrap();
+        assert_eq!(backoff.delay(u32::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(Backoff::new(-1.0, 2.0, false).is_err());
+        assert!(Backoff::new(f64::NAN, 2.0, false).is_err());
+        assert!(Backoff::new(1.0, 0.5, false).is_err());
+        assert!(Backoff::new(1.0, f64::INFINITY, false).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use miette::Result;
+use reqwest::Client;
+
+use crate::cdx::{CdxQuery, MatchType, get_wayback_cdx_urls};
+use crate::download::{DownloadOptions, fetch_capture};
+
+/// The `Allow` and `Disallow` rules of a `robots.txt` that apply to a user
+/// agent: those for `*` and those for its product token, e.g. `noway` for
+/// `noway/0.1.3`.
+#[derive(Debug, Clone, Default)]
+pub struct RobotsTxt {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl RobotsTxt {
+    pub fn parse(content: &str, user_agent: &str) -> Self {
+        let token = user_agent
+            .split(['/', ' '])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        let mut rules = Vec::new();
+        // Consecutive User-agent lines share the rules that follow them
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        agents.clear();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_ascii_lowercase());
+                }
+                key @ ("allow" | "disallow") => {
+                    in_rules = true;
+                    let applies = agents.iter().any(|agent| agent == "*" || *agent == token);
+                    // An empty Disallow allows everything
+                    if applies && !value.is_empty() {
+                        rules.push(Rule {
+                            allow: key == "allow",
+                            pattern: value.to_string(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self { rules }
+    }
+
+    /// Whether `path`, with its query string, may be crawled. The longest
+    /// matching rule decides, `Allow` winning ties.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| pattern_matches(&rule.pattern, path))
+            .max_by_key(|rule| (rule.pattern.len(), rule.allow))
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+/// Matches a robots.txt path pattern, where `*` stands for any characters
+/// and a trailing `$` anchors it to the end of the path.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = path.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return !anchored || rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    if anchored {
+        rest.ends_with(last)
+    } else {
+        rest.contains(last)
+    }
+}
+
+/// Downloads the most recent capture of `robots.txt` on `host` from the
+/// archive, `None` when it was never archived. It is looked up with the CDX
+/// API at `cdx_api`.
+pub async fn fetch_archived_robots_txt(
+    client: &Client,
+    host: &str,
+    cdx_api: &str,
+    options: &DownloadOptions,
+) -> Result<Option<RobotsTxt>> {
+    let query = CdxQuery {
+        cdx_api: cdx_api.to_string(),
+        match_type: MatchType::Exact,
+        timeout: Some(options.timeout),
+        limit: Some(-1),
+        user_agent: options.user_agent.clone(),
+        ..CdxQuery::new(format!("{}/robots.txt", host))
+    };
+    let Some(capture) = get_wayback_cdx_urls(client, &query).await?.pop() else {
+        return Ok(None);
+    };
+
+    // Fresh options so the file doesn't take a name from the ones handed
+    // out to downloads
+    let options = DownloadOptions {
+        retries: options.retries,
+        backoff: options.backoff,
+        timeout: options.timeout,
+        user_agent: options.user_agent.clone(),
+        user_agent_rotation: options.user_agent_rotation.clone(),
+        rate_limiter: options.rate_limiter.clone(),
+        header_rate_limiter: options.header_rate_limiter.clone(),
+        bandwidth_limiter: options.bandwidth_limiter.clone(),
+        timeouts: options.timeouts.clone(),
+        capture_url_template: options.capture_url_template.clone(),
+        ..DownloadOptions::default()
+    };
+    let fetched = fetch_capture(client, &capture, &options).await?;
+    tracing::debug!(
+        "Using robots.txt of {} archived at {}",
+        host,
+        capture.timestamp
+    );
+    Ok(Some(RobotsTxt::parse(
+        &String::from_utf8_lossy(&fetched.body),
+        &options.user_agent,
+    )))
+}
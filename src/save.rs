@@ -0,0 +1,84 @@
+use miette::{Result, miette};
+use reqwest::header::CONTENT_LOCATION;
+use reqwest::{Client, StatusCode};
+use std::time::Duration;
+
+use crate::rate_limit::RateLimiter;
+use crate::retry::Backoff;
+
+// Archiving a page happens while the request is open and regularly takes
+// much longer than an ordinary download
+const SAVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Asks the Wayback Machine to archive `url` now through its save API and
+/// returns the URL of the new snapshot.
+///
+/// Rate limiting and server errors are retried up to `retries` times after
+/// the pauses of `backoff`, like downloads.
+pub async fn submit_url(
+    client: &Client,
+    url: &str,
+    retries: u32,
+    backoff: Backoff,
+    rate_limiter: Option<&RateLimiter>,
+    user_agent: &str,
+) -> Result<String> {
+    let save_url = format!("https://web.archive.org/save/{}", url);
+    let mut attempt = 0;
+    loop {
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.acquire("web.archive.org").await;
+        }
+
+        let result = client
+            .get(&save_url)
+            .header("User-Agent", user_agent)
+            .timeout(SAVE_TIMEOUT)
+            .send()
+            .await;
+
+        let (error, retryable) = match result {
+            Ok(response) if response.status().is_success() => {
+                return snapshot_url(&response)
+                    .ok_or_else(|| miette!("The save API didn't return a snapshot for {}", url));
+            }
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+                (miette!("HTTP {}", status), retryable)
+            }
+            Err(e) => (miette!("Failed to submit URL: {}", e), true),
+        };
+
+        if !retryable || attempt >= retries {
+            return Err(error);
+        }
+
+        attempt += 1;
+        let delay = backoff.delay(attempt);
+        tracing::warn!(
+            "Retrying {} in {:.1?} ({}/{}): {}",
+            url,
+            delay,
+            attempt,
+            retries,
+            error
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The snapshot a save response points to, either through the redirect that
+/// was followed or the `Content-Location` header.
+fn snapshot_url(response: &reqwest::Response) -> Option<String> {
+    if response.url().path().starts_with("/web/") {
+        return Some(response.url().to_string());
+    }
+
+    let location = response.headers().get(CONTENT_LOCATION)?.to_str().ok()?;
+    if location.starts_with("/web/") {
+        Some(format!("https://web.archive.org{}", location))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,57 @@
+use url::Url;
+
+use crate::html_index::{ArchivedFile, link};
+use crate::timestamp::format_timestamp;
+
+/// Most URLs the Sitemap protocol allows in one file.
+pub const MAX_SITEMAP_URLS: usize = 50_000;
+
+/// A `sitemap.xml` following the Sitemap protocol 0.9 with a `<url>` for
+/// every HTML page among `files`, oldest first. Each `<loc>` is the file's
+/// name resolved against `base_url`, e.g. `https://mirror.example.org/` or
+/// the `file://` URL of the output directory, and `<lastmod>` is when the
+/// page was captured.
+pub fn render_sitemap(files: &[ArchivedFile], base_url: &Url) -> String {
+    let mut base_url = base_url.clone();
+    // Without the slash the last segment of the base would be replaced
+    if !base_url.path().ends_with('/') {
+        let path = format!("{}/", base_url.path());
+        base_url.set_path(&path);
+    }
+
+    let mut pages: Vec<&ArchivedFile> = files
+        .iter()
+        .filter(|file| file.filename.ends_with(".html") || file.filename.ends_with(".htm"))
+        .collect();
+    pages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+    let mut sitemap = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    ));
+    for page in pages {
+        let Ok(location) = base_url.join(&link(&page.filename)) else {
+            continue;
+        };
+        sitemap.push_str("  <url>\n");
+        sitemap.push_str(&format!("    <loc>{}</loc>\n", escape(location.as_str())));
+        let complete =
+            page.timestamp.len() == 14 && page.timestamp.chars().all(|c| c.is_ascii_digit());
+        if complete {
+            // W3C datetime, captures are timestamped in UTC
+            let modified = format_timestamp(&page.timestamp, "%Y-%m-%dT%H:%M:%S+00:00");
+            sitemap.push_str(&format!("    <lastmod>{}</lastmod>\n", modified));
+        }
+        sitemap.push_str("  </url>\n");
+    }
+    sitemap.push_str("</urlset>\n");
+    sitemap
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,301 @@
+use miette::{Context, IntoDiagnostic, Result, miette};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::timestamp::unix_seconds;
+
+const BLOCK: usize = 512;
+// Largest size the 11 octal digits of a header can hold
+const MAX_SIZE: u64 = 0o77_777_777_777;
+
+/// Writes a POSIX tar archive one entry at a time. Unlike zip, tar needs no
+/// index at the end, so it can go to a pipe like stdout as captures finish
+/// downloading.
+///
+/// Names longer than the ustar header allows are stored in a pax extended
+/// header. Entries are limited to 8 GiB.
+pub struct TarWriter {
+    /// Where the archive goes, for error messages.
+    name: String,
+    out: BufWriter<Box<dyn Write + Send>>,
+    finished: bool,
+}
+
+impl TarWriter {
+    /// Creates the archive at `path`, replacing any existing file.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .into_diagnostic()
+            .context(format!("Failed to create {}", path.display()))?;
+        Ok(Self {
+            name: path.display().to_string(),
+            out: BufWriter::new(Box::new(file)),
+            finished: false,
+        })
+    }
+
+    /// Writes the archive to stdout.
+    pub fn stdout() -> Self {
+        Self {
+            name: "stdout".to_string(),
+            out: BufWriter::new(Box::new(std::io::stdout())),
+            finished: false,
+        }
+    }
+
+    /// Adds a file named `name` with the contents `data`, dated with a CDX
+    /// style `YYYYMMDDHHMMSS` timestamp. The entry is flushed right away so
+    /// readers of a pipe see it.
+    pub fn add_file(&mut self, name: &str, data: &[u8], timestamp: &str) -> Result<()> {
+        if self.finished {
+            return Err(miette!("{} is already closed", self.name));
+        }
+        let size = data.len() as u64;
+        if size > MAX_SIZE {
+            return Err(miette!("{} is too large for a tar archive", name));
+        }
+        let mtime = unix_seconds(timestamp);
+
+        let (prefix, short_name) = split_name(name).unwrap_or_else(|| {
+            // Readers that don't know pax headers at least get a cut short name
+            ("", truncate(name, 100))
+        });
+        if prefix.is_empty() && short_name.len() < name.len() {
+            let record = pax_record("path", name);
+            let pax_name = format!("PaxHeaders/{}", truncate(short_name, 89));
+            let header = header(&pax_name, "", record.len() as u64, mtime, b'x');
+            self.write(&header)?;
+            self.write_padded(record.as_bytes())?;
+        }
+
+        let header = header(short_name, prefix, size, mtime, b'0');
+        self.write(&header)?;
+        self.write_padded(data)?;
+        self.flush()
+    }
+
+    /// Writes the two zero blocks that end the archive. Dropping the writer
+    /// does the same when it isn't finished, which covers runs that stop
+    /// early with an error. Code that exits the process without dropping it
+    /// calls [`TarWriter::close`] first.
+    pub fn finish(mut self) -> Result<String> {
+        self.close()?;
+        Ok(self.name.clone())
+    }
+
+    /// Ends the archive like [`TarWriter::finish`] without giving up the
+    /// writer, for when it is shared. Files can't be added afterwards, and
+    /// closing again does nothing.
+    pub fn close(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.write(&[0; 2 * BLOCK])?;
+        self.flush()
+    }
+
+    fn write_padded(&mut self, data: &[u8]) -> Result<()> {
+        self.write(data)?;
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        self.write(&[0; BLOCK][..padding])
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.out
+            .write_all(data)
+            .into_diagnostic()
+            .context(format!("Failed to write to {}", self.name))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.out
+            .flush()
+            .into_diagnostic()
+            .context(format!("Failed to write to {}", self.name))
+    }
+}
+
+impl Drop for TarWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.out.write_all(&[0; 2 * BLOCK]);
+            let _ = self.out.flush();
+        }
+    }
+}
+
+/// Splits `name` into the prefix and name fields of a ustar header, `None`
+/// when it doesn't fit in them.
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+    // The prefix ends at a / that isn't stored
+    name.match_indices('/')
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= 155 && rest.len() <= 100 && !rest.is_empty())
+}
+
+/// Cuts `name` to at most `max` bytes at a character boundary.
+fn truncate(name: &str, max: usize) -> &str {
+    let mut end = name.len().min(max);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// A pax record, `<length> <key>=<value>\n` where the length counts itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {}={}\n", key, value);
+    let mut length = rest.len();
+    while format!("{}{}", length, rest).len() != length {
+        length = format!("{}{}", length, rest).len();
+    }
+    format!("{}{}", length, rest)
+}
+
+/// A ustar header block for a regular file or, with `kind` `x`, a pax
+/// extended header.
+fn header(name: &str, prefix: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, format!("{:011o}\0", mtime.min(MAX_SIZE)).as_bytes());
+    // The checksum is computed with its own field filled with spaces
+    field(148, b"        ");
+    field(156, &[kind]);
+    field(257, b"ustar\x0000");
+    field(345, prefix.as_bytes());
+
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The number in an octal header field, which ends at a NUL or space.
+    fn octal(field: &[u8]) -> u64 {
+        let digits: String = field
+            .iter()
+            .take_while(|&&b| b != 0 && b != b' ')
+            .map(|&b| b as char)
+            .collect();
+        u64::from_str_radix(&digits, 8).unwrap()
+    }
+
+    fn text(field: &[u8]) -> &str {
+        let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+        std::str::from_utf8(&field[..end]).unwrap()
+    }
+
+    #[test]
+    fn headers_carry_their_checksum() {
+        let header = header("a.html", "", 1234, 1_700_000_000, b'0');
+        let mut unsummed = header;
+        unsummed[148..156].copy_from_slice(b"        ");
+        let sum: u64 = unsummed.iter().map(|&b| u64::from(b)).sum();
+        assert_eq!(octal(&header[148..156]), sum);
+        assert_eq!(&header[154..156], b"\0 ");
+
+        assert_eq!(text(&header[0..100]), "a.html");
+        assert_eq!(octal(&header[124..136]), 1234);
+        assert_eq!(octal(&header[136..148]), 1_700_000_000);
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..265], b"ustar\x0000");
+    }
+
+    #[test]
+    fn splits_long_names_at_a_slash() {
+        assert_eq!(split_name("short.html"), Some(("", "short.html")));
+
+        let dir = "d".repeat(60);
+        let name = format!("{}/{}/{}.html", dir, dir, "f".repeat(40));
+        let (prefix, rest) = split_name(&name).unwrap();
+        assert_eq!(prefix, format!("{}/{}", dir, dir));
+        assert_eq!(rest, format!("{}.html", "f".repeat(40)));
+
+        // Nothing after the last slash fits in 100 bytes
+        let name = format!("{}/{}", dir, "f".repeat(101));
+        assert_eq!(split_name(&name), None);
+        // No prefix fits in 155 bytes
+        let name = format!("{}/{}", "d".repeat(156), "f".repeat(10));
+        assert_eq!(split_name(&name), None);
+    }
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        assert_eq!(pax_record("path", "ab"), "11 path=ab\n");
+        // Around the lengths where the number gains a digit
+        for len in 0..1100 {
+            let record = pax_record("path", &"x".repeat(len));
+            let (length, rest) = record.split_once(' ').unwrap();
+            assert_eq!(length.parse::<usize>().unwrap(), record.len());
+            assert_eq!(rest, format!("path={}\n", "x".repeat(len)));
+        }
+    }
+
+    fn archive(name: &str, write: impl FnOnce(&mut TarWriter)) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("noway-{}-{}.tar", name, std::process::id()));
+        let mut tar = TarWriter::create(&path).unwrap();
+        write(&mut tar);
+        tar.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        data
+    }
+
+    #[test]
+    fn pads_entries_to_whole_blocks() {
+        let data = archive("padding", |tar| {
+            tar.add_file("a.txt", b"hello", "20240102030405").unwrap();
+            tar.add_file("b.txt", &[7; BLOCK], "20240102030405")
+                .unwrap();
+        });
+        // Header and one padded block each, then the two zero blocks
+        assert_eq!(data.len(), 6 * BLOCK);
+        assert_eq!(&data[BLOCK..BLOCK + 5], b"hello");
+        assert!(data[BLOCK + 5..2 * BLOCK].iter().all(|&b| b == 0));
+        assert_eq!(text(&data[2 * BLOCK..2 * BLOCK + 100]), "b.txt");
+        assert!(data[3 * BLOCK..4 * BLOCK].iter().all(|&b| b == 7));
+        assert!(data[4 * BLOCK..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn stores_names_that_dont_split_in_a_pax_header() {
+        let name = format!("{}.html", "f".repeat(120));
+        let data = archive("pax", |tar| {
+            tar.add_file(&name, b"x", "20240102030405").unwrap();
+        });
+        assert_eq!(data[156], b'x');
+        let record = pax_record("path", &name);
+        assert_eq!(octal(&data[124..136]), record.len() as u64);
+        assert_eq!(&data[BLOCK..BLOCK + record.len()], record.as_bytes());
+
+        let entry = &data[2 * BLOCK..3 * BLOCK];
+        assert_eq!(entry[156], b'0');
+        assert_eq!(text(&entry[0..100]), &name[..100]);
+    }
+
+    #[test]
+    fn closing_ends_the_archive_once() {
+        let data = archive("close", |tar| {
+            tar.add_file("a.txt", b"a", "20240102030405").unwrap();
+            tar.close().unwrap();
+            tar.close().unwrap();
+            assert!(tar.add_file("b.txt", b"b", "20240102030405").is_err());
+        });
+        assert_eq!(data.len(), 4 * BLOCK);
+    }
+}
@@ -0,0 +1,330 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use miette::{Result, miette};
+use rand::RngCore;
+use reqwest::Client;
+use serde_json::{Value, json};
+use tracing::Metadata;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing_core::span::Current;
+
+const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+thread_local! {
+    // Spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<(Id, &'static Metadata<'static>)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Collects the `tracing` spans of a run and exports them to an OTLP
+/// collector over HTTP/JSON.
+///
+/// The collector is `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`, or
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` followed by `/v1/traces`, and defaults to
+/// `http://localhost:4318/v1/traces`. Every span of the run belongs to the
+/// same trace. When `TRACEPARENT` holds a W3C trace context, that trace is
+/// continued and the root spans become children of its span.
+pub struct Telemetry {
+    client: Client,
+    endpoint: String,
+    service_name: String,
+    trace_id: [u8; 16],
+    parent_span_id: Option<[u8; 8]>,
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, OpenSpan>>,
+    finished: Mutex<Vec<Value>>,
+}
+
+struct OpenSpan {
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    metadata: &'static Metadata<'static>,
+    start: u64,
+    attributes: Vec<(String, Value)>,
+    references: usize,
+}
+
+impl Telemetry {
+    pub fn from_env() -> Self {
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").unwrap_or_else(|_| {
+            let base = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                .unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+            format!("{}/v1/traces", base.trim_end_matches('/'))
+        });
+        let service_name =
+            std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "noway".to_string());
+
+        let parent = std::env::var("TRACEPARENT")
+            .ok()
+            .and_then(|traceparent| parse_traceparent(&traceparent));
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => {
+                let mut trace_id = [0; 16];
+                rand::thread_rng().fill_bytes(&mut trace_id);
+                (trace_id, None)
+            }
+        };
+
+        Self {
+            client: Client::new(),
+            endpoint,
+            service_name,
+            trace_id,
+            parent_span_id,
+            next_id: AtomicU64::new(1),
+            open: Mutex::new(HashMap::new()),
+            finished: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Exports finished spans every few seconds until the process exits.
+    /// Failed exports are logged, they never fail the run.
+    pub fn spawn_exporter(self: &std::sync::Arc<Self>) {
+        let telemetry = std::sync::Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(EXPORT_INTERVAL).await;
+                if let Err(e) = telemetry.flush().await {
+                    tracing::warn!("{}", e);
+                }
+            }
+        });
+    }
+
+    /// Sends the spans that finished since the last export.
+    pub async fn flush(&self) -> Result<()> {
+        let spans = std::mem::take(&mut *self.finished.lock().unwrap());
+        if spans.is_empty() {
+            return Ok(());
+        }
+
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", json!({
+                        "stringValue": self.service_name
+                    }))]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "noway", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans
+                }]
+            }]
+        });
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| miette!("Failed to export spans to {}: {}", self.endpoint, e))?;
+        if !response.status().is_success() {
+            return Err(miette!(
+                "OTLP collector at {} responded with {}",
+                self.endpoint,
+                response.status()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let parent = if let Some(parent) = span.parent() {
+            Some(parent.into_u64())
+        } else if span.is_contextual() {
+            ENTERED.with(|entered| entered.borrow().last().map(|(id, _)| id.into_u64()))
+        } else {
+            None
+        };
+
+        let mut visitor = AttributeVisitor::default();
+        span.record(&mut visitor);
+        let mut span_id = [0; 8];
+        rand::thread_rng().fill_bytes(&mut span_id);
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut open = self.open.lock().unwrap();
+        // Spawned tasks can outlive the span they were started in, so the
+        // parent's id is looked up now rather than when the span closes
+        let parent_span_id = match parent {
+            Some(parent) => open.get(&parent).map(|parent| parent.span_id),
+            None => self.parent_span_id,
+        };
+        open.insert(
+            id,
+            OpenSpan {
+                span_id,
+                parent_span_id,
+                metadata: span.metadata(),
+                start: unix_nanos(),
+                attributes: visitor.attributes,
+                references: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    pub fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut visitor = AttributeVisitor::default();
+        values.record(&mut visitor);
+        if let Some(open) = self.open.lock().unwrap().get_mut(&span.into_u64()) {
+            for (key, value) in visitor.attributes {
+                open.attributes.retain(|(other, _)| *other != key);
+                open.attributes.push((key, value));
+            }
+        }
+    }
+
+    pub fn enter(&self, span: &Id) {
+        let metadata = self
+            .open
+            .lock()
+            .unwrap()
+            .get(&span.into_u64())
+            .map(|open| open.metadata);
+        if let Some(metadata) = metadata {
+            ENTERED.with(|entered| entered.borrow_mut().push((span.clone(), metadata)));
+        }
+    }
+
+    pub fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|(id, _)| id == span) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    pub fn current_span(&self) -> Current {
+        ENTERED.with(|entered| match entered.borrow().last() {
+            Some((id, metadata)) => Current::new(id.clone(), metadata),
+            None => Current::none(),
+        })
+    }
+
+    pub fn clone_span(&self, span: &Id) -> Id {
+        if let Some(open) = self.open.lock().unwrap().get_mut(&span.into_u64()) {
+            open.references += 1;
+        }
+        span.clone()
+    }
+
+    /// Finishes the span once the last handle to it is dropped.
+    pub fn try_close(&self, span: Id) -> bool {
+        let mut open = self.open.lock().unwrap();
+        let Some(closing) = open.get_mut(&span.into_u64()) else {
+            return false;
+        };
+        closing.references -= 1;
+        if closing.references > 0 {
+            return false;
+        }
+
+        let closed = open.remove(&span.into_u64()).unwrap();
+        drop(open);
+
+        let failed = closed.attributes.iter().any(|(key, _)| key == "error");
+        let mut exported = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&closed.span_id),
+            "name": closed.metadata.name(),
+            "kind": 1,
+            "startTimeUnixNano": closed.start.to_string(),
+            "endTimeUnixNano": unix_nanos().to_string(),
+            "attributes": closed
+                .attributes
+                .into_iter()
+                .map(|(key, value)| attribute(&key, value))
+                .collect::<Vec<_>>(),
+            "status": { "code": if failed { 2 } else { 1 } },
+        });
+        if let Some(parent_span_id) = closed.parent_span_id {
+            exported["parentSpanId"] = json!(hex(&parent_span_id));
+        }
+        self.finished.lock().unwrap().push(exported);
+        true
+    }
+}
+
+#[derive(Default)]
+struct AttributeVisitor {
+    attributes: Vec<(String, Value)>,
+}
+
+impl Visit for AttributeVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        // OTLP's JSON encoding writes 64 bit integers as strings
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, json!({ "boolValue": value }));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, json!({ "stringValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, json!({ "stringValue": format!("{:?}", value) }));
+    }
+}
+
+impl AttributeVisitor {
+    fn push(&mut self, field: &Field, value: Value) {
+        self.attributes.push((field.name().to_string(), value));
+    }
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+/// The trace and span ids of a W3C `traceparent` header value, e.g.
+/// `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`.
+fn parse_traceparent(traceparent: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = traceparent.trim().split('-');
+    let _version = parts.next()?;
+    let mut trace_id = [0; 16];
+    let mut span_id = [0; 8];
+    decode_hex(parts.next()?, &mut trace_id)?;
+    decode_hex(parts.next()?, &mut span_id)?;
+    // All zero ids are invalid
+    (trace_id != [0; 16] && span_id != [0; 8]).then_some((trace_id, span_id))
+}
+
+fn decode_hex(hex: &str, bytes: &mut [u8]) -> Option<()> {
+    if hex.len() != bytes.len() * 2 {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
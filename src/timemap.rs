@@ -0,0 +1,146 @@
+use miette::{Context, IntoDiagnostic, Result, miette};
+use reqwest::Client;
+use serde::Serialize;
+
+/// Where the Wayback Machine serves captures, the default base of
+/// [`fetch_timemap`].
+pub const DEFAULT_WAYBACK_BASE: &str = "https://web.archive.org/web";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// One link of a Memento timemap (RFC 7089), e.g. a capture with
+/// `rel="memento"` or the archived page with `rel="original"`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TimemapLink {
+    pub url: String,
+    /// Relation types of the link, e.g. `first memento`.
+    pub rel: String,
+    /// When a memento was captured, as given, e.g.
+    /// `Tue, 20 Jun 2000 14:25:57 GMT`.
+    pub datetime: Option<String>,
+    /// `datetime` as a capture timestamp (YYYYMMDDHHMMSS).
+    pub timestamp: Option<String>,
+}
+
+impl TimemapLink {
+    /// Whether the link is a capture rather than the original page, the
+    /// timegate or another timemap.
+    pub fn is_memento(&self) -> bool {
+        self.rel.split_whitespace().any(|rel| rel == "memento")
+    }
+}
+
+/// Downloads the timemap of `url` in link format from an archive serving
+/// captures under `wayback_base`, e.g. [`DEFAULT_WAYBACK_BASE`]. Empty when
+/// the URL was never archived.
+pub async fn fetch_timemap(
+    client: &Client,
+    url: &str,
+    wayback_base: &str,
+    user_agent: &str,
+) -> Result<String> {
+    let timemap_url = format!(
+        "{}/timemap/link/{}",
+        wayback_base.trim_end_matches('/'),
+        url
+    );
+    tracing::debug!("Timemap request: {}", timemap_url);
+    let response = client
+        .get(&timemap_url)
+        .header("User-Agent", user_agent)
+        .send()
+        .await
+        .into_diagnostic()
+        .context("Failed to fetch timemap")?;
+    // The Wayback Machine answers 404 for URLs it has no captures of
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(String::new());
+    }
+    if !response.status().is_success() {
+        return Err(miette!(
+            "Timemap request failed: HTTP {}",
+            response.status()
+        ));
+    }
+    response
+        .text()
+        .await
+        .into_diagnostic()
+        .context("Failed to read timemap")
+}
+
+/// Parses a timemap in the `application/link-format` of RFC 6690, one
+/// `<url>; rel="..."; datetime="..."` link after the other separated by
+/// commas. Links that don't start with `<url>` are skipped.
+pub fn parse_timemap(body: &str) -> Vec<TimemapLink> {
+    let mut links = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>').map(|end| start + end) else {
+            break;
+        };
+        let url = rest[start + 1..end].trim().to_string();
+        rest = &rest[end + 1..];
+
+        // Parameters run up to the next comma outside quotes, datetimes
+        // have one of their own
+        let mut in_quotes = false;
+        let params_end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c == ',' && !in_quotes
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let params = &rest[..params_end];
+        rest = &rest[params_end..];
+
+        let mut rel = String::new();
+        let mut datetime = None;
+        for param in params.split(';') {
+            let Some((key, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "rel" => rel = value,
+                "datetime" => datetime = Some(value),
+                _ => {}
+            }
+        }
+        let timestamp = datetime.as_deref().and_then(http_date_timestamp);
+        links.push(TimemapLink {
+            url,
+            rel,
+            datetime,
+            timestamp,
+        });
+    }
+    links
+}
+
+/// An HTTP date like `Tue, 20 Jun 2000 14:25:57 GMT` as a capture timestamp,
+/// `None` when it isn't one.
+fn http_date_timestamp(date: &str) -> Option<String> {
+    let date = date.split_once(',').map_or(date, |(_, date)| date);
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    let [day, month, year, time, ..] = parts[..] else {
+        return None;
+    };
+    let month = MONTHS
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(month))?
+        + 1;
+    let time = time.replace(':', "");
+    let digits = [day, year, time.as_str()]
+        .iter()
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    if !digits || day.len() > 2 || year.len() != 4 || time.len() != 6 {
+        return None;
+    }
+    Some(format!("{}{:02}{:0>2}{}", year, month, day, time))
+}
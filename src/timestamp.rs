@@ -0,0 +1,300 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use miette::{Result, miette};
+
+/// The current UTC time as a CDX style `YYYYMMDDHHMMSS` timestamp.
+pub fn now_timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    format_unix_seconds(seconds)
+}
+
+/// Formats seconds since the Unix epoch as a `YYYYMMDDHHMMSS` timestamp.
+pub fn format_unix_seconds(seconds: u64) -> String {
+    format_seconds(i64::try_from(seconds).unwrap_or(i64::MAX))
+}
+
+/// The `YYYYMMDDHHMMSS` timestamp one second after the complete `timestamp`,
+/// carrying into the minute, hour, day, month and year, e.g.
+/// `20231231235959` gives `20240101000000`.
+pub fn next_second(timestamp: &str) -> String {
+    let part = |range: std::ops::Range<usize>| {
+        timestamp
+            .get(range)
+            .and_then(|part| part.parse::<i64>().ok())
+            .unwrap_or_default()
+    };
+    let days = days_from_civil(part(0..4), part(4..6) as u32, part(6..8) as u32);
+    format_seconds(days * 86_400 + part(8..10) * 3600 + part(10..12) * 60 + part(12..14) + 1)
+}
+
+// Like format_unix_seconds, but also before 1970
+fn format_seconds(seconds: i64) -> String {
+    let days = seconds.div_euclid(86_400);
+    let time = seconds.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date, using Howard
+/// Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Formats a `YYYYMMDDHHMMSS` timestamp for people, e.g.
+/// `15 January 2023, 10:30:00 UTC`. Anything else is returned unchanged.
+pub fn format_readable(timestamp: &str) -> String {
+    let number =
+        |range: std::ops::Range<usize>| -> Option<usize> { timestamp.get(range)?.parse().ok() };
+    let parts = (
+        number(0..4),
+        number(4..6),
+        number(6..8),
+        number(8..10),
+        number(10..12),
+        number(12..14),
+    );
+    match parts {
+        (Some(year), Some(month @ 1..=12), Some(day), Some(hour), Some(minute), Some(second))
+            if timestamp.len() == 14 =>
+        {
+            format!(
+                "{} {} {}, {:02}:{:02}:{:02} UTC",
+                day,
+                MONTHS[month - 1],
+                year,
+                hour,
+                minute,
+                second
+            )
+        }
+        _ => timestamp.to_string(),
+    }
+}
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+/// Format of CDX timestamps, which [`format_timestamp`] keeps by default.
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// The strftime conversions [`format_timestamp`] understands.
+const TIMESTAMP_SPECIFIERS: &str = "YymdeHIMSpjbBaAFT%";
+
+/// Checks that `format` only uses the strftime conversions
+/// [`format_timestamp`] understands.
+pub fn validate_timestamp_format(format: &str) -> Result<()> {
+    if format.is_empty() {
+        return Err(miette!("The timestamp format is empty"));
+    }
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        match chars.next() {
+            Some(specifier) if TIMESTAMP_SPECIFIERS.contains(specifier) => {}
+            Some(specifier) => {
+                return Err(miette!(
+                    help = "Use %Y, %y, %m, %d, %e, %H, %I, %M, %S, %p, %j, %b, %B, %a, %A, %F, \
+                            %T or %%",
+                    "Unsupported conversion %{} in timestamp format {}",
+                    specifier,
+                    format
+                ));
+            }
+            None => return Err(miette!("Timestamp format {} ends with a lone %", format)),
+        }
+    }
+    Ok(())
+}
+
+/// Formats a `YYYYMMDDHHMMSS` timestamp with a strftime style `format`, e.g.
+/// `%Y-%m-%d %H:%M` gives `2023-01-15 10:30`. Anything else is returned
+/// unchanged, as are conversions [`validate_timestamp_format`] rejects.
+pub fn format_timestamp(timestamp: &str, format: &str) -> String {
+    let number =
+        |range: std::ops::Range<usize>| -> Option<u32> { timestamp.get(range)?.parse().ok() };
+    let parts = (
+        number(0..4),
+        number(4..6),
+        number(6..8),
+        number(8..10),
+        number(10..12),
+        number(12..14),
+    );
+    let (year, month, day, hour, minute, second) = match parts {
+        (Some(year), Some(month @ 1..=12), Some(day @ 1..=31), Some(h), Some(m), Some(s))
+            if timestamp.len() == 14 =>
+        {
+            (year, month, day, h, m, s)
+        }
+        _ => return timestamp.to_string(),
+    };
+
+    let days = days_from_civil(i64::from(year), month, day);
+    let day_of_year = days - days_from_civil(i64::from(year), 1, 1) + 1;
+    let weekday = WEEKDAYS[(days + 4).rem_euclid(7) as usize];
+    let month_name = MONTHS[month as usize - 1];
+    let hour12 = if hour % 12 == 0 { 12 } else { hour % 12 };
+
+    let mut formatted = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => formatted.push_str(&format!("{:04}", year)),
+            Some('y') => formatted.push_str(&format!("{:02}", year % 100)),
+            Some('m') => formatted.push_str(&format!("{:02}", month)),
+            Some('d') => formatted.push_str(&format!("{:02}", day)),
+            Some('e') => formatted.push_str(&format!("{:2}", day)),
+            Some('H') => formatted.push_str(&format!("{:02}", hour)),
+            Some('I') => formatted.push_str(&format!("{:02}", hour12)),
+            Some('M') => formatted.push_str(&format!("{:02}", minute)),
+            Some('S') => formatted.push_str(&format!("{:02}", second)),
+            Some('p') => formatted.push_str(if hour < 12 { "AM" } else { "PM" }),
+            Some('j') => formatted.push_str(&format!("{:03}", day_of_year)),
+            Some('b') => formatted.push_str(&month_name[..3]),
+            Some('B') => formatted.push_str(month_name),
+            Some('a') => formatted.push_str(&weekday[..3]),
+            Some('A') => formatted.push_str(weekday),
+            Some('F') => formatted.push_str(&format!("{:04}-{:02}-{:02}", year, month, day)),
+            Some('T') => {
+                formatted.push_str(&format!("{:02}:{:02}:{:02}", hour, minute, second));
+            }
+            Some('%') => formatted.push('%'),
+            Some(other) => {
+                formatted.push('%');
+                formatted.push(other);
+            }
+            None => formatted.push('%'),
+        }
+    }
+    formatted
+}
+
+/// Converts a date to days since 1970-01-01, the inverse of
+/// [`civil_from_days`].
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let day_of_year = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Seconds since the Unix epoch of a `YYYYMMDDHHMMSS` timestamp, the inverse
+/// of [`format_unix_seconds`]. Missing parts default to the start of the
+/// period, anything before 1970 or unreadable gives 0.
+pub(crate) fn unix_seconds(timestamp: &str) -> u64 {
+    let digits = format!("{:0<14}", timestamp);
+    let part = |range: std::ops::Range<usize>, default: u32| {
+        digits
+            .get(range)
+            .and_then(|part| part.parse::<u32>().ok())
+            .filter(|&value| value != 0 || default == 0)
+            .unwrap_or(default)
+    };
+    let days = days_from_civil(i64::from(part(0..4, 1970)), part(4..6, 1), part(6..8, 1));
+    let seconds = days * 86_400
+        + i64::from(part(8..10, 0)) * 3600
+        + i64::from(part(10..12, 0)) * 60
+        + i64::from(part(12..14, 0));
+    u64::try_from(seconds).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_second_carries_into_the_next_year() {
+        assert_eq!(next_second("20231231235959"), "20240101000000");
+    }
+
+    #[test]
+    fn next_second_within_a_minute() {
+        assert_eq!(next_second("20230115123045"), "20230115123046");
+    }
+
+    #[test]
+    fn next_second_carries_into_the_next_minute_and_hour() {
+        assert_eq!(next_second("20230115125959"), "20230115130000");
+        assert_eq!(next_second("20230115235959"), "20230116000000");
+    }
+
+    #[test]
+    fn next_second_reaches_february_29_in_leap_years() {
+        assert_eq!(next_second("20240228235959"), "20240229000000");
+        assert_eq!(next_second("20240229235959"), "20240301000000");
+    }
+
+    #[test]
+    fn next_second_skips_february_29_in_other_years() {
+        assert_eq!(next_second("20230228235959"), "20230301000000");
+        // Centuries are only leap years when divisible by 400
+        assert_eq!(next_second("19000228235959"), "19000301000000");
+        assert_eq!(next_second("20000228235959"), "20000229000000");
+    }
+
+    #[test]
+    fn next_second_carries_at_the_end_of_30_and_31_day_months() {
+        assert_eq!(next_second("20230430235959"), "20230501000000");
+        assert_eq!(next_second("20230131235959"), "20230201000000");
+        assert_eq!(next_second("20230630235959"), "20230701000000");
+    }
+
+    #[test]
+    fn next_second_before_1970() {
+        assert_eq!(next_second("19691231235959"), "19700101000000");
+        assert_eq!(next_second("19650101000000"), "19650101000001");
+    }
+}
@@ -0,0 +1,140 @@
+const TOOLBAR_BEGIN: &str = "<!-- BEGIN WAYBACK TOOLBAR INSERT -->";
+const TOOLBAR_END: &str = "<!-- END WAYBACK TOOLBAR INSERT -->";
+const REWRITE_JS_END: &str = "<!-- End Wayback Rewrite JS Include -->";
+const REWRITE_JS_STARTS: [&str; 3] = [
+    "<script src=\"//archive.org/includes/athena.js\"",
+    "<script type=\"text/javascript\" src=\"https://web-static.archive.org/",
+    "<script src=\"https://web-static.archive.org/",
+];
+const ARCHIVE_NOTICE: &str = "FILE ARCHIVED ON";
+
+/// Removes what the Wayback Machine injects into archived pages: the toolbar
+/// markup, the rewrite scripts and styles in `<head>` and the trailing
+/// "FILE ARCHIVED ON" comment.
+pub fn strip_wayback_artifacts(html: &str) -> String {
+    let mut html = html.to_string();
+
+    while let Some(start) = html.find(TOOLBAR_BEGIN) {
+        let Some(end) = html[start..].find(TOOLBAR_END) else {
+            break;
+        };
+        html.replace_range(start..start + end + TOOLBAR_END.len(), "");
+    }
+
+    // The rewrite include has no begin marker, it is inserted right after the
+    // opening <head> tag and always starts with one of a few known scripts.
+    if let Some(end) = html.find(REWRITE_JS_END) {
+        let start = REWRITE_JS_STARTS
+            .iter()
+            .filter_map(|marker| html[..end].find(marker))
+            .min()
+            .or_else(|| {
+                let head = html[..end].find("<head")?;
+                Some(head + html[head..end].find('>')? + 1)
+            });
+
+        if let Some(start) = start {
+            html.replace_range(start..end + REWRITE_JS_END.len(), "");
+        }
+    }
+
+    if let Some(notice) = html.rfind(ARCHIVE_NOTICE)
+        && let Some(start) = html[..notice].rfind("<!--")
+        && let Some(end) = html[notice..].find("-->")
+    {
+        html.replace_range(start..notice + end + "-->".len(), "");
+    }
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = "<html><head><title>Example</title></head><body><p>Hello</p></body></html>";
+
+    #[test]
+    fn removes_the_toolbar_block() {
+        let html = "<html><head></head><body><!-- BEGIN WAYBACK TOOLBAR INSERT -->\n\
+                    <div id=\"wm-ipp\">toolbar</div>\n<!-- END WAYBACK TOOLBAR INSERT -->\
+                    <p>Hello</p></body></html>";
+        assert_eq!(
+            strip_wayback_artifacts(html),
+            "<html><head></head><body><p>Hello</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn removes_every_toolbar_block() {
+        let html = "<!-- BEGIN WAYBACK TOOLBAR INSERT -->a<!-- END WAYBACK TOOLBAR INSERT -->\
+                    <p>Hello</p>\
+                    <!-- BEGIN WAYBACK TOOLBAR INSERT -->b<!-- END WAYBACK TOOLBAR INSERT -->";
+        assert_eq!(strip_wayback_artifacts(html), "<p>Hello</p>");
+    }
+
+    #[test]
+    fn removes_the_injected_scripts_and_styles() {
+        let html = "<html><head><script src=\"//archive.org/includes/athena.js\" \
+                    type=\"text/javascript\"></script>\n\
+                    <script type=\"text/javascript\">window.RufflePlayer=window.RufflePlayer||{};\
+                    </script>\n\
+                    <link rel=\"stylesheet\" type=\"text/css\" \
+                    href=\"https://web-static.archive.org/_static/css/banner-styles.css\"/>\n\
+                    <!-- End Wayback Rewrite JS Include -->\n\
+                    <title>Example</title></head><body><p>Hello</p></body></html>";
+        assert_eq!(
+            strip_wayback_artifacts(html),
+            "<html><head>\n<title>Example</title></head><body><p>Hello</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn removes_injected_scripts_with_an_unknown_first_tag() {
+        let html = "<html><head lang=\"en\"><script src=\"/_static/js/new.js\"></script>\
+                    <!-- End Wayback Rewrite JS Include --><title>Example</title></head></html>";
+        assert_eq!(
+            strip_wayback_artifacts(html),
+            "<html><head lang=\"en\"><title>Example</title></head></html>"
+        );
+    }
+
+    #[test]
+    fn removes_the_archive_notice() {
+        let html = "<html><body><p>Hello</p></body></html>\n<!--\n     \
+                    FILE ARCHIVED ON 10:30:00 Jan 15, 2023 AND RETRIEVED FROM THE\n     \
+                    INTERNET ARCHIVE ON 12:00:00 Feb 01, 2024.\n-->";
+        assert_eq!(
+            strip_wayback_artifacts(html),
+            "<html><body><p>Hello</p></body></html>\n"
+        );
+    }
+
+    #[test]
+    fn leaves_pages_without_injections_unchanged() {
+        assert_eq!(strip_wayback_artifacts(PAGE), PAGE);
+        assert_eq!(strip_wayback_artifacts(""), "");
+    }
+
+    #[test]
+    fn leaves_an_unterminated_toolbar_marker_alone() {
+        let html = "<body><!-- BEGIN WAYBACK TOOLBAR INSERT --><p>Hello</p></body>";
+        assert_eq!(strip_wayback_artifacts(html), html);
+    }
+
+    #[test]
+    fn removes_terminated_blocks_before_an_unterminated_one() {
+        let html = "<!-- BEGIN WAYBACK TOOLBAR INSERT -->a<!-- END WAYBACK TOOLBAR INSERT -->\
+                    <p>Hello</p><!-- BEGIN WAYBACK TOOLBAR INSERT -->b";
+        assert_eq!(
+            strip_wayback_artifacts(html),
+            "<p>Hello</p><!-- BEGIN WAYBACK TOOLBAR INSERT -->b"
+        );
+    }
+
+    #[test]
+    fn leaves_an_end_marker_without_a_begin_marker_alone() {
+        let html = "<p>Hello</p><!-- END WAYBACK TOOLBAR INSERT -->";
+        assert_eq!(strip_wayback_artifacts(html), html);
+    }
+}
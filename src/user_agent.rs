@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use miette::{Result, miette};
+use rand::Rng;
+
+/// User-Agents download requests take turns with, in order or at random.
+#[derive(Debug)]
+pub struct UserAgentRotation {
+    user_agents: Vec<String>,
+    random: bool,
+    next: AtomicUsize,
+}
+
+impl UserAgentRotation {
+    /// A rotation of the non-empty lines of `list`, a User-Agent per line.
+    pub fn parse(list: &str, random: bool) -> Result<Self> {
+        let user_agents: Vec<String> = list
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+        if user_agents.is_empty() {
+            return Err(miette!(
+                help = "Put one User-Agent string on each line",
+                "No User-Agent to rotate through"
+            ));
+        }
+        Ok(Self {
+            user_agents,
+            random,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// The User-Agent for the next request.
+    pub fn next(&self) -> &str {
+        let i = if self.random {
+            rand::thread_rng().gen_range(0..self.user_agents.len())
+        } else {
+            self.next.fetch_add(1, Ordering::Relaxed) % self.user_agents.len()
+        };
+        &self.user_agents[i]
+    }
+}
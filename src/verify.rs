@@ -0,0 +1,61 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use miette::{Context, IntoDiagnostic, Result};
+
+use crate::checksum::cdx_digest;
+use crate::gzip::gunzip;
+use crate::toolbar::strip_wayback_artifacts;
+
+/// How a downloaded file compares to the CDX digest of its capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// The file hashes to the digest.
+    Match,
+    /// The file hashes to the digest once the toolbar and scripts the
+    /// Wayback Machine adds to HTML pages are removed.
+    MatchWithoutArtifacts,
+    Mismatch,
+    /// There is no file at the path.
+    Missing,
+}
+
+/// Checks the file at `path` against `digest`, the SHA-1 in base 32 the CDX
+/// API lists for its capture, with or without a `sha1:` prefix. Files ending
+/// in `.gz` are compared decompressed.
+///
+/// The digest is that of the page as the archive first fetched it. Pages the
+/// Wayback Machine rewrote links in don't match it, so an HTML mismatch is
+/// expected unless the capture was downloaded unmodified.
+pub fn verify_file(path: &Path, digest: &str) -> Result<Verification> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Verification::Missing),
+        Err(error) => {
+            return Err(error)
+                .into_diagnostic()
+                .context(format!("Failed to read {}", path.display()));
+        }
+    };
+    let data = match path.extension() {
+        Some(extension) if extension == "gz" => gunzip(&data).unwrap_or(data),
+        _ => data,
+    };
+
+    let expected = digest
+        .strip_prefix("sha1:")
+        .unwrap_or(digest)
+        .to_ascii_uppercase();
+    if cdx_digest(&data) == expected {
+        return Ok(Verification::Match);
+    }
+    // Files downloaded without --strip-toolbar still have what the Wayback
+    // Machine injects, and stripping leaves other text alone
+    if let Ok(text) = std::str::from_utf8(&data) {
+        let stripped = strip_wayback_artifacts(text);
+        if stripped != text && cdx_digest(stripped.as_bytes()) == expected {
+            return Ok(Verification::MatchWithoutArtifacts);
+        }
+    }
+    Ok(Verification::Mismatch)
+}
@@ -0,0 +1,340 @@
+use miette::{Context, IntoDiagnostic, Result, miette};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::crc32::crc32;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+// Bit 11 of the general purpose flags marks names as UTF-8
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// Writes a zip archive one entry at a time, so captures are added as they
+/// finish downloading instead of being held in memory until the end.
+///
+/// Entries are deflated unless that doesn't make them smaller. Archives are
+/// limited to the classic format, 4 GiB and 65535 entries.
+pub struct ZipWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    offset: u64,
+    entries: Vec<CentralEntry>,
+    finished: bool,
+}
+
+struct CentralEntry {
+    name: String,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+impl ZipWriter {
+    /// Creates the archive at `path`, replacing any existing file.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .into_diagnostic()
+            .context(format!("Failed to create {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            offset: 0,
+            entries: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Adds a file named `name` with the contents `data`, dated with a CDX
+    /// style `YYYYMMDDHHMMSS` timestamp.
+    pub fn add_file(&mut self, name: &str, data: &[u8], timestamp: &str) -> Result<()> {
+        if self.finished {
+            return Err(miette!("{} is already closed", self.path.display()));
+        }
+        if self.entries.len() >= usize::from(u16::MAX) {
+            return Err(miette!(
+                "Zip archives can't hold more than {} entries",
+                u16::MAX
+            ));
+        }
+
+        let compressed = miniz_oxide::deflate::compress_to_vec(data, 6);
+        let (method, stored) = if compressed.len() < data.len() {
+            (METHOD_DEFLATED, compressed.as_slice())
+        } else {
+            (METHOD_STORED, data)
+        };
+
+        let too_large = || miette!("{} is too large for a zip archive", name);
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let compressed_size = u32::try_from(stored.len()).map_err(|_| too_large())?;
+        let offset = u32::try_from(self.offset)
+            .map_err(|_| miette!("Zip archives larger than 4 GiB aren't supported"))?;
+        let (time, date) = dos_date_time(timestamp);
+
+        let entry = CentralEntry {
+            name: name.to_string(),
+            method,
+            time,
+            date,
+            crc: crc32(data),
+            compressed_size,
+            size,
+            offset,
+        };
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&20u16.to_le_bytes());
+        header.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+        header.extend_from_slice(&entry.method.to_le_bytes());
+        header.extend_from_slice(&entry.time.to_le_bytes());
+        header.extend_from_slice(&entry.date.to_le_bytes());
+        header.extend_from_slice(&entry.crc.to_le_bytes());
+        header.extend_from_slice(&entry.compressed_size.to_le_bytes());
+        header.extend_from_slice(&entry.size.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+
+        self.write(&header)?;
+        self.write(stored)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory and closes the archive.
+    pub fn finish(mut self) -> Result<PathBuf> {
+        self.close()?;
+        Ok(self.path)
+    }
+
+    /// Writes the central directory like [`ZipWriter::finish`] without giving
+    /// up the writer, for when it is shared. Files can't be added afterwards,
+    /// and closing again does nothing.
+    pub fn close(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let directory_offset = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            // Made by version 2.0 on Unix, so no MS-DOS attributes are implied
+            directory.extend_from_slice(&0x0314u16.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes());
+            directory.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+            directory.extend_from_slice(&entry.method.to_le_bytes());
+            directory.extend_from_slice(&entry.time.to_le_bytes());
+            directory.extend_from_slice(&entry.date.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&entry.compressed_size.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            // Extra field, comment, disk number and internal attributes
+            directory.extend_from_slice(&[0; 8]);
+            // rw-r--r-- regular file
+            directory.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let directory_offset = u32::try_from(directory_offset)
+            .map_err(|_| miette!("Zip archives larger than 4 GiB aren't supported"))?;
+        let count = self.entries.len() as u16;
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&directory_offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+
+        self.write(&directory)?;
+        self.write(&end)?;
+        self.file
+            .flush()
+            .into_diagnostic()
+            .context(format!("Failed to write {}", self.path.display()))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file
+            .write_all(data)
+            .into_diagnostic()
+            .context(format!("Failed to write {}", self.path.display()))?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Converts a `YYYYMMDDHHMMSS` timestamp to the MS-DOS time and date fields
+/// zip uses. Missing parts default to the start of the period.
+fn dos_date_time(timestamp: &str) -> (u16, u16) {
+    let digits = format!("{:0<14}", timestamp);
+    let part = |range: std::ops::Range<usize>, default: u16| {
+        digits
+            .get(range)
+            .and_then(|part| part.parse::<u16>().ok())
+            .filter(|&value| value != 0 || default == 0)
+            .unwrap_or(default)
+    };
+
+    // MS-DOS dates start in 1980
+    let year = part(0..4, 1980).clamp(1980, 2107);
+    let month = part(4..6, 1);
+    let day = part(6..8, 1);
+    let time = (part(8..10, 0) << 11) | (part(10..12, 0) << 5) | (part(12..14, 0) / 2);
+    let date = ((year - 1980) << 9) | (month << 5) | day;
+    (time, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// An entry as the central directory and its local header describe it.
+    #[derive(Debug)]
+    struct Entry {
+        name: String,
+        method: u16,
+        time: u16,
+        date: u16,
+        crc: u32,
+        data: Vec<u8>,
+    }
+
+    /// Reads an archive back through its end record and central directory,
+    /// checking that every local header agrees with its directory entry.
+    fn read(archive: &[u8]) -> Vec<Entry> {
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(archive, end), 0x0605_4b50);
+        let count = usize::from(u16_at(archive, end + 10));
+        let directory_size = u32_at(archive, end + 12) as usize;
+        let mut offset = u32_at(archive, end + 16) as usize;
+        assert_eq!(offset + directory_size, end);
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let central = &archive[offset..];
+            assert_eq!(u32_at(central, 0), 0x0201_4b50);
+            let name_len = usize::from(u16_at(central, 28));
+            let local_offset = u32_at(central, 42) as usize;
+            let compressed_size = u32_at(central, 20) as usize;
+            let size = u32_at(central, 24) as usize;
+
+            let local = &archive[local_offset..];
+            assert_eq!(u32_at(local, 0), 0x0403_4b50);
+            // Method, time, date, CRC and both sizes match the directory
+            assert_eq!(local[8..26], central[10..28]);
+            assert_eq!(usize::from(u16_at(local, 26)), name_len);
+            assert_eq!(local[30..30 + name_len], central[46..46 + name_len]);
+
+            let method = u16_at(central, 10);
+            let stored = &local[30 + name_len..30 + name_len + compressed_size];
+            let data = match method {
+                METHOD_STORED => stored.to_vec(),
+                METHOD_DEFLATED => miniz_oxide::inflate::decompress_to_vec(stored).unwrap(),
+                _ => panic!("unknown method {}", method),
+            };
+            assert_eq!(data.len(), size);
+
+            entries.push(Entry {
+                name: String::from_utf8(central[46..46 + name_len].to_vec()).unwrap(),
+                method,
+                time: u16_at(central, 12),
+                date: u16_at(central, 14),
+                crc: u32_at(central, 16),
+                data,
+            });
+            offset += 46 + name_len;
+        }
+        entries
+    }
+
+    fn archive(name: &str, write: impl FnOnce(&mut ZipWriter)) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("noway-{}-{}.zip", name, std::process::id()));
+        let mut zip = ZipWriter::create(&path).unwrap();
+        write(&mut zip);
+        assert_eq!(zip.finish().unwrap(), path);
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        data
+    }
+
+    #[test]
+    fn reads_back_what_was_added() {
+        let page = "<html><body>hello</body></html>".repeat(20);
+        let random: Vec<u8> = (0..200u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let data = archive("round-trip", |zip| {
+            zip.add_file("page.html", page.as_bytes(), "20240229123456")
+                .unwrap();
+            zip.add_file("dir/random.bin", &random, "20240229123456")
+                .unwrap();
+            zip.add_file("empty.txt", b"", "20240229123456").unwrap();
+        });
+
+        let entries = read(&data);
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["page.html", "dir/random.bin", "empty.txt"]);
+
+        // Repetitive data shrinks, data that doesn't is stored as it is
+        assert_eq!(entries[0].method, METHOD_DEFLATED);
+        assert_eq!(entries[0].data, page.as_bytes());
+        assert_eq!(entries[1].method, METHOD_STORED);
+        assert_eq!(entries[1].data, random);
+        assert_eq!(entries[2].method, METHOD_STORED);
+        assert!(entries[2].data.is_empty());
+
+        for entry in &entries {
+            assert_eq!(entry.crc, crc32(&entry.data), "{}", entry.name);
+            assert_eq!((entry.time, entry.date), dos_date_time("20240229123456"));
+        }
+    }
+
+    #[test]
+    fn writes_an_empty_archive() {
+        let data = archive("empty", |_| {});
+        assert_eq!(data.len(), 22);
+        assert!(read(&data).is_empty());
+    }
+
+    #[test]
+    fn closing_ends_the_archive_once() {
+        let data = archive("close", |zip| {
+            zip.add_file("a.txt", b"a", "20240101000000").unwrap();
+            zip.close().unwrap();
+            zip.close().unwrap();
+            assert!(zip.add_file("b.txt", b"b", "20240101000000").is_err());
+        });
+        assert_eq!(read(&data).len(), 1);
+    }
+
+    #[test]
+    fn converts_timestamps_to_dos_dates() {
+        let (time, date) = dos_date_time("20240229123456");
+        assert_eq!(time, (12 << 11) | (34 << 5) | (56 / 2));
+        assert_eq!(date, ((2024 - 1980) << 9) | (2 << 5) | 29);
+
+        // Missing parts start the period, and years before 1980 don't exist
+        assert_eq!(dos_date_time("2021"), (0, (41 << 9) | (1 << 5) | 1));
+        assert_eq!(dos_date_time("19700101000000"), (0, (1 << 5) | 1));
+        assert_eq!(dos_date_time(""), (0, (1 << 5) | 1));
+    }
+}